@@ -15,6 +15,7 @@ use failure::Error;
 use foreign_types::ForeignTypeRef;
 use foreign_types_shared::ForeignTypeRef as OtherForeignTypeRef;
 
+use std::env;
 use std::mem;
 use std::os::raw::c_void;
 
@@ -29,14 +30,57 @@ use tokio::stream::StreamExt;
 use tokio::sync::mpsc::channel;
 use tokio::time::DelayQueue;
 
+mod qruff_cmd_broadcast;
+mod qruff_cmd_endpoint;
+mod qruff_cmd_poll;
+mod qruff_cmd_scheduler;
 mod qruff_modbus;
+mod qruff_modbus_decode;
+mod qruff_modbus_frame;
+mod qruff_modbus_gateway;
+mod qruff_modbus_scan;
+mod qruff_modbus_server;
+mod qruff_modbus_sim;
+mod qruff_modbus_stats;
+mod qruff_modbus_trace;
 mod qruff_module;
 mod utils;
 
-use qruff_modbus::{qruff_rtu_setup_settle_promise, SerialConfig, qruff_rtu_setup, register_rtu_context_class, rtu_setup, RtuContext, rtu_operation, qruff_rtu_operation_settle_promise, qruff_rtu_read_holding_registers, qruff_rtu_context_class_id, RtuOperation };
-use qruff_module::{js_init_module_qruff, CmdGenerator, Cmd};
+use qruff_cmd_broadcast::{CmdBroadcast, CmdReceiver, SubscribeOptions};
+use qruff_cmd_endpoint::{cmd_endpoint_iterate, cmd_endpoint_listen, js_iter_result, qruff_cmd_endpoint_settle_next};
+use qruff_cmd_poll::{cmd_poll_loop, js_cmd_poll, CmdPoll};
+use qruff_cmd_scheduler::CmdScheduler;
+use qruff_modbus::{qruff_rtu_setup_settle_promise, parse_parity, Framing, SerialConfig, ServedPort, qruff_rtu_setup, TcpConfig, qruff_tcp_setup, tcp_setup, register_rtu_context_class, rtu_setup, RtuContext, rtu_operation, qruff_rtu_operation_settle_promise, RtuResponse, ModbusError, ModbusErrorCode, js_modbus_error, js_bool_array, opt_unit, rtu_connect, qruff_rtu_context_class_id, RtuOperation, RtuCallOptions,
+    qruff_rtu_read_holding_registers, qruff_rtu_read_input_registers, qruff_rtu_read_coils, qruff_rtu_read_discrete_inputs,
+    qruff_rtu_write_single_register, qruff_rtu_write_multiple_registers, qruff_rtu_write_single_coil, qruff_rtu_write_multiple_coils,
+    qruff_rtu_read_write_multiple_registers, qruff_rtu_masked_write_register, qruff_rtu_queue_depth,
+    qruff_rtu_read_device_identification, qruff_rtu_report_server_id, qruff_rtu_diagnostics, qruff_rtu_get_comm_event_counter,
+    execute_operation, js_rtu_response };
+use qruff_modbus_decode::{decode_registers, ByteOrder, DataType, DecodedValue, RegisterDecode};
+use qruff_modbus_frame::{
+    ascii_frame, ascii_unframe, decode_request, decode_response, encode_exception, encode_request, encode_response, mbap_frame, read_ascii_line,
+    read_mbap_frame, read_rtu_frame, rtu_frame, rtu_request_len, rtu_response_len, rtu_unframe, ExceptionResponse, Request, Response,
+    EXCEPTION_GATEWAY_PATH_UNAVAILABLE, EXCEPTION_GATEWAY_TARGET_FAILED, EXCEPTION_ILLEGAL_DATA_ADDRESS,
+    EXCEPTION_ILLEGAL_FUNCTION, EXCEPTION_SLAVE_DEVICE_FAILURE,
+    device_identification_request, parse_comm_event_counter, parse_device_identification, parse_diagnostics,
+    parse_report_server_id, ServerId, FC_DIAGNOSTICS, FC_ENCAPSULATED_INTERFACE, FC_GET_COMM_EVENT_COUNTER,
+    FC_MASK_WRITE_REGISTER, FC_REPORT_SERVER_ID, MEI_READ_DEVICE_ID, hex, is_checksum_error,
+};
+use qruff_modbus_gateway::{modbus_gateway_start, qruff_modbus_gateway, run_gateway_cli, GatewayConfig};
+use qruff_modbus_scan::{modbus_scan, qruff_modbus_scan, qruff_modbus_scan_settle_promise, run_scan_cli, ScanConfig, ScanHit};
+use qruff_modbus_server::{
+    answer_request, modbus_server_start, qruff_modbus_server, qruff_modbus_server_call_handler, run_server, serve_tcp, qruff_modbus_server_class_id,
+    qruff_modbus_server_close, qruff_modbus_server_get, qruff_modbus_server_set, qruff_modbus_server_settle_promise,
+    register_modbus_server_class, ModbusServer, RegisterTables, ServerConfig, ServerReply, ServerRequest, MAX_TABLE_SIZE,
+};
+use qruff_modbus_sim::{qruff_modbus_server_inject, qruff_modbus_simulator, run_simulator_cli, FaultAction, Faults, PtyMaster};
+use qruff_modbus_stats::{qruff_rtu_reset_stats, qruff_rtu_stats, ModbusStats};
+use qruff_modbus_trace::{qruff_modbus_trace_call_listener, qruff_rtu_trace, TraceFrame, TraceSink, TraceTarget};
+use qruff_module::{js_init_module_qruff, CmdList, Cmd, GeneratorControl};
 use utils::{
-    check_msg_queue, eval_buf, fs_readall, jsc_module_loader, MsgType, RJSPromise, RJSTimerHandler, RRIdGenerator, RRIdManager, RespType, RuffCtx,
+    check_msg_queue, eval_buf, fs_readall, jsc_module_loader, MsgType, RJSCallback, RJSPromise, RJSTimerHandler, RRIdGenerator, RRIdManager, RespType, RuffCtx, XorShift, CmdGeneratorTask,
+    js_arg, js_call_listener, js_get_option, js_get_property, js_is_undefined, js_new_array, js_new_json, js_new_value, js_set_property, js_throw_type_error, js_to_bool, js_to_f64,
+    js_to_integer, js_to_string, js_to_vec, settle_promise,
 };
 
 
@@ -85,6 +129,84 @@ pub struct Opt {
     args: Vec<String>,
 }
 
+/// `qruff modbus <command>`, tools run instead of a script.
+#[derive(Debug, StructOpt)]
+#[structopt(name = "qruff modbus", about = "Modbus tools")]
+pub enum ModbusCmd {
+    /// Probe a serial bus for devices answering at each baud rate, parity and unit id
+    Scan {
+        /// Serial port to scan
+        #[structopt(long = "port", name = "PATH")]
+        port: String,
+
+        /// Baud rates to try, comma separated
+        #[structopt(long = "bauds", default_value = "9600")]
+        bauds: String,
+
+        /// Parities to try, comma separated
+        #[structopt(long = "parities", default_value = "even,none")]
+        parities: String,
+
+        /// Unit ids to probe, e.g. 1-247 or 1,2,10-20
+        #[structopt(long = "units", default_value = "1-247")]
+        units: String,
+
+        /// Serial framing, rtu or ascii
+        #[structopt(long = "framing", default_value = "rtu")]
+        framing: String,
+
+        /// How long each probe waits for an answer
+        #[structopt(long = "timeout-ms", default_value = "100")]
+        timeout_ms: u64,
+    },
+
+    /// Simulate a device on a pseudo terminal, printing the serial port to open
+    Sim {
+        /// JSON register map of the device
+        #[structopt(long = "map", name = "FILE")]
+        map: String,
+
+        /// Symlink to the serial port, removed on exit
+        #[structopt(long = "link")]
+        link: Option<String>,
+    },
+
+    /// Forward modbus TCP requests to the devices of a serial bus
+    Gateway {
+        /// Address to listen on, e.g. 0.0.0.0:502
+        #[structopt(long = "listen", name = "ADDR")]
+        listen: String,
+
+        /// Serial port to forward to
+        #[structopt(long = "serial", name = "PATH")]
+        serial: String,
+
+        /// Baud rate of the serial port
+        #[structopt(long = "baud", default_value = "9600")]
+        baud: u32,
+
+        /// Data bits, 7 or 8 (default: 7 for ascii framing, 8 otherwise)
+        #[structopt(long = "data-bits")]
+        data_bits: Option<u8>,
+
+        /// Parity, none, even or odd
+        #[structopt(long = "parity", default_value = "none")]
+        parity: String,
+
+        /// Stop bits, 1 or 2
+        #[structopt(long = "stop-bits", default_value = "1")]
+        stop_bits: u8,
+
+        /// Serial framing, rtu or ascii
+        #[structopt(long = "framing", default_value = "rtu")]
+        framing: String,
+
+        /// Unit ids forwarded as TCP=RTU pairs, e.g. 1=3,2=4 (default: all, unchanged)
+        #[structopt(long = "unit-map", name = "MAP")]
+        unit_map: Option<String>,
+    },
+}
+
 cfg_if! {
     if #[cfg(any(target_os = "macos", target_os = "ios"))] {
         const MALLOC_OVERHEAD: usize = 0;
@@ -201,6 +323,19 @@ fn main() -> Result<(), Error> {
     let mut ruff_ctx = RuffCtx::new(msg_tx, id_generator, Rc::clone(&request_msg));
     let mut timer_queue: DelayQueue<RJSTimerHandler> = DelayQueue::new();
 
+    if env::args().nth(1).as_deref() == Some("modbus") {
+        return match ModbusCmd::from_iter(env::args().skip(1)) {
+            ModbusCmd::Scan { port, bauds, parities, units, framing, timeout_ms } => {
+                run_scan_cli(ScanConfig::new(port, &bauds, &parities, &units, &framing, timeout_ms)?)
+            },
+            ModbusCmd::Sim { map, link } => run_simulator_cli(&map, link.as_deref()),
+            ModbusCmd::Gateway { listen, serial, baud, data_bits, parity, stop_bits, framing, unit_map } => {
+                let serial = SerialConfig::line(serial, baud, data_bits, &parity, stop_bits, &framing)?;
+                run_gateway_cli(&listen, serial, unit_map.as_deref())
+            },
+        };
+    }
+
     let opt = Opt::from_clap(
         &Opt::clap()
             .version(qjs::LONG_VERSION.as_str())
@@ -0,0 +1,255 @@
+//! Fan-out of the commands of one generator to any number of subscribers.
+//!
+//! Endpoints and attached modbus contexts subscribe to a generator, each
+//! optionally to a few command ids only, and get their own queue of
+//! `capacity` commands. A subscriber that doesn't keep up is handled by its
+//! policy: `block` holds the generator back until there is room again,
+//! `dropOldest` loses the oldest queued commands (the queue then holds at
+//! least `capacity`) and `error` ends the subscription with an error.
+//! Unsubscribing, or the generator stopping, ends the subscription once the
+//! queued commands are taken. Dropping the receiving end unsubscribes too.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use failure::{format_err, Error};
+use tokio::sync::broadcast;
+use tokio::sync::mpsc::{self, error::TrySendError};
+
+use crate::{ffi, js_get_option, js_to_integer, js_to_string, js_to_vec, Cmd, ContextRef};
+
+pub const DEFAULT_SUBSCRIBER_CAPACITY: usize = 100;
+pub const MAX_SUBSCRIBER_CAPACITY: usize = 65536;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SlowPolicy {
+    Block,
+    DropOldest,
+    Error,
+}
+
+impl std::str::FromStr for SlowPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "block" => Ok(SlowPolicy::Block),
+            "dropOldest" => Ok(SlowPolicy::DropOldest),
+            "error" => Ok(SlowPolicy::Error),
+            _ => Err(format_err!("policy must be block, dropOldest or error, got '{}'", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SubscribeOptions {
+    /// only these commands, all of them when `None`
+    pub ids: Option<Vec<String>>,
+    pub policy: SlowPolicy,
+    pub capacity: usize,
+}
+
+impl Default for SubscribeOptions {
+    fn default() -> Self {
+        SubscribeOptions {
+            ids: None,
+            policy: SlowPolicy::Block,
+            capacity: DEFAULT_SUBSCRIBER_CAPACITY,
+        }
+    }
+}
+
+impl SubscribeOptions {
+    /// `{ids, policy, capacity}`, all optional.
+    pub unsafe fn from_js(ctxt: &ContextRef, opts: ffi::JSValue) -> Result<Self, Error> {
+        let options = SubscribeOptions {
+            ids: js_get_option(ctxt, opts, "ids", |v, name| {
+                js_to_vec(ctxt, v, name, |item, name| js_to_string(ctxt, item, name))
+            })?,
+            policy: js_get_option(ctxt, opts, "policy", |v, name| js_to_string(ctxt, v, name))?
+                .map(|policy| policy.parse())
+                .transpose()?
+                .unwrap_or(SlowPolicy::Block),
+            capacity: js_get_option(ctxt, opts, "capacity", |v, name| js_to_integer(ctxt, v, name))?
+                .unwrap_or(DEFAULT_SUBSCRIBER_CAPACITY),
+        };
+        if options.capacity == 0 || options.capacity > MAX_SUBSCRIBER_CAPACITY {
+            return Err(format_err!(
+                "capacity must be between 1 and {}, got {}",
+                MAX_SUBSCRIBER_CAPACITY,
+                options.capacity
+            ));
+        }
+        Ok(options)
+    }
+}
+
+#[derive(Debug)]
+enum SubscriberTx {
+    Queue(mpsc::Sender<Cmd>),
+    Lossy(broadcast::Sender<Cmd>),
+}
+
+#[derive(Debug)]
+struct Subscriber {
+    id: u32,
+    ids: Option<Vec<String>>,
+    policy: SlowPolicy,
+    tx: SubscriberTx,
+    /// set when dropped for falling behind with the `error` policy
+    overflowed: Arc<AtomicBool>,
+}
+
+impl Subscriber {
+    fn wants(&self, cmd: &Cmd) -> bool {
+        self.ids.as_ref().map_or(true, |ids| ids.contains(&cmd.id))
+    }
+}
+
+#[derive(Debug)]
+enum ReceiverKind {
+    Queue(mpsc::Receiver<Cmd>),
+    Lossy(broadcast::Receiver<Cmd>),
+}
+
+/// The end of one subscription, taken by whatever consumes the commands.
+#[derive(Debug)]
+pub struct CmdReceiver {
+    kind: ReceiverKind,
+    overflowed: Arc<AtomicBool>,
+    capacity: usize,
+    broadcast: Arc<CmdBroadcast>,
+    id: u32,
+}
+
+impl Drop for CmdReceiver {
+    /// Nobody takes the commands anymore, the generator doesn't need to wait on them.
+    fn drop(&mut self) {
+        self.broadcast.unsubscribe(self.id);
+    }
+}
+
+impl CmdReceiver {
+    /// The next command, `None` once the subscription ended, an error when it was
+    /// dropped for falling behind.
+    pub async fn recv(&mut self) -> Result<Option<Cmd>, Error> {
+        let cmd = match self.kind {
+            ReceiverKind::Queue(ref mut rx) => rx.recv().await,
+            ReceiverKind::Lossy(ref mut rx) => loop {
+                match rx.recv().await {
+                    Ok(cmd) => break Some(cmd),
+                    Err(broadcast::RecvError::Lagged(skipped)) => debug!("subscriber dropped {} commands", skipped),
+                    Err(broadcast::RecvError::Closed) => break None,
+                }
+            },
+        };
+        match cmd {
+            None if self.overflowed.load(Ordering::SeqCst) => Err(format_err!(
+                "the subscriber fell more than {} commands behind",
+                self.capacity
+            )),
+            cmd => Ok(cmd),
+        }
+    }
+}
+
+/// The subscribers of one generator, shared between its JS object and its task.
+#[derive(Debug)]
+pub struct CmdBroadcast {
+    /// `None` once the generator stopped
+    subscribers: Mutex<Option<Vec<Subscriber>>>,
+}
+
+impl CmdBroadcast {
+    pub fn new() -> Self {
+        CmdBroadcast {
+            subscribers: Mutex::new(Some(Vec::new())),
+        }
+    }
+
+    /// Adds the subscriber `id` to `broadcast`, whose subscription is already over when the generator stopped.
+    pub fn subscribe(broadcast: &Arc<CmdBroadcast>, id: u32, options: SubscribeOptions) -> CmdReceiver {
+        let overflowed = Arc::new(AtomicBool::new(false));
+        let (tx, kind) = match options.policy {
+            SlowPolicy::DropOldest => {
+                let (tx, rx) = broadcast::channel(options.capacity);
+                (SubscriberTx::Lossy(tx), ReceiverKind::Lossy(rx))
+            },
+            SlowPolicy::Block | SlowPolicy::Error => {
+                let (tx, rx) = mpsc::channel(options.capacity);
+                (SubscriberTx::Queue(tx), ReceiverKind::Queue(rx))
+            },
+        };
+        if let Some(ref mut subscribers) = *broadcast.subscribers.lock().unwrap() {
+            subscribers.push(Subscriber {
+                id,
+                ids: options.ids,
+                policy: options.policy,
+                tx,
+                overflowed: overflowed.clone(),
+            });
+        }
+        CmdReceiver {
+            kind,
+            overflowed,
+            capacity: options.capacity,
+            broadcast: broadcast.clone(),
+            id,
+        }
+    }
+
+    /// Ends the subscription `id`, `false` when there was none.
+    pub fn unsubscribe(&self, id: u32) -> bool {
+        match *self.subscribers.lock().unwrap() {
+            Some(ref mut subscribers) => {
+                let len = subscribers.len();
+                subscribers.retain(|subscriber| subscriber.id != id);
+                subscribers.len() != len
+            },
+            None => false,
+        }
+    }
+
+    /// Ends every subscription, for good.
+    pub fn close(&self) {
+        self.subscribers.lock().unwrap().take();
+    }
+
+    /// Queues `cmd` for every subscriber wanting it, waiting on the blocking ones that are full.
+    pub async fn send(&self, cmd: &Cmd) {
+        let mut blocked = Vec::new();
+        {
+            let mut subscribers = self.subscribers.lock().unwrap();
+            let subscribers = match *subscribers {
+                Some(ref mut subscribers) => subscribers,
+                None => return,
+            };
+            let mut gone = Vec::new();
+            for subscriber in subscribers.iter_mut().filter(|subscriber| subscriber.wants(cmd)) {
+                let kept = match subscriber.tx {
+                    SubscriberTx::Lossy(ref tx) => tx.send(cmd.clone()).is_ok(),
+                    SubscriberTx::Queue(ref mut tx) => match tx.try_send(cmd.clone()) {
+                        Ok(()) => true,
+                        Err(TrySendError::Full(cmd)) if subscriber.policy == SlowPolicy::Block => {
+                            blocked.push((tx.clone(), cmd));
+                            true
+                        },
+                        Err(TrySendError::Full(_)) => {
+                            subscriber.overflowed.store(true, Ordering::SeqCst);
+                            false
+                        },
+                        Err(TrySendError::Closed(_)) => false,
+                    },
+                };
+                if !kept {
+                    gone.push(subscriber.id);
+                }
+            }
+            subscribers.retain(|subscriber| !gone.contains(&subscriber.id));
+        }
+        for (mut tx, cmd) in blocked {
+            // a receiver gone meanwhile is dropped on the next command
+            let _ = tx.send(cmd).await;
+        }
+    }
+}
@@ -0,0 +1,70 @@
+//! Hands the commands reaching a `CmdEndpoint` to JS, either to a callback,
+//! `endpoint.onCmd(fn)`, or one at a time to `for await (const cmd of endpoint)`.
+//!
+//! Each command is the object it was configured as, `fn` gets them as they
+//! are triggered until it detaches, see `js_call_listener`, which ends the
+//! subscription of the endpoint. The iteration ends once the generator
+//! stopped, or the endpoint unsubscribed, and every command it sent was taken.
+//! A subscription dropped for falling behind rejects the pending `next()`.
+
+use failure::Error;
+use tokio::sync::mpsc::{Sender, UnboundedReceiver};
+
+use crate::{ffi, Cmd, CmdReceiver, ContextRef, RJSPromise, RespType, js_new_json, js_new_value, js_set_property, settle_promise};
+
+/// Passes every command coming out of `rx` to the JS thread, tagged with the listener `id`.
+pub async fn cmd_endpoint_listen(mut rx: CmdReceiver, mut tx: Sender<RespType>, id: u32) {
+    loop {
+        match rx.recv().await {
+            Ok(Some(cmd)) => {
+                if tx.send(RespType::EndpointCmd(id, cmd)).await.is_err() {
+                    return;
+                }
+            },
+            Ok(None) => break,
+            Err(err) => {
+                warn!("onCmd: {}", err);
+                break;
+            },
+        }
+    }
+    let _ = tx.send(RespType::CmdEndpointClosed(id)).await;
+}
+
+/// Answers each promise id coming out of `next` with the next command out of `rx`, `None` once it closed.
+pub async fn cmd_endpoint_iterate(mut rx: CmdReceiver, mut next: UnboundedReceiver<u32>, mut tx: Sender<RespType>) {
+    while let Some(job_id) = next.recv().await {
+        let cmd = rx.recv().await;
+        if tx.send(RespType::EndpointNext(job_id, cmd)).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// `{value, done}`, as the promises of an async iterator resolve to.
+pub unsafe fn js_iter_result(ctxt: &ContextRef, cmd: Option<Cmd>) -> ffi::JSValue {
+    let obj = ffi::JS_NewObject(ctxt.as_ptr());
+    let done = cmd.is_none();
+    let value = match cmd {
+        Some(cmd) => js_new_json(ctxt, &cmd),
+        None => ffi::UNDEFINED,
+    };
+    js_set_property(ctxt, obj, "value", value);
+    js_set_property(ctxt, obj, "done", js_new_value(ctxt, done));
+    obj
+}
+
+pub fn qruff_cmd_endpoint_settle_next<'a>(promise: RJSPromise<'a>, cmd: Result<Option<Cmd>, Error>) {
+    let ctxt = promise.ctxt;
+    let result = unsafe {
+        match cmd {
+            Ok(cmd) => Ok(js_iter_result(ctxt, cmd)),
+            Err(err) => {
+                let obj = ffi::JS_NewError(ctxt.as_ptr());
+                js_set_property(ctxt, obj, "message", js_new_value(ctxt, err.to_string()));
+                Err(obj)
+            },
+        }
+    };
+    settle_promise(&promise, result);
+}
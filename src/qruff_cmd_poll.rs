@@ -0,0 +1,71 @@
+//! `cmd_generator.attach(ctx, callback, options)`: the commands a generator triggers
+//! are read off a modbus context and the results handed to `callback`.
+//!
+//! Each command reads `reg_len` registers or bits of its `function` table
+//! from `reg_offset`, the callback gets `{id, value, unitOfMeasure}` with the
+//! value decoded as the command's `dataType` and scaled, or `{id, error}` when
+//! the read failed. Once the callback detaches, see `js_call_listener`, the
+//! reads stop and the context unsubscribes.
+
+use tokio::sync::mpsc::Sender;
+
+use crate::{
+    execute_operation, js_modbus_error, js_rtu_response, CmdReceiver, ModbusError, RtuCallOptions, RtuContext, RtuResponse,
+    ffi, ContextRef, RespType,
+    js_new_value, js_set_property,
+};
+
+/// Result of the read of one triggered command.
+#[derive(Debug)]
+pub struct CmdPoll {
+    pub id: String,
+    pub unit_of_measure: Option<String>,
+    pub result: Result<RtuResponse, ModbusError>,
+}
+
+/// Reads every command coming out of `rx` off `context`, one at a time as they share the bus.
+pub async fn cmd_poll_loop(mut rx: CmdReceiver, context: RtuContext, mut tx: Sender<RespType>, id: u32) {
+    loop {
+        let cmd = match rx.recv().await {
+            Ok(Some(cmd)) => cmd,
+            Ok(None) => break,
+            Err(err) => {
+                warn!("cmd poll: {}", err);
+                break;
+            },
+        };
+        let options = RtuCallOptions {
+            unit: cmd.unit,
+            // checked when the generator was created
+            decode: cmd.decode().unwrap_or_default(),
+            ..RtuCallOptions::default()
+        };
+        let result = execute_operation(&context, &cmd.operation(), &options).await.map(|response| match response {
+            RtuResponse::Decoded(value) => RtuResponse::Decoded(cmd.scale_value(value)),
+            response => response,
+        });
+        let poll = CmdPoll {
+            id: cmd.id,
+            unit_of_measure: cmd.unit_of_measure,
+            result,
+        };
+        if tx.send(RespType::CmdPoll(id, poll)).await.is_err() {
+            return;
+        }
+    }
+    let _ = tx.send(RespType::CmdPollStopped(id)).await;
+}
+
+/// `{id, value, unitOfMeasure}` or `{id, error}`.
+pub unsafe fn js_cmd_poll(ctxt: &ContextRef, poll: CmdPoll) -> ffi::JSValue {
+    let obj = ffi::JS_NewObject(ctxt.as_ptr());
+    js_set_property(ctxt, obj, "id", js_new_value(ctxt, poll.id));
+    if let Some(unit_of_measure) = poll.unit_of_measure {
+        js_set_property(ctxt, obj, "unitOfMeasure", js_new_value(ctxt, unit_of_measure));
+    }
+    match poll.result {
+        Ok(response) => js_set_property(ctxt, obj, "value", js_rtu_response(ctxt, response)),
+        Err(err) => js_set_property(ctxt, obj, "error", js_modbus_error(ctxt, &err)),
+    }
+    obj
+}
@@ -0,0 +1,218 @@
+//! When the commands of a generator are due.
+//!
+//! Every command keeps its own deadline, `interval` milliseconds after the
+//! previous one, shifted by `phase` at start and by up to `jitter` at each
+//! firing. Deadlines follow the schedule rather than the firings, so a late
+//! firing doesn't push the next ones back, and the firings a slow consumer
+//! made the generator miss are skipped rather than sent in a burst.
+//!
+//! Commands added, removed or updated on a running generator leave the
+//! deadlines of the others alone.
+
+use tokio::time::{Duration, Instant};
+
+use crate::{Cmd, XorShift};
+
+/// Anywhere from 0 to `max` milliseconds.
+fn jitter(random: &mut XorShift, max: u64) -> Duration {
+    if max == 0 {
+        return Duration::from_millis(0);
+    }
+    Duration::from_millis((random.next_f64() * (max + 1) as f64) as u64)
+}
+
+#[derive(Debug)]
+struct Slot {
+    cmd: Cmd,
+    /// deadline without jitter
+    base: Instant,
+    /// when the command fires next
+    due: Instant,
+}
+
+#[derive(Debug)]
+pub struct CmdScheduler {
+    slots: Vec<Slot>,
+    random: XorShift,
+}
+
+impl CmdScheduler {
+    /// Schedules `cmds` from `start`, each firing first after its phase.
+    pub fn new(cmds: Vec<Cmd>, start: Instant) -> Self {
+        let mut scheduler = CmdScheduler {
+            slots: Vec::with_capacity(cmds.len()),
+            random: XorShift::new(),
+        };
+        for cmd in cmds {
+            scheduler.add(cmd, start);
+        }
+        scheduler
+    }
+
+    /// Schedules `cmd` from `start`, replacing any command of the same id.
+    pub fn add(&mut self, cmd: Cmd, start: Instant) {
+        self.remove(&cmd.id);
+        let base = start + Duration::from_millis(cmd.phase);
+        let due = base + jitter(&mut self.random, cmd.jitter);
+        self.slots.push(Slot { cmd, base, due });
+    }
+
+    /// Unschedules the command `id`, `false` when there was none.
+    pub fn remove(&mut self, id: &str) -> bool {
+        let len = self.slots.len();
+        self.slots.retain(|slot| slot.cmd.id != id);
+        self.slots.len() != len
+    }
+
+    /// Replaces the command of the same id, a new interval counting from its last firing.
+    pub fn update(&mut self, cmd: Cmd, now: Instant) {
+        let slot = match self.slots.iter_mut().find(|slot| slot.cmd.id == cmd.id) {
+            Some(slot) => slot,
+            None => return self.add(cmd, now),
+        };
+        if cmd.interval != slot.cmd.interval {
+            // the previous firing was one old interval before the next one
+            slot.base = slot
+                .base
+                .checked_sub(Duration::from_millis(slot.cmd.interval))
+                .map_or(now, |last| last + Duration::from_millis(cmd.interval))
+                .max(now);
+        }
+        if cmd.interval != slot.cmd.interval || cmd.jitter != slot.cmd.jitter {
+            slot.due = slot.base + jitter(&mut self.random, cmd.jitter);
+        }
+        slot.cmd = cmd;
+    }
+
+    /// The earliest deadline, `None` once there is nothing to schedule.
+    pub fn next_due(&self) -> Option<Instant> {
+        self.slots.iter().map(|slot| slot.due).min()
+    }
+
+    /// The commands due at `now`, in the order they were given, each moved to its next deadline.
+    pub fn take_due(&mut self, now: Instant) -> Vec<Cmd> {
+        let mut due = Vec::new();
+        for slot in self.slots.iter_mut().filter(|slot| slot.due <= now) {
+            let interval = slot.cmd.interval;
+            slot.base += Duration::from_millis(interval);
+            if slot.base <= now {
+                let missed = (now - slot.base).as_millis() as u64 / interval + 1;
+                slot.base += Duration::from_millis(missed * interval);
+            }
+            slot.due = slot.base + jitter(&mut self.random, slot.cmd.jitter);
+            due.push(slot.cmd.clone());
+        }
+        due
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cmd(id: &str, interval: u64, phase: u64, jitter: u64) -> Cmd {
+        serde_json::from_value(serde_json::json!({
+            "id": id,
+            "reg_offset": 0,
+            "reg_len": 1,
+            "interval": interval,
+            "phase": phase,
+            "jitter": jitter,
+        }))
+        .unwrap()
+    }
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    fn ids(cmds: Vec<Cmd>) -> Vec<String> {
+        cmds.into_iter().map(|cmd| cmd.id).collect()
+    }
+
+    #[test]
+    fn missed_firings_are_skipped() {
+        let start = Instant::now();
+        let mut scheduler = CmdScheduler::new(vec![cmd("a", 100, 0, 0)], start);
+        assert_eq!(ids(scheduler.take_due(start)), ["a"]);
+        assert_eq!(scheduler.next_due(), Some(start + ms(100)));
+
+        // three deadlines went by, the command fires once and keeps to its schedule
+        assert_eq!(ids(scheduler.take_due(start + ms(350))), ["a"]);
+        assert_eq!(scheduler.next_due(), Some(start + ms(400)));
+        assert!(scheduler.take_due(start + ms(399)).is_empty());
+
+        // a late firing doesn't push the next one back
+        assert_eq!(ids(scheduler.take_due(start + ms(430))), ["a"]);
+        assert_eq!(scheduler.next_due(), Some(start + ms(500)));
+    }
+
+    #[test]
+    fn phase_offsets_the_first_firing() {
+        let start = Instant::now();
+        let mut scheduler = CmdScheduler::new(vec![cmd("a", 100, 0, 0), cmd("b", 100, 50, 0)], start);
+        assert_eq!(ids(scheduler.take_due(start)), ["a"]);
+        assert_eq!(scheduler.next_due(), Some(start + ms(50)));
+        assert!(scheduler.take_due(start + ms(49)).is_empty());
+        assert_eq!(ids(scheduler.take_due(start + ms(50))), ["b"]);
+        assert_eq!(ids(scheduler.take_due(start + ms(100))), ["a"]);
+        assert_eq!(ids(scheduler.take_due(start + ms(150))), ["b"]);
+        assert_eq!(ids(scheduler.take_due(start + ms(200))), ["a"]);
+    }
+
+    #[test]
+    fn jitter_stays_within_bounds() {
+        let start = Instant::now();
+        let mut scheduler = CmdScheduler::new(vec![cmd("a", 100, 0, 20)], start);
+        for firing in 0..200 {
+            let base = start + ms(firing * 100);
+            let due = scheduler.next_due().unwrap();
+            assert!(due >= base && due <= base + ms(20), "firing {} due {:?} after its base", firing, due - base);
+            assert_eq!(ids(scheduler.take_due(due)), ["a"]);
+        }
+    }
+
+    #[test]
+    fn an_interval_change_counts_from_the_last_firing() {
+        let start = Instant::now();
+        let mut scheduler = CmdScheduler::new(vec![cmd("a", 100, 0, 0)], start);
+        assert_eq!(ids(scheduler.take_due(start)), ["a"]);
+
+        scheduler.update(cmd("a", 300, 0, 0), start + ms(30));
+        assert_eq!(scheduler.next_due(), Some(start + ms(300)));
+
+        // a new interval already over fires right away
+        scheduler.update(cmd("a", 20, 0, 0), start + ms(50));
+        assert_eq!(scheduler.next_due(), Some(start + ms(50)));
+        assert_eq!(ids(scheduler.take_due(start + ms(50))), ["a"]);
+        assert_eq!(scheduler.next_due(), Some(start + ms(70)));
+
+        // the same interval leaves the deadline alone
+        scheduler.update(cmd("a", 20, 5, 0), start + ms(60));
+        assert_eq!(scheduler.next_due(), Some(start + ms(70)));
+    }
+
+    #[test]
+    fn add_and_remove_leave_the_others_alone() {
+        let start = Instant::now();
+        let mut scheduler = CmdScheduler::new(vec![cmd("a", 100, 0, 0)], start);
+        assert_eq!(ids(scheduler.take_due(start)), ["a"]);
+
+        scheduler.add(cmd("b", 100, 10, 0), start + ms(30));
+        assert_eq!(scheduler.next_due(), Some(start + ms(40)));
+        assert_eq!(ids(scheduler.take_due(start + ms(40))), ["b"]);
+        assert_eq!(scheduler.next_due(), Some(start + ms(100)));
+
+        // adding an id again replaces the command
+        scheduler.add(cmd("b", 50, 0, 0), start + ms(60));
+        assert_eq!(ids(scheduler.take_due(start + ms(60))), ["b"]);
+        assert_eq!(ids(scheduler.take_due(start + ms(100))), ["a"]);
+        assert_eq!(ids(scheduler.take_due(start + ms(110))), ["b"]);
+
+        assert!(scheduler.remove("b"));
+        assert!(!scheduler.remove("b"));
+        assert_eq!(scheduler.next_due(), Some(start + ms(200)));
+        assert!(scheduler.remove("a"));
+        assert_eq!(scheduler.next_due(), None);
+    }
+}
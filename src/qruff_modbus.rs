@@ -1,22 +1,645 @@
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, HashSet};
 use std::ops::{Deref, DerefMut};
+use std::str::FromStr;
+use std::sync::{Arc, Weak};
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+use std::time::Duration;
 use std::{slice, fmt};
-use failure::Error;
+use std::io;
+use std::net::SocketAddr;
+use failure::{Error, Fail};
+use futures::FutureExt;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::sync::mpsc::Sender;
-use tokio_modbus::client::{rtu, Context, Reader};
-use tokio_serial::{Serial, SerialPortSettings};
+use tokio::sync::{oneshot, Mutex, Notify};
+use tokio::task::JoinHandle;
+use failure::format_err;
+use tokio::net::{lookup_host, TcpStream};
+use tokio::time::{delay_for, delay_until, timeout, Instant};
+use tokio_modbus::client::{tcp, Client, Context, Reader, Writer};
+use tokio_modbus::prelude::{Request as ClientRequest, Response as ClientResponse, Slave, SlaveContext};
+use tokio_serial::{ClearBuffer, DataBits, FlowControl, Parity, Serial, SerialPort, SerialPortSettings, StopBits};
 
 use crate::{
+    decode_registers, ByteOrder, DecodedValue, RegisterDecode,
+    ascii_frame, ascii_unframe, decode_response, device_identification_request, encode_exception, encode_request, encode_response,
+    parse_comm_event_counter, parse_device_identification, parse_diagnostics, parse_report_server_id, ServerId,
+    FC_DIAGNOSTICS, FC_ENCAPSULATED_INTERFACE, FC_GET_COMM_EVENT_COUNTER, FC_MASK_WRITE_REGISTER, FC_REPORT_SERVER_ID, read_ascii_line, read_rtu_frame, rtu_frame,
+    rtu_response_len, rtu_unframe,
+    ExceptionResponse, Request, Response, TraceFrame, TraceSink, ModbusStats, is_checksum_error,
     ffi, mem, ClassId, ContextRef, MsgType, RuffCtx, Runtime,
-    RuntimeRef, Value, RJSPromise, RespType, Args, ForeignTypeRef
+    RuntimeRef, Value, RJSPromise, RespType, Args, ForeignTypeRef,
+    js_arg, js_get_option, js_new_array, js_new_value, js_set_property, js_throw_type_error, js_to_bool,
+    js_to_integer, js_to_string, js_to_vec, js_is_undefined, settle_promise,
 };
 
+/// How frames are delimited on a serial line.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Framing {
+    /// binary frames with a CRC, separated by 3.5 characters of silence
+    Rtu,
+    /// `:` + hex + LRC + CRLF
+    Ascii,
+}
+
+impl FromStr for Framing {
+    type Err = Error;
+
+    fn from_str(framing: &str) -> Result<Framing, Error> {
+        match framing.to_lowercase().as_str() {
+            "rtu" => Ok(Framing::Rtu),
+            "ascii" => Ok(Framing::Ascii),
+            _ => Err(format_err!("framing must be 'rtu' or 'ascii', got '{}'", framing)),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct SerialConfig {
-    path: String,
-    settings: SerialPortSettings,
+    pub path: String,
+    pub settings: SerialPortSettings,
+    pub framing: Framing,
+    pub unit: u8,
+    policy: RetryPolicy,
+    /// bus priority of the context's requests, higher goes first
+    priority: u8,
+}
+
+const DEFAULT_REQUEST_TIMEOUT_MS: u64 = 1000;
+const DEFAULT_RETRIES: u32 = 2;
+const DEFAULT_RETRY_BACKOFF_MS: u64 = 50;
+
+/// How long one request may take and how often it is repeated before giving up.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    timeout: Duration,
+    retries: u32,
+    /// delay before the first retry, doubled on every further one
+    backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            timeout: Duration::from_millis(DEFAULT_REQUEST_TIMEOUT_MS),
+            retries: DEFAULT_RETRIES,
+            backoff: Duration::from_millis(DEFAULT_RETRY_BACKOFF_MS),
+        }
+    }
+}
+
+impl RetryPolicy {
+    fn apply(&self, options: &RetryOptions) -> RetryPolicy {
+        RetryPolicy {
+            timeout: options.timeout.unwrap_or(self.timeout),
+            retries: options.retries.unwrap_or(self.retries),
+            backoff: options.backoff.unwrap_or(self.backoff),
+        }
+    }
+
+    fn backoff_for(&self, retry: u32) -> Duration {
+        self.backoff * 2u32.saturating_pow(retry.min(16))
+    }
+}
+
+/// `{timeoutMs, retries, retryBackoffMs}`, accepted both at setup and per call.
+#[derive(Debug, Default)]
+pub struct RetryOptions {
+    timeout: Option<Duration>,
+    retries: Option<u32>,
+    backoff: Option<Duration>,
+}
+
+impl RetryOptions {
+    unsafe fn from_js(ctxt: &ContextRef, opts: ffi::JSValue) -> Result<RetryOptions, Error> {
+        let timeout = js_get_option(ctxt, opts, "timeoutMs", |v, name| js_to_integer::<u64>(ctxt, v, name))?;
+        if timeout == Some(0) {
+            return Err(format_err!("timeoutMs must be greater than 0"));
+        }
+        Ok(RetryOptions {
+            timeout: timeout.map(Duration::from_millis),
+            retries: js_get_option(ctxt, opts, "retries", |v, name| js_to_integer(ctxt, v, name))?,
+            backoff: js_get_option(ctxt, opts, "retryBackoffMs", |v, name| js_to_integer(ctxt, v, name))?
+                .map(Duration::from_millis),
+        })
+    }
+}
+
+/// The `code` of a rejected modbus promise, exception codes follow the modbus spec.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ModbusErrorCode {
+    IllegalFunction,
+    IllegalDataAddress,
+    IllegalDataValue,
+    SlaveDeviceFailure,
+    Acknowledge,
+    SlaveDeviceBusy,
+    MemoryParityError,
+    GatewayPathUnavailable,
+    GatewayTargetFailed,
+    Timeout,
+    Io,
+    InvalidResponse,
+}
+
+/// Exception code and the description tokio-modbus prints for it.
+const MODBUS_EXCEPTIONS: [(u8, ModbusErrorCode, &str); 9] = [
+    (0x01, ModbusErrorCode::IllegalFunction, "illegal function"),
+    (0x02, ModbusErrorCode::IllegalDataAddress, "illegal data address"),
+    (0x03, ModbusErrorCode::IllegalDataValue, "illegal data value"),
+    (0x04, ModbusErrorCode::SlaveDeviceFailure, "device failure"),
+    (0x05, ModbusErrorCode::Acknowledge, "acknowledge"),
+    (0x06, ModbusErrorCode::SlaveDeviceBusy, "device busy"),
+    (0x08, ModbusErrorCode::MemoryParityError, "memory parity error"),
+    (0x0A, ModbusErrorCode::GatewayPathUnavailable, "gateway path unavailable"),
+    (0x0B, ModbusErrorCode::GatewayTargetFailed, "gateway target device failed to respond"),
+];
+
+impl ModbusErrorCode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ModbusErrorCode::IllegalFunction => "ILLEGAL_FUNCTION",
+            ModbusErrorCode::IllegalDataAddress => "ILLEGAL_DATA_ADDRESS",
+            ModbusErrorCode::IllegalDataValue => "ILLEGAL_DATA_VALUE",
+            ModbusErrorCode::SlaveDeviceFailure => "SLAVE_DEVICE_FAILURE",
+            ModbusErrorCode::Acknowledge => "ACKNOWLEDGE",
+            ModbusErrorCode::SlaveDeviceBusy => "SLAVE_DEVICE_BUSY",
+            ModbusErrorCode::MemoryParityError => "MEMORY_PARITY_ERROR",
+            ModbusErrorCode::GatewayPathUnavailable => "GATEWAY_PATH_UNAVAILABLE",
+            ModbusErrorCode::GatewayTargetFailed => "GATEWAY_TARGET_FAILED",
+            ModbusErrorCode::Timeout => "TIMEOUT",
+            ModbusErrorCode::Io => "IO",
+            ModbusErrorCode::InvalidResponse => "INVALID_RESPONSE",
+        }
+    }
+
+    pub fn exception_code(self) -> Option<u8> {
+        MODBUS_EXCEPTIONS.iter().find(|(_, code, _)| *code == self).map(|(value, _, _)| *value)
+    }
+
+    pub fn from_exception_code(value: u8) -> Option<ModbusErrorCode> {
+        MODBUS_EXCEPTIONS.iter().find(|(v, _, _)| *v == value).map(|(_, code, _)| *code)
+    }
+
+    /// Reverse of `as_str`, for codes set on errors thrown from JS.
+    pub fn from_name(name: &str) -> Option<ModbusErrorCode> {
+        MODBUS_EXCEPTIONS
+            .iter()
+            .map(|(_, code, _)| *code)
+            .find(|code| code.as_str() == name)
+    }
+}
+
+/// Error a modbus promise is rejected with, surfaced to JS as an `Error` carrying the same fields.
+#[derive(Debug, Fail)]
+#[fail(display = "{}", message)]
+pub struct ModbusError {
+    pub code: ModbusErrorCode,
+    pub message: String,
+    pub function: Option<u8>,
+    pub unit: Option<u8>,
+    pub address: Option<u16>,
+}
+
+impl ModbusError {
+    pub fn new(code: ModbusErrorCode, message: String) -> ModbusError {
+        ModbusError {
+            code,
+            message,
+            function: None,
+            unit: None,
+            address: None,
+        }
+    }
+
+    fn with_request(mut self, operation: &RtuOperation, unit: u8) -> ModbusError {
+        self.function = Some(operation.function_code());
+        self.unit = Some(unit);
+        self.address = operation.address();
+        self
+    }
+
+    /// Classifies an error returned by the tokio-modbus client.
+    ///
+    /// Exception responses only reach us as `io::Error`s formatted
+    /// "Modbus function <fc>: <description>", so they are told apart by text.
+    pub fn from_io(err: io::Error) -> ModbusError {
+        let message = err.to_string();
+        let lower = message.to_lowercase();
+        let code = if lower.starts_with("modbus function") {
+            MODBUS_EXCEPTIONS
+                .iter()
+                .find(|(_, _, description)| lower.contains(description))
+                .map_or(ModbusErrorCode::InvalidResponse, |(_, code, _)| *code)
+        } else {
+            match err.kind() {
+                io::ErrorKind::TimedOut => ModbusErrorCode::Timeout,
+                io::ErrorKind::InvalidData => ModbusErrorCode::InvalidResponse,
+                _ => ModbusErrorCode::Io,
+            }
+        };
+        ModbusError::new(code, message)
+    }
+
+    /// Error for an exception answered by the device.
+    pub fn from_exception(exception: ExceptionResponse) -> ModbusError {
+        let (code, description) = MODBUS_EXCEPTIONS
+            .iter()
+            .find(|(value, _, _)| *value == exception.exception)
+            .map_or((ModbusErrorCode::InvalidResponse, "unknown exception"), |(_, code, description)| (*code, *description));
+        ModbusError::new(
+            code,
+            format!("Modbus function {}: {} (exception code {})", exception.function, description, exception.exception),
+        )
+    }
+
+    pub fn from_error(err: Error) -> ModbusError {
+        match err.downcast::<ModbusError>() {
+            Ok(err) => err,
+            Err(err) => match err.downcast::<io::Error>() {
+                Ok(err) => ModbusError::from_io(err),
+                Err(err) => ModbusError::new(ModbusErrorCode::Io, err.to_string()),
+            },
+        }
+    }
+
+    pub fn is_retryable(&self) -> bool {
+        match self.code {
+            ModbusErrorCode::Timeout | ModbusErrorCode::Io | ModbusErrorCode::InvalidResponse => true,
+            _ => false,
+        }
+    }
+}
+
+/// Builds the JS `Error` object a promise is rejected with.
+pub unsafe fn js_modbus_error(ctxt: &ContextRef, err: &ModbusError) -> ffi::JSValue {
+    let obj = ffi::JS_NewError(ctxt.as_ptr());
+    js_set_property(ctxt, obj, "name", js_new_value(ctxt, String::from("ModbusError")));
+    js_set_property(ctxt, obj, "message", js_new_value(ctxt, err.message.clone()));
+    js_set_property(ctxt, obj, "code", js_new_value(ctxt, String::from(err.code.as_str())));
+    if let Some(exception) = err.code.exception_code() {
+        js_set_property(ctxt, obj, "exceptionCode", js_new_value(ctxt, exception as i32));
+    }
+    if let Some(function) = err.function {
+        js_set_property(ctxt, obj, "functionCode", js_new_value(ctxt, function as i32));
+    }
+    if let Some(unit) = err.unit {
+        js_set_property(ctxt, obj, "unit", js_new_value(ctxt, unit as i32));
+    }
+    if let Some(address) = err.address {
+        js_set_property(ctxt, obj, "address", js_new_value(ctxt, address as i32));
+    }
+    obj
+}
+
+const DEFAULT_BAUD_RATE: u32 = 9600;
+const DEFAULT_RTU_UNIT: u8 = 1;
+/// Unit id addressing the device itself on modbus TCP.
+const DEFAULT_TCP_UNIT: u8 = 0xFF;
+const MAX_RTU_UNIT: u8 = 247;
+
+pub unsafe fn opt_unit(ctxt: &ContextRef, opts: ffi::JSValue, max: u8) -> Result<Option<u8>, Error> {
+    match js_get_option(ctxt, opts, "unit", |v, name| js_to_integer::<u8>(ctxt, v, name))? {
+        Some(unit) if unit > max => Err(format_err!("unit must be between 0 and {}, got {}", max, unit)),
+        unit => Ok(unit),
+    }
+}
+
+/// `none`, `even` or `odd`, or their initials.
+pub fn parse_parity(parity: &str) -> Result<Parity, Error> {
+    match parity.to_lowercase().as_str() {
+        "none" | "n" => Ok(Parity::None),
+        "even" | "e" => Ok(Parity::Even),
+        "odd" | "o" => Ok(Parity::Odd),
+        _ => Err(format_err!("parity must be 'none', 'even' or 'odd', got '{}'", parity)),
+    }
+}
+
+pub fn parse_data_bits(bits: u8) -> Result<DataBits, Error> {
+    match bits {
+        5 => Ok(DataBits::Five),
+        6 => Ok(DataBits::Six),
+        7 => Ok(DataBits::Seven),
+        8 => Ok(DataBits::Eight),
+        _ => Err(format_err!("dataBits must be 5, 6, 7 or 8, got {}", bits)),
+    }
+}
+
+pub fn parse_stop_bits(bits: u8) -> Result<StopBits, Error> {
+    match bits {
+        1 => Ok(StopBits::One),
+        2 => Ok(StopBits::Two),
+        _ => Err(format_err!("stopBits must be 1 or 2, got {}", bits)),
+    }
 }
 
-pub struct RtuContext(Context);
+impl SerialConfig {
+    /// Line given on the command line, `data_bits` defaulting to what `framing` carries.
+    pub fn line(
+        path: String,
+        baud: u32,
+        data_bits: Option<u8>,
+        parity: &str,
+        stop_bits: u8,
+        framing: &str,
+    ) -> Result<SerialConfig, Error> {
+        let framing: Framing = framing.parse()?;
+        let policy = RetryPolicy::default();
+        let settings = SerialPortSettings {
+            baud_rate: baud,
+            data_bits: match data_bits {
+                Some(bits) => parse_data_bits(bits)?,
+                None if framing == Framing::Ascii => DataBits::Seven,
+                None => DataBits::Eight,
+            },
+            parity: parse_parity(parity)?,
+            stop_bits: parse_stop_bits(stop_bits)?,
+            timeout: policy.timeout,
+            ..Default::default()
+        };
+        let config = SerialConfig { path, settings, framing, unit: DEFAULT_RTU_UNIT, policy, priority: DEFAULT_PRIORITY };
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Builds the line settings from either a baud rate or an options object
+    /// `{baud, dataBits, parity, stopBits, flowControl, framing, unit, priority}` plus the `RetryOptions`.
+    pub unsafe fn from_js(ctxt: &ContextRef, path: String, opts: ffi::JSValue) -> Result<SerialConfig, Error> {
+        let mut settings = SerialPortSettings {
+            baud_rate: DEFAULT_BAUD_RATE,
+            ..Default::default()
+        };
+        let mut framing = Framing::Rtu;
+        let mut unit = DEFAULT_RTU_UNIT;
+        let mut priority = DEFAULT_PRIORITY;
+        let mut policy = RetryPolicy::default();
+
+        if Value::from(opts).is_number() {
+            settings.baud_rate = js_to_integer(ctxt, opts, "baud")?;
+        } else {
+            if let Some(baud) = js_get_option(ctxt, opts, "baud", |v, name| js_to_integer(ctxt, v, name))? {
+                settings.baud_rate = baud;
+            }
+            if let Some(bits) = js_get_option(ctxt, opts, "dataBits", |v, name| js_to_integer::<u8>(ctxt, v, name))? {
+                settings.data_bits = parse_data_bits(bits)?;
+            }
+            if let Some(parity) = js_get_option(ctxt, opts, "parity", |v, name| js_to_string(ctxt, v, name))? {
+                settings.parity = parse_parity(&parity)?;
+            }
+            if let Some(bits) = js_get_option(ctxt, opts, "stopBits", |v, name| js_to_integer::<u8>(ctxt, v, name))? {
+                settings.stop_bits = parse_stop_bits(bits)?;
+            }
+            if let Some(flow) = js_get_option(ctxt, opts, "flowControl", |v, name| js_to_string(ctxt, v, name))? {
+                settings.flow_control = match flow.to_lowercase().as_str() {
+                    "none" => FlowControl::None,
+                    "software" => FlowControl::Software,
+                    "hardware" => FlowControl::Hardware,
+                    _ => return Err(format_err!("flowControl must be 'none', 'software' or 'hardware', got '{}'", flow)),
+                };
+            }
+            if let Some(name) = js_get_option(ctxt, opts, "framing", |v, name| js_to_string(ctxt, v, name))? {
+                framing = name.parse()?;
+            }
+            policy = policy.apply(&RetryOptions::from_js(ctxt, opts)?);
+            if let Some(default_priority) = js_get_option(ctxt, opts, "priority", |v, name| js_to_integer(ctxt, v, name))? {
+                priority = default_priority;
+            }
+            if let Some(default_unit) = opt_unit(ctxt, opts, MAX_RTU_UNIT)? {
+                unit = default_unit;
+            }
+        }
+        settings.timeout = policy.timeout;
+
+        let config = SerialConfig { path, settings, framing, unit, policy, priority };
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Line probed by a bus scan: a single try, answered within `timeout`.
+    pub fn probe(path: String, baud: u32, parity: Parity, framing: Framing, timeout: Duration) -> Result<SerialConfig, Error> {
+        let policy = RetryPolicy {
+            timeout,
+            retries: 0,
+            ..RetryPolicy::default()
+        };
+        let settings = SerialPortSettings {
+            baud_rate: baud,
+            data_bits: if framing == Framing::Ascii { DataBits::Seven } else { DataBits::Eight },
+            parity,
+            timeout,
+            ..Default::default()
+        };
+        let config = SerialConfig { path, settings, framing, unit: DEFAULT_RTU_UNIT, policy, priority: DEFAULT_PRIORITY };
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Rejects line settings that cannot carry modbus characters in the chosen framing.
+    fn validate(&self) -> Result<(), Error> {
+        let settings = &self.settings;
+        if settings.baud_rate == 0 {
+            return Err(format_err!("baud must be greater than 0"));
+        }
+        if self.framing == Framing::Ascii {
+            // only hex digits go on the wire, 7 data bits are the usual choice
+            if settings.data_bits != DataBits::Seven && settings.data_bits != DataBits::Eight {
+                return Err(format_err!("modbus ASCII framing requires 7 or 8 data bits, got {:?}", settings.data_bits));
+            }
+            return Ok(());
+        }
+        if settings.data_bits != DataBits::Eight {
+            return Err(format_err!("modbus RTU framing requires 8 data bits, got {:?}", settings.data_bits));
+        }
+        // a modbus RTU character is 11 bits: start, 8 data, parity or second stop bit, stop
+        if settings.parity != Parity::None && settings.stop_bits == StopBits::Two {
+            return Err(format_err!(
+                "{:?} parity with 2 stop bits is not a valid modbus character, use 8{}1 or 8N2",
+                settings.parity,
+                if settings.parity == Parity::Even { "E" } else { "O" }
+            ));
+        }
+        Ok(())
+    }
+}
+
+const DEFAULT_TCP_CONNECT_TIMEOUT_MS: u64 = 5000;
+
+#[derive(Debug)]
+pub struct TcpConfig {
+    host: String,
+    port: u16,
+    connect_timeout: Duration,
+    /// serial framing tunneled through the socket, modbus TCP when unset
+    framing: Option<Framing>,
+    unit: u8,
+    policy: RetryPolicy,
+    priority: u8,
+}
+
+const DEFAULT_PRIORITY: u8 = 0;
+
+/// Modbus TCP connection, requests issued concurrently are serialized on the mutex.
+struct TcpLink {
+    /// `None` once a request timed out, its late answer would be read by the next request
+    context: Mutex<Option<Context>>,
+    /// requests waiting on the mutex
+    waiting: AtomicUsize,
+    addr: SocketAddr,
+    connect_timeout: Duration,
+}
+
+impl TcpLink {
+    async fn connect(addr: SocketAddr, connect_timeout: Duration, unit: u8) -> io::Result<Context> {
+        timeout(connect_timeout, tcp::connect_slave(addr, Slave(unit)))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, format!("timed out connecting to {}", addr)))?
+    }
+}
+
+/// Where a context sends its requests.
+#[derive(Clone)]
+enum ModbusClient {
+    Tcp(Arc<TcpLink>),
+    /// serial line shared with every other context opened on the same path
+    Bus(Arc<BusArbiter>),
+}
+
+/// Shared handle to a connected modbus client.
+///
+/// The JS object owns one clone of the handle, every queued operation owns
+/// another one, so the connection lives as long as anybody still uses it.
+#[derive(Clone)]
+pub struct RtuContext {
+    client: ModbusClient,
+    /// unit id used when an operation doesn't pick one
+    unit: u8,
+    policy: RetryPolicy,
+    priority: u8,
+    /// frame trace, shared by every clone so `trace()` also covers queued operations
+    trace: Arc<std::sync::Mutex<Option<TraceSink>>>,
+    stats: Arc<std::sync::Mutex<ModbusStats>>,
+}
+
+impl RtuContext {
+    fn tcp(context: Context, addr: SocketAddr, connect_timeout: Duration, unit: u8, policy: RetryPolicy) -> Self {
+        let link = TcpLink {
+            context: Mutex::new(Some(context)),
+            waiting: AtomicUsize::new(0),
+            addr,
+            connect_timeout,
+        };
+        RtuContext {
+            client: ModbusClient::Tcp(Arc::new(link)),
+            unit,
+            policy,
+            priority: DEFAULT_PRIORITY,
+            trace: Arc::new(std::sync::Mutex::new(None)),
+            stats: Arc::new(std::sync::Mutex::new(ModbusStats::default())),
+        }
+    }
+
+    fn bus(bus: Arc<BusArbiter>, unit: u8, policy: RetryPolicy, priority: u8) -> Self {
+        RtuContext {
+            client: ModbusClient::Bus(bus),
+            unit,
+            policy,
+            priority,
+            trace: Arc::new(std::sync::Mutex::new(None)),
+            stats: Arc::new(std::sync::Mutex::new(ModbusStats::default())),
+        }
+    }
+
+    pub fn unit(&self) -> u8 {
+        self.unit
+    }
+
+    pub fn policy(&self) -> RetryPolicy {
+        self.policy
+    }
+
+    /// Drops the context, when it was the last one on its serial port this
+    /// waits for the port to be closed so it can be opened again right away.
+    pub async fn close(self) {
+        if let ModbusClient::Bus(bus) = self.client {
+            if let Ok(mut bus) = Arc::try_unwrap(bus) {
+                let worker = bus.worker.take();
+                mem::drop(bus);
+                if let Some(worker) = worker {
+                    let _ = worker.await;
+                }
+            }
+        }
+    }
+
+    /// Requests waiting for the connection, from this and every context sharing it.
+    pub fn queue_depth(&self) -> usize {
+        match self.client {
+            ModbusClient::Tcp(ref link) => link.waiting.load(AtomicOrdering::SeqCst),
+            ModbusClient::Bus(ref bus) => bus.queue_depth(),
+        }
+    }
+
+    /// Replaces the frame trace of the context, returning the one it had.
+    pub fn set_trace(&self, trace: Option<TraceSink>) -> Option<TraceSink> {
+        mem::replace(&mut *self.trace.lock().unwrap(), trace)
+    }
+
+    fn trace(&self) -> Option<TraceSink> {
+        self.trace.lock().unwrap().clone()
+    }
+
+    pub fn stats(&self) -> ModbusStats {
+        self.stats.lock().unwrap().clone()
+    }
+
+    /// Starts the counters over, returning the ones dropped.
+    pub fn reset_stats(&self) -> ModbusStats {
+        mem::replace(&mut *self.stats.lock().unwrap(), ModbusStats::default())
+    }
+}
+
+/// Per call options, passed as the last argument of every operation.
+#[derive(Debug, Default)]
+pub struct RtuCallOptions {
+    pub unit: Option<u8>,
+    /// bus priority, overriding the context's
+    pub priority: Option<u8>,
+    pub retry: RetryOptions,
+    /// `{decode, byteOrder}`, turns register reads into numbers, a string or bitfields
+    pub decode: Option<RegisterDecode>,
+}
+
+impl RtuCallOptions {
+    unsafe fn from_js(ctxt: &ContextRef, opts: ffi::JSValue) -> Result<RtuCallOptions, Error> {
+        let data_type = js_get_option(ctxt, opts, "decode", |v, name| js_to_string(ctxt, v, name))?;
+        let order = js_get_option(ctxt, opts, "byteOrder", |v, name| js_to_string(ctxt, v, name))?;
+        let decode = match (data_type, order) {
+            (Some(data_type), order) => Some(RegisterDecode {
+                data_type: data_type.parse()?,
+                order: order.map_or(Ok(ByteOrder::default()), |order| order.parse())?,
+            }),
+            (None, Some(_)) => return Err(format_err!("byteOrder needs a decode type")),
+            (None, None) => None,
+        };
+        Ok(RtuCallOptions {
+            unit: opt_unit(ctxt, opts, u8::max_value())?,
+            priority: js_get_option(ctxt, opts, "priority", |v, name| js_to_integer(ctxt, v, name))?,
+            retry: RetryOptions::from_js(ctxt, opts)?,
+            decode,
+        })
+    }
+
+    fn check(&self, operation: &RtuOperation) -> Result<(), Error> {
+        if let Some(decode) = self.decode {
+            match operation.register_count() {
+                Some(count) => decode.check_count(count)?,
+                None => return Err(format_err!("decode only applies to register reads")),
+            }
+        }
+        Ok(())
+    }
+}
 
 //pub struct RtuContextPtr(Context);
 //impl Deref for RtuContextPtr {
@@ -48,131 +671,1029 @@ impl fmt::Debug for RtuContext {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("RtuContext")
          .field("context", &"inner pointer")
+         .field("unit", &self.unit)
+         .field("policy", &self.policy)
+         .field("priority", &self.priority)
          .finish()
     }
 }
 
 pub fn qruff_rtu_setup_settle_promise<'a>(promise: RJSPromise<'a>, context: Result<RtuContext, Error>) {
-    let (handle, args) = match context {
+    let result = match context {
         Ok(context) => {
             let rtu_setup = promise.ctxt.new_object_class(*QRUFF_RTU_CONTEXT_CLASS_ID);
             rtu_setup.set_opaque(Box::into_raw(Box::new(context)));
-            let args = rtu_setup.into_values(&promise.ctxt);
-            (&promise.resolve, args)
+            Ok(js_new_value(promise.ctxt, rtu_setup))
         },
-        Err(err) => {
-            let mut resp_err = String::new();
-            resp_err.push_str(&format!("QJS Error {:?}", err));
-            let args = resp_err.into_values(&promise.ctxt);
-            (&promise.reject, args)
-        }
+        Err(err) => Err(unsafe { js_modbus_error(promise.ctxt, &ModbusError::from_error(err)) }),
     };
-    unsafe {
-        ffi::JS_Call(
-            promise.ctxt.as_ptr(),
-            handle.raw(),
-            ffi::NULL,
-            1 as i32,
-            args.as_ptr() as *mut _,
-        );
-    }
-    for arg in &args {
-        promise.ctxt.free_value(*arg);
-    }
+    settle_promise(&promise, result);
 }
 
+/// One modbus request issued on a `RtuContext`.
 #[derive(Debug)]
 pub enum RtuOperation {
-    ReadHoldingRegister(Box<RtuContext>, u16, u16),
+    ReadHoldingRegisters(u16, u16),
+    ReadInputRegisters(u16, u16),
+    ReadCoils(u16, u16),
+    ReadDiscreteInputs(u16, u16),
+    WriteSingleRegister(u16, u16),
+    WriteMultipleRegisters(u16, Vec<u16>),
+    WriteSingleCoil(u16, bool),
+    WriteMultipleCoils(u16, Vec<bool>),
+    /// read address, read count, write address, values to write
+    ReadWriteMultipleRegisters(u16, u16, u16, Vec<u16>),
+    /// address, AND mask, OR mask
+    MaskedWriteRegister(u16, u16, u16),
+    /// read device id code, first object id
+    ReadDeviceIdentification(u8, u8),
+    ReportServerId,
+    /// sub-function, data word
+    Diagnostics(u16, u16),
+    GetCommEventCounter,
 }
 
 unsafe impl Send for RtuOperation {}
 unsafe impl Sync for RtuOperation {}
 
-unsafe fn raw_byte_access(s16: &mut [u16]) -> &mut [u8] {
-    slice::from_raw_parts_mut(s16.as_mut_ptr() as *mut u8, s16.len() * 2)
+impl RtuOperation {
+    pub fn function_code(&self) -> u8 {
+        match self {
+            RtuOperation::ReadCoils(..) => 0x01,
+            RtuOperation::ReadDiscreteInputs(..) => 0x02,
+            RtuOperation::ReadHoldingRegisters(..) => 0x03,
+            RtuOperation::ReadInputRegisters(..) => 0x04,
+            RtuOperation::WriteSingleCoil(..) => 0x05,
+            RtuOperation::WriteSingleRegister(..) => 0x06,
+            RtuOperation::WriteMultipleCoils(..) => 0x0F,
+            RtuOperation::WriteMultipleRegisters(..) => 0x10,
+            RtuOperation::MaskedWriteRegister(..) => FC_MASK_WRITE_REGISTER,
+            RtuOperation::ReadWriteMultipleRegisters(..) => 0x17,
+            RtuOperation::ReadDeviceIdentification(..) => FC_ENCAPSULATED_INTERFACE,
+            RtuOperation::ReportServerId => FC_REPORT_SERVER_ID,
+            RtuOperation::Diagnostics(..) => FC_DIAGNOSTICS,
+            RtuOperation::GetCommEventCounter => FC_GET_COMM_EVENT_COUNTER,
+        }
+    }
+
+    /// Number of registers read, for the operations answering with registers.
+    pub fn register_count(&self) -> Option<u16> {
+        match *self {
+            RtuOperation::ReadHoldingRegisters(_, cnt)
+            | RtuOperation::ReadInputRegisters(_, cnt)
+            | RtuOperation::ReadWriteMultipleRegisters(_, cnt, _, _) => Some(cnt),
+            _ => None,
+        }
+    }
+
+    /// First register or coil the operation touches, if it touches any.
+    pub fn address(&self) -> Option<u16> {
+        let addr = match *self {
+            RtuOperation::ReadHoldingRegisters(addr, _)
+            | RtuOperation::ReadInputRegisters(addr, _)
+            | RtuOperation::ReadCoils(addr, _)
+            | RtuOperation::ReadDiscreteInputs(addr, _)
+            | RtuOperation::WriteSingleRegister(addr, _)
+            | RtuOperation::WriteMultipleRegisters(addr, _)
+            | RtuOperation::WriteSingleCoil(addr, _)
+            | RtuOperation::WriteMultipleCoils(addr, _)
+            | RtuOperation::ReadWriteMultipleRegisters(addr, _, _, _)
+            | RtuOperation::MaskedWriteRegister(addr, _, _) => addr,
+            RtuOperation::ReadDeviceIdentification(..)
+            | RtuOperation::ReportServerId
+            | RtuOperation::Diagnostics(..)
+            | RtuOperation::GetCommEventCounter => return None,
+        };
+        Some(addr)
+    }
 }
 
-pub fn qruff_rtu_operation_settle_promise<'a>(promise: RJSPromise<'a>, content: Result<Vec<u16>, Error>) {
-    let (handle, args) = match content {
-        Ok(mut content) => {
-            unsafe {
-                let bytes = raw_byte_access(content.as_mut());
-                (&promise.resolve, promise.ctxt.new_array_buffer_copy(bytes).into_values(&promise.ctxt))
-            }
-        },
-        Err(err) => {
-            let mut resp_err = String::new();
-            resp_err.push_str(&format!("QJS Error {:?}", err));
-            let args = resp_err.into_values(&promise.ctxt);
-            (&promise.reject, args)
+/// Result of one `RtuOperation`, by the shape of its response.
+#[derive(Debug)]
+pub enum RtuResponse {
+    Registers(Vec<u16>),
+    Decoded(DecodedValue),
+    Bits(Vec<bool>),
+    Written,
+    /// response data of the identification and diagnostics functions, before parsing
+    Raw(Vec<u8>),
+    /// conformity level and every object of all pages
+    DeviceIdentification(u8, Vec<(u8, Vec<u8>)>),
+    ServerId(ServerId),
+    /// sub-function, data word
+    Diagnostics(u16, u16),
+    /// status, event count
+    CommEventCounter(u16, u16),
+}
+
+/// Names of the basic and regular device identification objects.
+const DEVICE_ID_OBJECTS: [&str; 7] = [
+    "vendorName",
+    "productCode",
+    "revision",
+    "vendorUrl",
+    "productName",
+    "modelName",
+    "userApplicationName",
+];
+
+unsafe fn js_device_identification(ctxt: &ContextRef, conformity_level: u8, objects: Vec<(u8, Vec<u8>)>) -> ffi::JSValue {
+    let obj = ffi::JS_NewObject(ctxt.as_ptr());
+    let by_id = ffi::JS_NewObject(ctxt.as_ptr());
+    js_set_property(ctxt, obj, "conformityLevel", js_new_value(ctxt, conformity_level as i32));
+    for (id, value) in objects {
+        let value = String::from_utf8_lossy(&value).into_owned();
+        if let Some(name) = DEVICE_ID_OBJECTS.get(id as usize) {
+            js_set_property(ctxt, obj, name, js_new_value(ctxt, value.clone()));
         }
-    };
-    unsafe {
-        ffi::JS_Call(
-            promise.ctxt.as_ptr(),
-            handle.raw(),
-            ffi::NULL,
-            1 as i32,
-            args.as_ptr() as *mut _,
-        );
+        js_set_property(ctxt, by_id, &id.to_string(), js_new_value(ctxt, value));
     }
-    for arg in &args {
-        promise.ctxt.free_value(*arg);
+    js_set_property(ctxt, obj, "objects", by_id);
+    obj
+}
+
+pub unsafe fn js_bool_array(ctxt: &ContextRef, bits: Vec<bool>) -> ffi::JSValue {
+    let values = bits.into_iter().map(|bit| js_new_value(ctxt, bit)).collect();
+    js_new_array(ctxt, values)
+}
+
+/// The JS value an operation resolves with.
+pub unsafe fn js_rtu_response(ctxt: &ContextRef, response: RtuResponse) -> ffi::JSValue {
+    match response {
+        RtuResponse::Registers(content) => {
+            // registers keep the modbus (big endian) byte order whatever the host is
+            let bytes: Vec<u8> = content.iter().flat_map(|word| word.to_be_bytes().to_vec()).collect();
+            js_new_value(ctxt, ctxt.new_array_buffer_copy(&bytes))
+        },
+        RtuResponse::Decoded(DecodedValue::Numbers(values)) => {
+            let values = values.into_iter().map(|value| js_new_value(ctxt, value)).collect();
+            js_new_array(ctxt, values)
+        },
+        RtuResponse::Decoded(DecodedValue::Text(text)) => js_new_value(ctxt, text),
+        RtuResponse::Decoded(DecodedValue::Bits(words)) => {
+            let values = words.into_iter().map(|bits| js_bool_array(ctxt, bits)).collect();
+            js_new_array(ctxt, values)
+        },
+        RtuResponse::Bits(content) => js_bool_array(ctxt, content),
+        RtuResponse::Written => ffi::UNDEFINED,
+        RtuResponse::Raw(data) => js_new_value(ctxt, ctxt.new_array_buffer_copy(&data)),
+        RtuResponse::DeviceIdentification(conformity_level, objects) => {
+            js_device_identification(ctxt, conformity_level, objects)
+        },
+        RtuResponse::ServerId(server_id) => {
+            let obj = ffi::JS_NewObject(ctxt.as_ptr());
+            js_set_property(ctxt, obj, "serverId", js_new_value(ctxt, server_id.id as i32));
+            js_set_property(ctxt, obj, "running", js_new_value(ctxt, server_id.running));
+            let data = ctxt.new_array_buffer_copy(&server_id.additional_data);
+            js_set_property(ctxt, obj, "additionalData", js_new_value(ctxt, data));
+            obj
+        },
+        RtuResponse::Diagnostics(sub_function, data) => {
+            let obj = ffi::JS_NewObject(ctxt.as_ptr());
+            js_set_property(ctxt, obj, "subFunction", js_new_value(ctxt, sub_function as i32));
+            js_set_property(ctxt, obj, "data", js_new_value(ctxt, data as i32));
+            obj
+        },
+        RtuResponse::CommEventCounter(status, event_count) => {
+            let obj = ffi::JS_NewObject(ctxt.as_ptr());
+            js_set_property(ctxt, obj, "status", js_new_value(ctxt, status as i32));
+            // a device still busy with a program command reports 0xFFFF
+            js_set_property(ctxt, obj, "busy", js_new_value(ctxt, status == 0xFFFF));
+            js_set_property(ctxt, obj, "eventCount", js_new_value(ctxt, event_count as i32));
+            obj
+        },
     }
 }
 
-pub unsafe extern "C" fn qruff_rtu_read_holding_registers(
-    ctx: *mut ffi::JSContext,
+pub fn qruff_rtu_operation_settle_promise<'a>(promise: RJSPromise<'a>, content: Result<RtuResponse, ModbusError>) {
+    let result = match content {
+        Ok(response) => Ok(unsafe { js_rtu_response(promise.ctxt, response) }),
+        Err(err) => Err(unsafe { js_modbus_error(promise.ctxt, &err) }),
+    };
+    settle_promise(&promise, result);
+}
+
+/// Queues `operation` on the context behind `this` and returns the promise settled with its result.
+unsafe fn qruff_rtu_push_operation(
+    ctxt: &ContextRef,
     this_val: ffi::JSValue,
-    argc: ::std::os::raw::c_int,
-    argv: *mut ffi::JSValue,
+    operation: Result<RtuOperation, Error>,
+    opts: ffi::JSValue,
 ) -> ffi::JSValue {
-    let ctxt = ContextRef::from_ptr(ctx);
-    let this = Value::from(this_val);
-    let args = slice::from_raw_parts(argv, argc as usize);
-    let arg0 = Value::from(args[0]);
-    let arg1 = Value::from(args[1]);
-
-    let addr = ctxt.to_int32(&arg0).unwrap() as u16;
-    let reg_len = ctxt.to_int32(&arg1).unwrap() as u16;
+    let parsed = operation.and_then(|operation| {
+        let options = RtuCallOptions::from_js(ctxt, opts)?;
+        options.check(&operation)?;
+        Ok((operation, options))
+    });
+    let (operation, options) = match parsed {
+        Ok(parsed) => parsed,
+        Err(err) => return js_throw_type_error(ctxt, &err.to_string()),
+    };
+    let ptr = Value::from(this_val).get_opaque::<RtuContext>(*QRUFF_RTU_CONTEXT_CLASS_ID);
+    if ptr.is_null() {
+        return ffi::EXCEPTION;
+    }
+    let context = (*ptr).clone();
     let mut ruff_ctx = ctxt.userdata::<RuffCtx>().unwrap();
 
-    let ptr = this.get_opaque::<RtuContext>(*QRUFF_RTU_CONTEXT_CLASS_ID);
     let rfunc: [ffi::JSValue; 2] = [ffi::UNDEFINED; 2];
-    let ret = unsafe {
-        let id = ruff_ctx.as_mut().id_generator.next_id();
-        let promise = ffi::JS_NewPromiseCapability(ctxt.as_ptr(), rfunc.as_ptr() as *mut _);
-        let handle = RJSPromise::new(
-            id,
-            ctxt,
-            &Value::from(promise),
-            &Value::from(rfunc[0]),
-            &Value::from(rfunc[1]),
-        );
+    let id = ruff_ctx.as_mut().id_generator.next_id();
+    let promise = ffi::JS_NewPromiseCapability(ctxt.as_ptr(), rfunc.as_ptr() as *mut _);
+    let handle = RJSPromise::new(
+        id,
+        ctxt,
+        &Value::from(promise),
+        &Value::from(rfunc[0]),
+        &Value::from(rfunc[1]),
+    );
 
-        let mut request_msg = ruff_ctx.as_mut().request_msg.lock().unwrap();
-        request_msg.push(MsgType::AddRtuOperation(id,
-                                                  RtuOperation::ReadHoldingRegister(Box::from_raw(ptr), addr, reg_len),
-                                                  handle));
-        promise
+    let mut request_msg = ruff_ctx.as_mut().request_msg.lock().unwrap();
+    request_msg.push(MsgType::AddRtuOperation(id, context, operation, options, handle));
+    promise
+}
+
+/// Defines the JS method for one operation, `$opts_idx` is the position of the optional per call options.
+macro_rules! rtu_operation_func {
+    ($c_func:ident, $opts_idx:expr, |$ctxt:ident, $args:ident| $parse:expr) => {
+        pub unsafe extern "C" fn $c_func(
+            ctx: *mut ffi::JSContext,
+            this_val: ffi::JSValue,
+            argc: ::std::os::raw::c_int,
+            argv: *mut ffi::JSValue,
+        ) -> ffi::JSValue {
+            let $ctxt = ContextRef::from_ptr(ctx);
+            let $args = slice::from_raw_parts(argv, argc as usize);
+            let operation = (|| -> Result<RtuOperation, Error> { $parse })();
+            qruff_rtu_push_operation($ctxt, this_val, operation, js_arg($args, $opts_idx))
+        }
     };
-    ret
 }
 
-pub async fn rtu_operation(operation: RtuOperation, mut tx: Sender<RespType>, job_id: u32) {
-    match operation {
-        RtuOperation::ReadHoldingRegister(mut context, addr, reg_len) => {
-            match context.as_mut().0.read_holding_registers(addr, reg_len).await {
-                Ok(content) => {
-                    tx.try_send(RespType::RtuReadHoldingRegisters(job_id, Ok(content))).unwrap();
+unsafe fn arg_u16(ctxt: &ContextRef, args: &[ffi::JSValue], idx: usize, name: &str) -> Result<u16, Error> {
+    js_to_integer(ctxt, js_arg(args, idx), name)
+}
+
+unsafe fn arg_u16_vec(ctxt: &ContextRef, args: &[ffi::JSValue], idx: usize, name: &str) -> Result<Vec<u16>, Error> {
+    js_to_vec(ctxt, js_arg(args, idx), name, |item, name| js_to_integer(ctxt, item, name))
+}
+
+unsafe fn arg_bool_vec(ctxt: &ContextRef, args: &[ffi::JSValue], idx: usize, name: &str) -> Result<Vec<bool>, Error> {
+    js_to_vec(ctxt, js_arg(args, idx), name, |item, _| Ok(js_to_bool(ctxt, item)))
+}
+
+rtu_operation_func!(qruff_rtu_read_holding_registers, 2, |ctxt, args| Ok(RtuOperation::ReadHoldingRegisters(
+    arg_u16(ctxt, args, 0, "address")?,
+    arg_u16(ctxt, args, 1, "count")?,
+)));
+
+rtu_operation_func!(qruff_rtu_read_input_registers, 2, |ctxt, args| Ok(RtuOperation::ReadInputRegisters(
+    arg_u16(ctxt, args, 0, "address")?,
+    arg_u16(ctxt, args, 1, "count")?,
+)));
+
+rtu_operation_func!(qruff_rtu_read_coils, 2, |ctxt, args| Ok(RtuOperation::ReadCoils(
+    arg_u16(ctxt, args, 0, "address")?,
+    arg_u16(ctxt, args, 1, "count")?,
+)));
+
+rtu_operation_func!(qruff_rtu_read_discrete_inputs, 2, |ctxt, args| Ok(RtuOperation::ReadDiscreteInputs(
+    arg_u16(ctxt, args, 0, "address")?,
+    arg_u16(ctxt, args, 1, "count")?,
+)));
+
+rtu_operation_func!(qruff_rtu_write_single_register, 2, |ctxt, args| Ok(RtuOperation::WriteSingleRegister(
+    arg_u16(ctxt, args, 0, "address")?,
+    arg_u16(ctxt, args, 1, "value")?,
+)));
+
+rtu_operation_func!(qruff_rtu_write_multiple_registers, 2, |ctxt, args| Ok(RtuOperation::WriteMultipleRegisters(
+    arg_u16(ctxt, args, 0, "address")?,
+    arg_u16_vec(ctxt, args, 1, "values")?,
+)));
+
+rtu_operation_func!(qruff_rtu_write_single_coil, 2, |ctxt, args| Ok(RtuOperation::WriteSingleCoil(
+    arg_u16(ctxt, args, 0, "address")?,
+    js_to_bool(ctxt, js_arg(args, 1)),
+)));
+
+rtu_operation_func!(qruff_rtu_write_multiple_coils, 2, |ctxt, args| Ok(RtuOperation::WriteMultipleCoils(
+    arg_u16(ctxt, args, 0, "address")?,
+    arg_bool_vec(ctxt, args, 1, "values")?,
+)));
+
+rtu_operation_func!(qruff_rtu_read_write_multiple_registers, 4, |ctxt, args| Ok(RtuOperation::ReadWriteMultipleRegisters(
+    arg_u16(ctxt, args, 0, "readAddress")?,
+    arg_u16(ctxt, args, 1, "readCount")?,
+    arg_u16(ctxt, args, 2, "writeAddress")?,
+    arg_u16_vec(ctxt, args, 3, "values")?,
+)));
+
+rtu_operation_func!(qruff_rtu_masked_write_register, 3, |ctxt, args| Ok(RtuOperation::MaskedWriteRegister(
+    arg_u16(ctxt, args, 0, "address")?,
+    arg_u16(ctxt, args, 1, "andMask")?,
+    arg_u16(ctxt, args, 2, "orMask")?,
+)));
+
+/// `queue_depth()`, requests waiting for the line of this context, including other contexts on the same port.
+pub unsafe extern "C" fn qruff_rtu_queue_depth(
+    ctx: *mut ffi::JSContext,
+    this_val: ffi::JSValue,
+    _argc: ::std::os::raw::c_int,
+    _argv: *mut ffi::JSValue,
+) -> ffi::JSValue {
+    let ctxt = ContextRef::from_ptr(ctx);
+    let ptr = Value::from(this_val).get_opaque::<RtuContext>(*QRUFF_RTU_CONTEXT_CLASS_ID);
+    if ptr.is_null() {
+        return ffi::EXCEPTION;
+    }
+    js_new_value(ctxt, (*ptr).queue_depth() as i32)
+}
+
+unsafe fn arg_opt_u8(ctxt: &ContextRef, args: &[ffi::JSValue], idx: usize, name: &str, default: u8) -> Result<u8, Error> {
+    let value = js_arg(args, idx);
+    if js_is_undefined(value) {
+        Ok(default)
+    } else {
+        js_to_integer(ctxt, value, name)
+    }
+}
+
+rtu_operation_func!(qruff_rtu_read_device_identification, 2, |ctxt, args| {
+    let read_code = arg_opt_u8(ctxt, args, 0, "readCode", DEVICE_ID_BASIC)?;
+    if read_code < DEVICE_ID_BASIC || read_code > DEVICE_ID_INDIVIDUAL {
+        return Err(format_err!("readCode must be between 1 and 4, got {}", read_code));
+    }
+    Ok(RtuOperation::ReadDeviceIdentification(read_code, arg_opt_u8(ctxt, args, 1, "objectId", 0)?))
+});
+
+rtu_operation_func!(qruff_rtu_report_server_id, 0, |_ctxt, _args| Ok(RtuOperation::ReportServerId));
+
+rtu_operation_func!(qruff_rtu_diagnostics, 2, |ctxt, args| {
+    let data = js_arg(args, 1);
+    Ok(RtuOperation::Diagnostics(
+        arg_u16(ctxt, args, 0, "subFunction")?,
+        if js_is_undefined(data) { 0 } else { js_to_integer(ctxt, data, "data")? },
+    ))
+});
+
+rtu_operation_func!(qruff_rtu_get_comm_event_counter, 0, |_ctxt, _args| Ok(RtuOperation::GetCommEventCounter));
+
+/// Read device id code streaming the basic objects.
+const DEVICE_ID_BASIC: u8 = 1;
+/// Read device id code asking for the one object given.
+const DEVICE_ID_INDIVIDUAL: u8 = 4;
+/// Upper bound of read device identification requests for one call, a device can't list more objects.
+const MAX_DEVICE_ID_PAGES: usize = 256;
+
+async fn masked_write_register(context: &mut Context, addr: u16, and_mask: u16, or_mask: u16) -> io::Result<()> {
+    let mut data = Vec::with_capacity(6);
+    for word in &[addr, and_mask, or_mask] {
+        data.extend_from_slice(&word.to_be_bytes());
+    }
+    match context.call(ClientRequest::Custom(FC_MASK_WRITE_REGISTER, data.clone())).await? {
+        ClientResponse::Custom(FC_MASK_WRITE_REGISTER, echo) if echo == data => Ok(()),
+        rsp => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unexpected response to masked write register: {:?}", rsp),
+        )),
+    }
+}
+
+async fn execute(context: &mut Context, operation: &RtuOperation) -> io::Result<RtuResponse> {
+    let response = match *operation {
+        RtuOperation::ReadHoldingRegisters(addr, cnt) => {
+            RtuResponse::Registers(context.read_holding_registers(addr, cnt).await?)
+        },
+        RtuOperation::ReadInputRegisters(addr, cnt) => {
+            RtuResponse::Registers(context.read_input_registers(addr, cnt).await?)
+        },
+        RtuOperation::ReadWriteMultipleRegisters(read_addr, read_cnt, write_addr, ref values) => {
+            RtuResponse::Registers(
+                context
+                    .read_write_multiple_registers(read_addr, read_cnt, write_addr, values)
+                    .await?,
+            )
+        },
+        RtuOperation::ReadCoils(addr, cnt) => RtuResponse::Bits(context.read_coils(addr, cnt).await?),
+        RtuOperation::ReadDiscreteInputs(addr, cnt) => {
+            RtuResponse::Bits(context.read_discrete_inputs(addr, cnt).await?)
+        },
+        RtuOperation::WriteSingleRegister(addr, value) => {
+            context.write_single_register(addr, value).await?;
+            RtuResponse::Written
+        },
+        RtuOperation::WriteMultipleRegisters(addr, ref values) => {
+            context.write_multiple_registers(addr, values).await?;
+            RtuResponse::Written
+        },
+        RtuOperation::WriteSingleCoil(addr, value) => {
+            context.write_single_coil(addr, value).await?;
+            RtuResponse::Written
+        },
+        RtuOperation::WriteMultipleCoils(addr, ref values) => {
+            context.write_multiple_coils(addr, values).await?;
+            RtuResponse::Written
+        },
+        RtuOperation::MaskedWriteRegister(addr, and_mask, or_mask) => {
+            masked_write_register(context, addr, and_mask, or_mask).await?;
+            RtuResponse::Written
+        },
+        RtuOperation::ReadDeviceIdentification(..)
+        | RtuOperation::ReportServerId
+        | RtuOperation::Diagnostics(..)
+        | RtuOperation::GetCommEventCounter => {
+            let fc = operation.function_code();
+            let data = match operation.request() {
+                Request::Custom(_, data) => data,
+                _ => unreachable!(),
+            };
+            match context.call(ClientRequest::Custom(fc, data)).await? {
+                ClientResponse::Custom(rsp_fc, data) if rsp_fc == fc => RtuResponse::Raw(data),
+                rsp => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("unexpected response to function {:#04x}: {:?}", fc, rsp),
+                    ))
                 },
-                Err(err) => {
-                    tx.try_send(RespType::RtuReadHoldingRegisters(job_id, Err(err.into()))).unwrap();
+            }
+        },
+    };
+    Ok(response)
+}
+
+impl RtuOperation {
+    /// The frame level request sent for the operation.
+    fn request(&self) -> Request {
+        match *self {
+            RtuOperation::ReadHoldingRegisters(addr, cnt) => Request::ReadHoldingRegisters(addr, cnt),
+            RtuOperation::ReadInputRegisters(addr, cnt) => Request::ReadInputRegisters(addr, cnt),
+            RtuOperation::ReadCoils(addr, cnt) => Request::ReadCoils(addr, cnt),
+            RtuOperation::ReadDiscreteInputs(addr, cnt) => Request::ReadDiscreteInputs(addr, cnt),
+            RtuOperation::WriteSingleRegister(addr, value) => Request::WriteSingleRegister(addr, value),
+            RtuOperation::WriteMultipleRegisters(addr, ref values) => Request::WriteMultipleRegisters(addr, values.clone()),
+            RtuOperation::WriteSingleCoil(addr, value) => Request::WriteSingleCoil(addr, value),
+            RtuOperation::WriteMultipleCoils(addr, ref values) => Request::WriteMultipleCoils(addr, values.clone()),
+            RtuOperation::ReadWriteMultipleRegisters(read_addr, read_cnt, write_addr, ref values) => {
+                Request::ReadWriteMultipleRegisters(read_addr, read_cnt, write_addr, values.clone())
+            },
+            RtuOperation::MaskedWriteRegister(addr, and_mask, or_mask) => Request::MaskWriteRegister(addr, and_mask, or_mask),
+            RtuOperation::ReadDeviceIdentification(read_code, object_id) => {
+                Request::Custom(FC_ENCAPSULATED_INTERFACE, device_identification_request(read_code, object_id))
+            },
+            RtuOperation::ReportServerId => Request::Custom(FC_REPORT_SERVER_ID, vec![]),
+            RtuOperation::Diagnostics(sub_function, data) => {
+                let mut request = sub_function.to_be_bytes().to_vec();
+                request.extend_from_slice(&data.to_be_bytes());
+                Request::Custom(FC_DIAGNOSTICS, request)
+            },
+            RtuOperation::GetCommEventCounter => Request::Custom(FC_GET_COMM_EVENT_COUNTER, vec![]),
+        }
+    }
+
+    /// The frame level response `response` was decoded from.
+    pub fn response(&self, response: &RtuResponse) -> Response {
+        let (registers, bits) = match *response {
+            RtuResponse::Registers(ref registers) => (registers.clone(), vec![]),
+            RtuResponse::Bits(ref bits) => (vec![], bits.clone()),
+            // identification and diagnostics answers go back as the device sent them
+            RtuResponse::Raw(ref data) => return Response::Custom(self.function_code(), data.clone()),
+            _ => (vec![], vec![]),
+        };
+        match *self {
+            RtuOperation::ReadCoils(..) => Response::ReadCoils(bits),
+            RtuOperation::ReadDiscreteInputs(..) => Response::ReadDiscreteInputs(bits),
+            RtuOperation::ReadHoldingRegisters(..) => Response::ReadHoldingRegisters(registers),
+            RtuOperation::ReadInputRegisters(..) => Response::ReadInputRegisters(registers),
+            RtuOperation::ReadWriteMultipleRegisters(..) => Response::ReadWriteMultipleRegisters(registers),
+            RtuOperation::WriteSingleCoil(addr, value) => Response::WriteSingleCoil(addr, value),
+            RtuOperation::WriteSingleRegister(addr, value) => Response::WriteSingleRegister(addr, value),
+            RtuOperation::WriteMultipleCoils(addr, ref values) => Response::WriteMultipleCoils(addr, values.len() as u16),
+            RtuOperation::WriteMultipleRegisters(addr, ref values) => Response::WriteMultipleRegisters(addr, values.len() as u16),
+            RtuOperation::MaskedWriteRegister(addr, and_mask, or_mask) => Response::MaskWriteRegister(addr, and_mask, or_mask),
+            RtuOperation::ReadDeviceIdentification(..)
+            | RtuOperation::ReportServerId
+            | RtuOperation::Diagnostics(..)
+            | RtuOperation::GetCommEventCounter => Response::Custom(self.function_code(), vec![]),
+        }
+    }
+}
+
+fn rtu_response(response: Response) -> RtuResponse {
+    match response {
+        Response::ReadCoils(bits) | Response::ReadDiscreteInputs(bits) => RtuResponse::Bits(bits),
+        Response::ReadHoldingRegisters(registers)
+        | Response::ReadInputRegisters(registers)
+        | Response::ReadWriteMultipleRegisters(registers) => RtuResponse::Registers(registers),
+        Response::Custom(_, data) => RtuResponse::Raw(data),
+        _ => RtuResponse::Written,
+    }
+}
+
+type BusReply = io::Result<Result<Response, ExceptionResponse>>;
+
+/// One request waiting for the serial line.
+struct BusJob {
+    priority: u8,
+    /// submission order, keeps requests of the same priority first come first served
+    seq: u64,
+    unit: u8,
+    request: Request,
+    timeout: Duration,
+    /// trace of the context the request comes from
+    trace: Option<TraceSink>,
+    /// the answer and how long the transaction took
+    reply: oneshot::Sender<(BusReply, Duration)>,
+}
+
+impl PartialEq for BusJob {
+    fn eq(&self, other: &Self) -> bool {
+        self.priority == other.priority && self.seq == other.seq
+    }
+}
+
+impl Eq for BusJob {}
+
+impl PartialOrd for BusJob {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for BusJob {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.priority.cmp(&other.priority).then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+#[derive(Default)]
+struct BusQueue {
+    jobs: BinaryHeap<BusJob>,
+    next_seq: u64,
+    /// set once the last context on the port is gone, stops the worker
+    closed: bool,
+}
+
+struct BusShared {
+    queue: std::sync::Mutex<BusQueue>,
+    notify: Notify,
+}
+
+/// Owner of one serial port, or of a socket tunneling one, every context opened on it queues its requests here.
+///
+/// A single worker drains the queue, highest priority first. With RTU framing
+/// it keeps the line silent for 3.5 characters between the end of one
+/// transaction and the next request so devices can tell the frames apart.
+pub struct BusArbiter {
+    path: String,
+    /// line settings of a serial port, sockets have none
+    settings: Option<SerialPortSettings>,
+    framing: Framing,
+    shared: Arc<BusShared>,
+    /// the worker owning the port, awaited by `RtuContext::close`
+    worker: Option<JoinHandle<()>>,
+}
+
+impl BusArbiter {
+    /// Requests waiting for the line, the one on the wire isn't counted.
+    fn queue_depth(&self) -> usize {
+        self.shared.queue.lock().unwrap().jobs.len()
+    }
+
+    /// Queues `request` and waits for its answer and the round trip it took,
+    /// `timeout` only starts once the request is sent.
+    async fn submit(&self, unit: u8, request: Request, priority: u8, timeout: Duration, trace: Option<TraceSink>) -> (BusReply, Duration) {
+        let (reply, rx) = oneshot::channel();
+        {
+            let mut queue = self.shared.queue.lock().unwrap();
+            let seq = queue.next_seq;
+            queue.next_seq += 1;
+            queue.jobs.push(BusJob { priority, seq, unit, request, timeout, trace, reply });
+        }
+        self.shared.notify.notify();
+        rx.await.unwrap_or_else(|_| {
+            let err = io::Error::new(io::ErrorKind::BrokenPipe, format!("serial port {} was closed", self.path));
+            (Err(err), Duration::from_millis(0))
+        })
+    }
+
+    /// Line settings a second context on the same port must agree with.
+    fn check_settings(&self, settings: &SerialPortSettings, framing: Framing) -> Result<(), Error> {
+        if self.framing != framing {
+            return Err(format_err!("{} is already open with {:?} framing", self.path, self.framing));
+        }
+        let ours = match self.settings {
+            Some(ref ours) => ours,
+            None => return Ok(()),
+        };
+        if ours.baud_rate != settings.baud_rate
+            || ours.data_bits != settings.data_bits
+            || ours.parity != settings.parity
+            || ours.stop_bits != settings.stop_bits
+        {
+            return Err(format_err!(
+                "{} is already open at {} baud {:?} {:?} {:?}",
+                self.path,
+                ours.baud_rate,
+                ours.data_bits,
+                ours.parity,
+                ours.stop_bits
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for BusArbiter {
+    fn drop(&mut self) {
+        self.shared.queue.lock().unwrap().closed = true;
+        self.shared.notify.notify();
+    }
+}
+
+lazy_static! {
+    /// Open serial ports by path, an entry dies with the last context using the port.
+    static ref SERIAL_BUSES: std::sync::Mutex<HashMap<String, Weak<BusArbiter>>> = std::sync::Mutex::new(HashMap::new());
+    /// Serial ports answered by a modbus server, locked after `SERIAL_BUSES`.
+    static ref SERVED_PORTS: std::sync::Mutex<HashSet<String>> = std::sync::Mutex::new(HashSet::new());
+}
+
+/// A serial port held by a modbus server, given back on drop.
+#[derive(Debug)]
+pub struct ServedPort(String);
+
+impl ServedPort {
+    /// Claims `path` for a server, refused while a context or another server uses it.
+    pub fn claim(path: &str) -> Result<ServedPort, Error> {
+        let buses = SERIAL_BUSES.lock().unwrap();
+        if buses.get(path).map_or(false, |bus| bus.strong_count() > 0) {
+            return Err(format_err!("{} is in use by a modbus context", path));
+        }
+        if !SERVED_PORTS.lock().unwrap().insert(path.to_string()) {
+            return Err(format_err!("{} is in use by another modbus server", path));
+        }
+        Ok(ServedPort(path.to_string()))
+    }
+}
+
+impl Drop for ServedPort {
+    fn drop(&mut self) {
+        SERVED_PORTS.lock().unwrap().remove(&self.0);
+    }
+}
+
+/// Modbus RTU asks for 3.5 character times of silence between frames, fixed to 1.75 ms above 19200 baud.
+fn inter_frame_silence(baud: u32) -> Duration {
+    if baud > 19200 {
+        Duration::from_micros(1750)
+    } else {
+        // 3.5 characters of 11 bits
+        Duration::from_micros(38_500_000 / baud as u64)
+    }
+}
+
+/// Returns the arbiter of `config.path`, opening the port the first time.
+fn open_bus(config: &SerialConfig) -> Result<Arc<BusArbiter>, Error> {
+    let mut buses = SERIAL_BUSES.lock().unwrap();
+    if let Some(bus) = buses.get(&config.path).and_then(Weak::upgrade) {
+        bus.check_settings(&config.settings, config.framing)?;
+        return Ok(bus);
+    }
+    if SERVED_PORTS.lock().unwrap().contains(&config.path) {
+        return Err(format_err!("{} is in use by a modbus server", config.path));
+    }
+    let port = Serial::from_path(&config.path, &config.settings)
+        .map_err(|err| format_err!("failed to open {}: {}", config.path, err))?;
+    let silence = match config.framing {
+        Framing::Rtu => inter_frame_silence(config.settings.baud_rate),
+        // ASCII frames are delimited by their start and end characters
+        Framing::Ascii => Duration::from_millis(0),
+    };
+    let bus = spawn_bus(config.path.clone(), Some(config.settings.clone()), config.framing, port, silence);
+    buses.retain(|_, bus| bus.strong_count() > 0);
+    buses.insert(config.path.clone(), Arc::downgrade(&bus));
+    Ok(bus)
+}
+
+/// Starts the worker draining the queue of a new arbiter onto `port`.
+fn spawn_bus<P: BusPort>(
+    path: String,
+    settings: Option<SerialPortSettings>,
+    framing: Framing,
+    port: P,
+    silence: Duration,
+) -> Arc<BusArbiter> {
+    let shared = Arc::new(BusShared {
+        queue: std::sync::Mutex::new(BusQueue::default()),
+        notify: Notify::new(),
+    });
+    let worker = tokio::spawn(bus_worker(shared.clone(), port, framing, silence));
+    Arc::new(BusArbiter { path, settings, framing, shared, worker: Some(worker) })
+}
+
+/// Byte stream a bus worker talks over.
+trait BusPort: AsyncRead + AsyncWrite + Unpin + Send + 'static {
+    /// Drops whatever was received but not read yet.
+    fn discard_input(&mut self) -> io::Result<()>;
+}
+
+impl BusPort for Serial {
+    fn discard_input(&mut self) -> io::Result<()> {
+        self.clear(ClearBuffer::Input).map_err(io::Error::from)
+    }
+}
+
+impl BusPort for TcpStream {
+    fn discard_input(&mut self) -> io::Result<()> {
+        let mut buf = [0u8; 256];
+        // reads until nothing is buffered anymore, never waits for more
+        while let Some(read) = self.read(&mut buf).now_or_never() {
+            if read? == 0 {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reads the next response frame off the line, as it was received.
+async fn read_response<P: BusPort>(port: &mut P, framing: Framing) -> io::Result<Vec<u8>> {
+    match framing {
+        Framing::Rtu => read_rtu_frame(port, rtu_response_len).await,
+        Framing::Ascii => read_ascii_line(port).await,
+    }
+}
+
+/// Checks the CRC or LRC of a received frame, returning its unit and PDU.
+fn unframe(framing: Framing, frame: &[u8]) -> io::Result<(u8, Vec<u8>)> {
+    match framing {
+        Framing::Rtu => rtu_unframe(frame).map(|(unit, pdu)| (unit, pdu.to_vec())),
+        Framing::Ascii => ascii_unframe(frame),
+    }
+}
+
+async fn bus_transaction<P: BusPort>(port: &mut P, framing: Framing, job: &BusJob) -> BusReply {
+    let pdu = encode_request(&job.request);
+    let frame = match framing {
+        Framing::Rtu => rtu_frame(job.unit, &pdu),
+        Framing::Ascii => ascii_frame(job.unit, &pdu),
+    };
+    if let Some(ref trace) = job.trace {
+        trace.record(TraceFrame::request(job.unit, pdu[0], frame.clone())).await;
+    }
+    port.write_all(&frame).await?;
+    let sent = Instant::now();
+    let frame = timeout(job.timeout, read_response(port, framing))
+        .await
+        .map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("modbus request timed out after {} ms", job.timeout.as_millis()),
+            )
+        })??;
+    let unframed = unframe(framing, &frame);
+    if let Some(ref trace) = job.trace {
+        // a frame failing its check can't be trusted, it is traced against the request
+        let (unit, function) = match unframed {
+            Ok((unit, ref pdu)) if !pdu.is_empty() => (unit, pdu[0]),
+            _ => (job.unit, job.request.function_code()),
+        };
+        let crc_ok = Some(unframed.is_ok());
+        trace.record(TraceFrame::response(unit, function, frame, sent.elapsed(), crc_ok)).await;
+    }
+    let (unit, pdu) = unframed?;
+    if unit != job.unit {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("response from unit {} to a request for unit {}", unit, job.unit),
+        ));
+    }
+    decode_response(&pdu, &job.request)
+}
+
+async fn bus_worker<P: BusPort>(shared: Arc<BusShared>, mut port: P, framing: Framing, silence: Duration) {
+    let mut idle_since = Instant::now();
+    loop {
+        let job = {
+            let mut queue = shared.queue.lock().unwrap();
+            if queue.closed {
+                break;
+            }
+            queue.jobs.pop()
+        };
+        let job = match job {
+            Some(job) => job,
+            None => {
+                shared.notify.notified().await;
+                continue;
+            },
+        };
+        delay_until(idle_since + silence).await;
+        let started = Instant::now();
+        let result = bus_transaction(&mut port, framing, &job).await;
+        let round_trip = started.elapsed();
+        if result.is_err() {
+            // a late or garbled answer must not be taken for the next response
+            if let Err(err) = port.discard_input() {
+                warn!("failed to flush the bus input: {}", err);
+            }
+        }
+        idle_since = Instant::now();
+        let _ = job.reply.send((result, round_trip));
+    }
+    debug!("serial bus worker stopped");
+}
+
+/// Unit id followed by the PDU, what a modbus TCP trace shows of a frame.
+fn unit_frame(unit: u8, pdu: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(pdu.len() + 1);
+    frame.push(unit);
+    frame.extend_from_slice(pdu);
+    frame
+}
+
+impl RtuContext {
+    /// One try of `operation`, waiting for the connection doesn't count against `timeout`.
+    async fn attempt(&self, operation: &RtuOperation, unit: u8, timeout_after: Duration, priority: u8) -> Result<RtuResponse, ModbusError> {
+        match self.client {
+            ModbusClient::Tcp(ref link) => {
+                link.waiting.fetch_add(1, AtomicOrdering::SeqCst);
+                let mut context = link.context.lock().await;
+                link.waiting.fetch_sub(1, AtomicOrdering::SeqCst);
+                if context.is_none() {
+                    match TcpLink::connect(link.addr, link.connect_timeout, unit).await {
+                        Ok(reconnected) => *context = Some(reconnected),
+                        Err(err) => {
+                            self.stats.lock().unwrap().record_failure(err.kind() == io::ErrorKind::TimedOut, false);
+                            return Err(ModbusError::from_io(err));
+                        },
+                    }
+                }
+                let client = context.as_mut().unwrap();
+                client.set_slave(Slave(unit));
+                let trace = self.trace();
+                if let Some(ref trace) = trace {
+                    let pdu = encode_request(&operation.request());
+                    trace.record(TraceFrame::request(unit, pdu[0], unit_frame(unit, &pdu))).await;
                 }
+                let sent = Instant::now();
+                let result = match timeout(timeout_after, execute(client, operation)).await {
+                    Ok(result) => result.map_err(ModbusError::from_io),
+                    Err(_) => Err(ModbusError::new(
+                        ModbusErrorCode::Timeout,
+                        format!("modbus request timed out after {} ms", timeout_after.as_millis()),
+                    )),
+                };
+                let latency = sent.elapsed();
+                if result.as_ref().err().map_or(false, |err| err.code == ModbusErrorCode::Timeout) {
+                    // tokio-modbus reads one frame per request, the late answer is left behind with the socket
+                    *context = None;
+                }
+                if let Some(ref trace) = trace {
+                    // tokio-modbus keeps the answer to itself, it is traced as encoded back from the result
+                    let pdu = match result {
+                        Ok(ref response) => Some(encode_response(&operation.response(response))),
+                        Err(ref err) => err.code.exception_code().map(|exception| {
+                            encode_exception(&ExceptionResponse { function: operation.function_code(), exception })
+                        }),
+                    };
+                    if let Some(pdu) = pdu {
+                        trace.record(TraceFrame::response(unit, pdu[0], unit_frame(unit, &pdu), latency, None)).await;
+                    }
+                }
+                let mut stats = self.stats.lock().unwrap();
+                match result {
+                    Ok(_) => stats.record_response(latency),
+                    Err(ref err) => match err.code.exception_code() {
+                        Some(exception) => stats.record_exception(exception, latency),
+                        None => stats.record_failure(err.code == ModbusErrorCode::Timeout, false),
+                    },
+                }
+                result
+            },
+            ModbusClient::Bus(ref bus) => {
+                let (reply, latency) = bus.submit(unit, operation.request(), priority, timeout_after, self.trace()).await;
+                let mut stats = self.stats.lock().unwrap();
+                match reply {
+                    Ok(Ok(response)) => {
+                        stats.record_response(latency);
+                        Ok(rtu_response(response))
+                    },
+                    Ok(Err(exception)) => {
+                        stats.record_exception(exception.exception, latency);
+                        Err(ModbusError::from_exception(exception))
+                    },
+                    Err(err) => {
+                        stats.record_failure(err.kind() == io::ErrorKind::TimedOut, is_checksum_error(&err));
+                        Err(ModbusError::from_io(err))
+                    },
+                }
+            },
+        }
+    }
+
+    /// Runs `operation` on `unit` under the retry `policy`, every retry queues up again behind `priority`.
+    pub async fn run(&self, operation: &RtuOperation, unit: u8, policy: RetryPolicy, priority: Option<u8>) -> Result<RtuResponse, ModbusError> {
+        let priority = priority.unwrap_or(self.priority);
+        let mut attempt = 0;
+        loop {
+            let err = match self.attempt(operation, unit, policy.timeout, priority).await {
+                Ok(response) => return Ok(response),
+                Err(err) => err,
+            };
+            // exception responses are an answer from the device, asking again won't change it
+            if attempt >= policy.retries || !err.is_retryable() {
+                return Err(err.with_request(operation, unit));
             }
+            debug!("retrying {:?} on unit {} after {}", operation, unit, err);
+            self.stats.lock().unwrap().record_retry();
+            delay_for(policy.backoff_for(attempt)).await;
+            attempt += 1;
+        }
+    }
+}
+
+/// Parses the response data of the identification and diagnostics functions.
+fn parse_raw(operation: &RtuOperation, data: &[u8]) -> io::Result<RtuResponse> {
+    let response = match *operation {
+        RtuOperation::ReportServerId => RtuResponse::ServerId(parse_report_server_id(data)?),
+        RtuOperation::Diagnostics(..) => {
+            let (sub_function, data) = parse_diagnostics(data)?;
+            RtuResponse::Diagnostics(sub_function, data)
+        },
+        RtuOperation::GetCommEventCounter => {
+            let (status, event_count) = parse_comm_event_counter(data)?;
+            RtuResponse::CommEventCounter(status, event_count)
+        },
+        _ => RtuResponse::Raw(data.to_vec()),
+    };
+    Ok(response)
+}
+
+/// Reads device identification objects, following the pages until the device has sent them all.
+async fn read_device_identification(
+    context: &RtuContext,
+    read_code: u8,
+    object_id: u8,
+    unit: u8,
+    policy: RetryPolicy,
+    priority: Option<u8>,
+) -> Result<RtuResponse, ModbusError> {
+    let mut next = object_id;
+    let mut objects = Vec::new();
+    for _ in 0..MAX_DEVICE_ID_PAGES {
+        let operation = RtuOperation::ReadDeviceIdentification(read_code, next);
+        let data = match context.run(&operation, unit, policy, priority).await? {
+            RtuResponse::Raw(data) => data,
+            response => return Ok(response),
+        };
+        let page = parse_device_identification(&data).map_err(|err| ModbusError::from_io(err).with_request(&operation, unit))?;
+        objects.extend(page.objects);
+        match page.more_follows {
+            Some(id) if read_code != DEVICE_ID_INDIVIDUAL && id > next => next = id,
+            _ => return Ok(RtuResponse::DeviceIdentification(page.conformity_level, objects)),
         }
     }
+    Err(ModbusError::new(
+        ModbusErrorCode::InvalidResponse,
+        format!("device identification of unit {} didn't end after {} requests", unit, MAX_DEVICE_ID_PAGES),
+    ))
+}
+
+/// Runs `operation` on the bus of `context`, with the retries and decoding asked for in `options`.
+pub async fn execute_operation(
+    context: &RtuContext,
+    operation: &RtuOperation,
+    options: &RtuCallOptions,
+) -> Result<RtuResponse, ModbusError> {
+    let unit = options.unit.unwrap_or(context.unit);
+    let policy = context.policy.apply(&options.retry);
+    let result = match operation {
+        RtuOperation::ReadDeviceIdentification(read_code, object_id) => {
+            read_device_identification(context, *read_code, *object_id, unit, policy, options.priority).await
+        },
+        _ => match (context.run(operation, unit, policy, options.priority).await, options.decode) {
+            (Ok(RtuResponse::Registers(registers)), Some(decode)) => decode_registers(&registers, &decode)
+                .map(RtuResponse::Decoded)
+                .map_err(|err| ModbusError::new(ModbusErrorCode::InvalidResponse, err.to_string())),
+            (Ok(RtuResponse::Raw(data)), _) => parse_raw(operation, &data).map_err(ModbusError::from_io),
+            (result, _) => result,
+        },
+    };
+    result.map_err(|err| match err.function {
+        Some(_) => err,
+        None => err.with_request(operation, unit),
+    })
+}
+
+pub async fn rtu_operation(
+    context: RtuContext,
+    operation: RtuOperation,
+    options: RtuCallOptions,
+    mut tx: Sender<RespType>,
+    job_id: u32,
+) {
+    let result = execute_operation(&context, &operation, &options).await;
+    tx.send(RespType::RtuOperation(job_id, result)).await.unwrap();
 }
 
 pub unsafe extern "C" fn qruff_rtu_setup(
@@ -182,23 +1703,16 @@ pub unsafe extern "C" fn qruff_rtu_setup(
     argv: *mut ffi::JSValue,
 ) -> ffi::JSValue {
     let ctxt = ContextRef::from_ptr(ctx);
-    let this = Value::from(this_val);
     let args = slice::from_raw_parts(argv, argc as usize);
-    let arg0 = Value::from(args[0]);
-    let arg1 = Value::from(args[1]);
 
-    let port = match ctxt.to_cstring(&arg0) {
-        Some(value) => String::from(value.to_string_lossy()),
-        None => return ffi::EXCEPTION,
+    let port = match js_to_string(ctxt, js_arg(args, 0), "path") {
+        Ok(port) => port,
+        Err(err) => return js_throw_type_error(ctxt, &err.to_string()),
     };
-
-    let baud = ctxt.to_int32(&arg1).unwrap() as u32;
-    let serial_config = SerialConfig {
-        path: port,
-        settings: SerialPortSettings {
-            baud_rate: baud,
-            ..Default::default()
-        },
+    // invalid line settings throw, as the options of every other setup do
+    let serial_config = match SerialConfig::from_js(ctxt, port, js_arg(args, 1)) {
+        Ok(serial_config) => serial_config,
+        Err(err) => return js_throw_type_error(ctxt, &err.to_string()),
     };
     let mut ruff_ctx = ctxt.userdata::<RuffCtx>().unwrap();
 
@@ -221,22 +1735,96 @@ pub unsafe extern "C" fn qruff_rtu_setup(
     ret
 }
 
+/// Context on the shared bus of `config.path`, contexts on the same port take turns on the line.
+pub async fn rtu_connect(config: &SerialConfig) -> Result<RtuContext, Error> {
+    let bus = open_bus(config)?;
+    Ok(RtuContext::bus(bus, config.unit, config.policy, config.priority))
+}
+
 pub async fn rtu_setup(config: SerialConfig, mut tx: Sender<RespType>, job_id: u32) {
-    let port = Serial::from_path(&config.path, &config.settings);
-    match port {
-        Ok(port) => {
-            match rtu::connect(port).await {
-                Ok(context) => {
-                    tx.try_send(RespType::RtuSetup(job_id, Ok(RtuContext(context)))).unwrap();
-                },
-                Err(err) => tx.try_send(RespType::RtuSetup(job_id, Err(err.into()))).unwrap(),
-            }
-        },
-        Err(err) => {
-            tx.try_send(RespType::RtuSetup(job_id, Err(err.into())));
-        },
+    let context = rtu_connect(&config).await;
+    tx.send(RespType::RtuSetup(job_id, context)).await.unwrap();
+}
+
+/// `tcp_setup(host, port, opts)`, resolves to a context with the same methods as `rtu_setup`.
+///
+/// Supported `opts`: `connectTimeoutMs`, `unit`, `framing`, `priority` and the `RetryOptions`.
+/// With `framing: 'rtu'` or `'ascii'` the socket carries serial frames, as
+/// terminal servers tunneling a serial line do, instead of modbus TCP.
+pub unsafe extern "C" fn qruff_tcp_setup(
+    ctx: *mut ffi::JSContext,
+    _this_val: ffi::JSValue,
+    argc: ::std::os::raw::c_int,
+    argv: *mut ffi::JSValue,
+) -> ffi::JSValue {
+    let ctxt = ContextRef::from_ptr(ctx);
+    let args = slice::from_raw_parts(argv, argc as usize);
+
+    let config = (|| -> Result<TcpConfig, Error> {
+        let opts = js_arg(args, 2);
+        let connect_timeout_ms = js_get_option(ctxt, opts, "connectTimeoutMs", |v, name| js_to_integer(ctxt, v, name))?
+            .unwrap_or(DEFAULT_TCP_CONNECT_TIMEOUT_MS);
+        let framing = js_get_option(ctxt, opts, "framing", |v, name| js_to_string(ctxt, v, name))?
+            .map(|name| name.parse::<Framing>())
+            .transpose()?;
+        // behind a terminal server the unit ids are the serial ones
+        let unit = match framing {
+            Some(_) => opt_unit(ctxt, opts, MAX_RTU_UNIT)?.unwrap_or(DEFAULT_RTU_UNIT),
+            None => opt_unit(ctxt, opts, u8::max_value())?.unwrap_or(DEFAULT_TCP_UNIT),
+        };
+        Ok(TcpConfig {
+            host: js_to_string(ctxt, js_arg(args, 0), "host")?,
+            port: js_to_integer(ctxt, js_arg(args, 1), "port")?,
+            connect_timeout: Duration::from_millis(connect_timeout_ms),
+            framing,
+            unit,
+            policy: RetryPolicy::default().apply(&RetryOptions::from_js(ctxt, opts)?),
+            priority: js_get_option(ctxt, opts, "priority", |v, name| js_to_integer(ctxt, v, name))?
+                .unwrap_or(DEFAULT_PRIORITY),
+        })
+    })();
+    let config = match config {
+        Ok(config) => config,
+        Err(err) => return js_throw_type_error(ctxt, &err.to_string()),
+    };
+    let mut ruff_ctx = ctxt.userdata::<RuffCtx>().unwrap();
+
+    let rfunc: [ffi::JSValue; 2] = [ffi::UNDEFINED; 2];
+    let id = ruff_ctx.as_mut().id_generator.next_id();
+    let promise = ffi::JS_NewPromiseCapability(ctxt.as_ptr(), rfunc.as_ptr() as *mut _);
+    let handle = RJSPromise::new(
+        id,
+        ctxt,
+        &Value::from(promise),
+        &Value::from(rfunc[0]),
+        &Value::from(rfunc[1]),
+    );
+
+    let mut request_msg = ruff_ctx.as_mut().request_msg.lock().unwrap();
+    request_msg.push(MsgType::CreateTcpSetup(id, config, handle));
+    promise
+}
+
+async fn tcp_connect(config: &TcpConfig) -> Result<RtuContext, Error> {
+    let addr = lookup_host((config.host.as_str(), config.port))
+        .await?
+        .next()
+        .ok_or_else(|| format_err!("no address found for {}:{}", config.host, config.port))?;
+    if let Some(framing) = config.framing {
+        let stream = timeout(config.connect_timeout, TcpStream::connect(addr))
+            .await
+            .map_err(|_| format_err!("timed out connecting to {}", addr))??;
+        // the terminal server keeps the silence on its serial side
+        let bus = spawn_bus(addr.to_string(), None, framing, stream, Duration::from_millis(0));
+        return Ok(RtuContext::bus(bus, config.unit, config.policy, config.priority));
     }
-    return;
+    let context = TcpLink::connect(addr, config.connect_timeout, config.unit).await?;
+    Ok(RtuContext::tcp(context, addr, config.connect_timeout, config.unit, config.policy))
+}
+
+pub async fn tcp_setup(config: TcpConfig, mut tx: Sender<RespType>, job_id: u32) {
+    let context = tcp_connect(&config).await;
+    tx.send(RespType::RtuSetup(job_id, context)).await.unwrap();
 }
 
 pub fn qruff_rtu_context_class_id () -> ClassId {
@@ -249,7 +1837,7 @@ lazy_static! {
 
 pub fn register_rtu_context_class(rt: &RuntimeRef) -> bool {
     unsafe extern "C" fn qruff_rtu_context_finalizer(_rt: *mut ffi::JSRuntime, obj: ffi::JSValue) {
-        let ptr = ffi::JS_GetOpaque(obj, *QRUFF_RTU_CONTEXT_CLASS_ID);
+        let ptr = ffi::JS_GetOpaque(obj, *QRUFF_RTU_CONTEXT_CLASS_ID) as *mut RtuContext;
 
         trace!("free userdata {:p} @ {:?}", ptr, obj.u.ptr);
         println!("free userdata {:p} @ {:?}", ptr, obj.u.ptr);
@@ -0,0 +1,177 @@
+use std::str::FromStr;
+
+use failure::{format_err, Error};
+
+/// How a run of registers is turned into values.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DataType {
+    Uint16,
+    Int16,
+    Uint32,
+    Int32,
+    Float32,
+    Float64,
+    /// two ASCII characters per register, trailing NULs trimmed
+    String,
+    /// 16 booleans per register, least significant bit first
+    Bitfield,
+}
+
+impl DataType {
+    /// Number of registers one value occupies.
+    pub fn words(self) -> usize {
+        match self {
+            DataType::Uint32 | DataType::Int32 | DataType::Float32 => 2,
+            DataType::Float64 => 4,
+            _ => 1,
+        }
+    }
+}
+
+impl FromStr for DataType {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.to_lowercase().as_str() {
+            "uint16" => DataType::Uint16,
+            "int16" => DataType::Int16,
+            "uint32" => DataType::Uint32,
+            "int32" => DataType::Int32,
+            "float32" => DataType::Float32,
+            "float64" => DataType::Float64,
+            "string" => DataType::String,
+            "bitfield" => DataType::Bitfield,
+            _ => return Err(format_err!(
+                "decode must be one of uint16, int16, uint32, int32, float32, float64, string, bitfield, got '{}'",
+                s
+            )),
+        })
+    }
+}
+
+/// Order of the bytes of a multi register value, named after the big endian `ABCD`.
+///
+/// `CDAB` swaps the registers, `BADC` swaps the bytes inside each register and
+/// `DCBA` does both.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ByteOrder {
+    ABCD,
+    CDAB,
+    BADC,
+    DCBA,
+}
+
+impl ByteOrder {
+    fn swap_words(self) -> bool {
+        self == ByteOrder::CDAB || self == ByteOrder::DCBA
+    }
+
+    fn swap_bytes(self) -> bool {
+        self == ByteOrder::BADC || self == ByteOrder::DCBA
+    }
+}
+
+impl Default for ByteOrder {
+    fn default() -> Self {
+        ByteOrder::ABCD
+    }
+}
+
+impl FromStr for ByteOrder {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.to_uppercase().as_str() {
+            "ABCD" => ByteOrder::ABCD,
+            "CDAB" => ByteOrder::CDAB,
+            "BADC" => ByteOrder::BADC,
+            "DCBA" => ByteOrder::DCBA,
+            _ => return Err(format_err!("byteOrder must be one of ABCD, CDAB, BADC, DCBA, got '{}'", s)),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RegisterDecode {
+    pub data_type: DataType,
+    pub order: ByteOrder,
+}
+
+impl RegisterDecode {
+    /// Checks that `count` registers split into whole values.
+    pub fn check_count(&self, count: u16) -> Result<(), Error> {
+        let words = self.data_type.words();
+        if count as usize % words != 0 {
+            return Err(format_err!(
+                "{:?} values take {} registers each, {} registers can't be decoded",
+                self.data_type,
+                words,
+                count
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DecodedValue {
+    Numbers(Vec<f64>),
+    Text(String),
+    Bits(Vec<Vec<bool>>),
+}
+
+/// Big endian bytes of one value, reordered to `ABCD`.
+fn value_bytes(words: &[u16], order: ByteOrder) -> Vec<u8> {
+    let mut words = words.to_vec();
+    if order.swap_words() {
+        words.reverse();
+    }
+    words
+        .iter()
+        .map(|word| if order.swap_bytes() { word.swap_bytes() } else { *word })
+        .flat_map(|word| word.to_be_bytes().to_vec())
+        .collect()
+}
+
+fn decode_number(bytes: &[u8], data_type: DataType) -> f64 {
+    let mut buf = [0u8; 8];
+    buf[..bytes.len()].copy_from_slice(bytes);
+    match data_type {
+        DataType::Uint16 => u16::from_be_bytes([buf[0], buf[1]]) as f64,
+        DataType::Int16 => i16::from_be_bytes([buf[0], buf[1]]) as f64,
+        DataType::Uint32 => u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]) as f64,
+        DataType::Int32 => i32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]) as f64,
+        DataType::Float32 => f32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]) as f64,
+        DataType::Float64 => f64::from_be_bytes(buf),
+        DataType::String | DataType::Bitfield => unreachable!("not a numeric type"),
+    }
+}
+
+pub fn decode_registers(registers: &[u16], decode: &RegisterDecode) -> Result<DecodedValue, Error> {
+    decode.check_count(registers.len() as u16)?;
+    let value = match decode.data_type {
+        DataType::String => {
+            // characters never span registers, only the byte swap applies
+            let order = if decode.order.swap_bytes() { ByteOrder::BADC } else { ByteOrder::ABCD };
+            let bytes = value_bytes(registers, order);
+            let text = String::from_utf8_lossy(&bytes);
+            DecodedValue::Text(text.trim_end_matches('\0').to_string())
+        },
+        DataType::Bitfield => DecodedValue::Bits(
+            registers
+                .iter()
+                .map(|word| {
+                    let word = if decode.order.swap_bytes() { word.swap_bytes() } else { *word };
+                    (0..16).map(|bit| word & (1 << bit) != 0).collect()
+                })
+                .collect(),
+        ),
+        data_type => DecodedValue::Numbers(
+            registers
+                .chunks(data_type.words())
+                .map(|words| decode_number(&value_bytes(words, decode.order), data_type))
+                .collect(),
+        ),
+    };
+    Ok(value)
+}
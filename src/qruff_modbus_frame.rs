@@ -0,0 +1,682 @@
+//! Modbus PDU encoding plus the RTU, ASCII and MBAP framings around it.
+//!
+//! tokio-modbus only offers the client side with a fixed set of function
+//! codes, the server, the gateway and the raw frame tracing need to see and
+//! build the bytes themselves.
+
+use std::{error, fmt, io};
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+pub const FC_READ_COILS: u8 = 0x01;
+pub const FC_READ_DISCRETE_INPUTS: u8 = 0x02;
+pub const FC_READ_HOLDING_REGISTERS: u8 = 0x03;
+pub const FC_READ_INPUT_REGISTERS: u8 = 0x04;
+pub const FC_WRITE_SINGLE_COIL: u8 = 0x05;
+pub const FC_WRITE_SINGLE_REGISTER: u8 = 0x06;
+pub const FC_WRITE_MULTIPLE_COILS: u8 = 0x0F;
+pub const FC_WRITE_MULTIPLE_REGISTERS: u8 = 0x10;
+pub const FC_MASK_WRITE_REGISTER: u8 = 0x16;
+pub const FC_READ_WRITE_MULTIPLE_REGISTERS: u8 = 0x17;
+pub const FC_DIAGNOSTICS: u8 = 0x08;
+pub const FC_GET_COMM_EVENT_COUNTER: u8 = 0x0B;
+pub const FC_REPORT_SERVER_ID: u8 = 0x11;
+/// Encapsulated interface transport, carrying read device identification.
+pub const FC_ENCAPSULATED_INTERFACE: u8 = 0x2B;
+pub const MEI_READ_DEVICE_ID: u8 = 0x0E;
+
+pub const EXCEPTION_ILLEGAL_FUNCTION: u8 = 0x01;
+pub const EXCEPTION_ILLEGAL_DATA_ADDRESS: u8 = 0x02;
+pub const EXCEPTION_ILLEGAL_DATA_VALUE: u8 = 0x03;
+pub const EXCEPTION_SLAVE_DEVICE_FAILURE: u8 = 0x04;
+pub const EXCEPTION_GATEWAY_PATH_UNAVAILABLE: u8 = 0x0A;
+pub const EXCEPTION_GATEWAY_TARGET_FAILED: u8 = 0x0B;
+
+/// Largest PDU the spec allows, 253 bytes.
+pub const MAX_PDU_LEN: usize = 253;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Request {
+    ReadCoils(u16, u16),
+    ReadDiscreteInputs(u16, u16),
+    ReadHoldingRegisters(u16, u16),
+    ReadInputRegisters(u16, u16),
+    WriteSingleCoil(u16, bool),
+    WriteSingleRegister(u16, u16),
+    WriteMultipleCoils(u16, Vec<bool>),
+    WriteMultipleRegisters(u16, Vec<u16>),
+    /// address, AND mask, OR mask
+    MaskWriteRegister(u16, u16, u16),
+    /// read address, read count, write address, values to write
+    ReadWriteMultipleRegisters(u16, u16, u16, Vec<u16>),
+    /// any other function code with its raw data
+    Custom(u8, Vec<u8>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Response {
+    ReadCoils(Vec<bool>),
+    ReadDiscreteInputs(Vec<bool>),
+    ReadHoldingRegisters(Vec<u16>),
+    ReadInputRegisters(Vec<u16>),
+    WriteSingleCoil(u16, bool),
+    WriteSingleRegister(u16, u16),
+    WriteMultipleCoils(u16, u16),
+    WriteMultipleRegisters(u16, u16),
+    MaskWriteRegister(u16, u16, u16),
+    ReadWriteMultipleRegisters(Vec<u16>),
+    Custom(u8, Vec<u8>),
+}
+
+/// Exception answer, `function` is the request function code without the 0x80 flag.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ExceptionResponse {
+    pub function: u8,
+    pub exception: u8,
+}
+
+impl Request {
+    pub fn function_code(&self) -> u8 {
+        match self {
+            Request::ReadCoils(..) => FC_READ_COILS,
+            Request::ReadDiscreteInputs(..) => FC_READ_DISCRETE_INPUTS,
+            Request::ReadHoldingRegisters(..) => FC_READ_HOLDING_REGISTERS,
+            Request::ReadInputRegisters(..) => FC_READ_INPUT_REGISTERS,
+            Request::WriteSingleCoil(..) => FC_WRITE_SINGLE_COIL,
+            Request::WriteSingleRegister(..) => FC_WRITE_SINGLE_REGISTER,
+            Request::WriteMultipleCoils(..) => FC_WRITE_MULTIPLE_COILS,
+            Request::WriteMultipleRegisters(..) => FC_WRITE_MULTIPLE_REGISTERS,
+            Request::MaskWriteRegister(..) => FC_MASK_WRITE_REGISTER,
+            Request::ReadWriteMultipleRegisters(..) => FC_READ_WRITE_MULTIPLE_REGISTERS,
+            Request::Custom(fc, _) => *fc,
+        }
+    }
+}
+
+impl Response {
+    pub fn function_code(&self) -> u8 {
+        match self {
+            Response::ReadCoils(..) => FC_READ_COILS,
+            Response::ReadDiscreteInputs(..) => FC_READ_DISCRETE_INPUTS,
+            Response::ReadHoldingRegisters(..) => FC_READ_HOLDING_REGISTERS,
+            Response::ReadInputRegisters(..) => FC_READ_INPUT_REGISTERS,
+            Response::WriteSingleCoil(..) => FC_WRITE_SINGLE_COIL,
+            Response::WriteSingleRegister(..) => FC_WRITE_SINGLE_REGISTER,
+            Response::WriteMultipleCoils(..) => FC_WRITE_MULTIPLE_COILS,
+            Response::WriteMultipleRegisters(..) => FC_WRITE_MULTIPLE_REGISTERS,
+            Response::MaskWriteRegister(..) => FC_MASK_WRITE_REGISTER,
+            Response::ReadWriteMultipleRegisters(..) => FC_READ_WRITE_MULTIPLE_REGISTERS,
+            Response::Custom(fc, _) => *fc,
+        }
+    }
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// A frame whose CRC or LRC doesn't match, most likely noise on the line.
+#[derive(Debug)]
+struct ChecksumMismatch(String);
+
+impl fmt::Display for ChecksumMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl error::Error for ChecksumMismatch {}
+
+fn checksum_mismatch(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, ChecksumMismatch(msg))
+}
+
+/// Whether `err` is a frame failing its CRC or LRC check rather than any other invalid data.
+pub fn is_checksum_error(err: &io::Error) -> bool {
+    err.get_ref().map_or(false, |inner| inner.is::<ChecksumMismatch>())
+}
+
+fn word(data: &[u8], idx: usize) -> u16 {
+    u16::from_be_bytes([data[idx], data[idx + 1]])
+}
+
+fn push_words(out: &mut Vec<u8>, words: &[u16]) {
+    for w in words {
+        out.extend_from_slice(&w.to_be_bytes());
+    }
+}
+
+pub fn pack_bits(bits: &[bool]) -> Vec<u8> {
+    let mut out = vec![0u8; (bits.len() + 7) / 8];
+    for (idx, _) in bits.iter().enumerate().filter(|(_, bit)| **bit) {
+        out[idx / 8] |= 1 << (idx % 8);
+    }
+    out
+}
+
+pub fn unpack_bits(data: &[u8], count: usize) -> Vec<bool> {
+    (0..count).map(|idx| data[idx / 8] & (1 << (idx % 8)) != 0).collect()
+}
+
+fn coil_value(value: bool) -> u16 {
+    if value {
+        0xFF00
+    } else {
+        0x0000
+    }
+}
+
+fn words_from(data: &[u8], count: usize) -> Vec<u16> {
+    (0..count).map(|idx| word(data, idx * 2)).collect()
+}
+
+pub fn encode_request(request: &Request) -> Vec<u8> {
+    let mut out = vec![request.function_code()];
+    match request {
+        Request::ReadCoils(addr, cnt)
+        | Request::ReadDiscreteInputs(addr, cnt)
+        | Request::ReadHoldingRegisters(addr, cnt)
+        | Request::ReadInputRegisters(addr, cnt) => push_words(&mut out, &[*addr, *cnt]),
+        Request::WriteSingleCoil(addr, value) => push_words(&mut out, &[*addr, coil_value(*value)]),
+        Request::WriteSingleRegister(addr, value) => push_words(&mut out, &[*addr, *value]),
+        Request::WriteMultipleCoils(addr, values) => {
+            let packed = pack_bits(values);
+            push_words(&mut out, &[*addr, values.len() as u16]);
+            out.push(packed.len() as u8);
+            out.extend_from_slice(&packed);
+        },
+        Request::WriteMultipleRegisters(addr, values) => {
+            push_words(&mut out, &[*addr, values.len() as u16]);
+            out.push((values.len() * 2) as u8);
+            push_words(&mut out, values);
+        },
+        Request::MaskWriteRegister(addr, and_mask, or_mask) => push_words(&mut out, &[*addr, *and_mask, *or_mask]),
+        Request::ReadWriteMultipleRegisters(read_addr, read_cnt, write_addr, values) => {
+            push_words(&mut out, &[*read_addr, *read_cnt, *write_addr, values.len() as u16]);
+            out.push((values.len() * 2) as u8);
+            push_words(&mut out, values);
+        },
+        Request::Custom(_, data) => out.extend_from_slice(data),
+    }
+    out
+}
+
+/// Parses a request PDU, the error is the exception code to answer with.
+pub fn decode_request(pdu: &[u8]) -> Result<Request, u8> {
+    let fc = *pdu.get(0).ok_or(EXCEPTION_ILLEGAL_FUNCTION)?;
+    let data = &pdu[1..];
+    let need = |len: usize| if data.len() < len { Err(EXCEPTION_ILLEGAL_DATA_VALUE) } else { Ok(()) };
+    let request = match fc {
+        FC_READ_COILS | FC_READ_DISCRETE_INPUTS | FC_READ_HOLDING_REGISTERS | FC_READ_INPUT_REGISTERS => {
+            need(4)?;
+            let (addr, cnt) = (word(data, 0), word(data, 2));
+            let max = if fc <= FC_READ_DISCRETE_INPUTS { 2000 } else { 125 };
+            if cnt == 0 || cnt > max {
+                return Err(EXCEPTION_ILLEGAL_DATA_VALUE);
+            }
+            match fc {
+                FC_READ_COILS => Request::ReadCoils(addr, cnt),
+                FC_READ_DISCRETE_INPUTS => Request::ReadDiscreteInputs(addr, cnt),
+                FC_READ_HOLDING_REGISTERS => Request::ReadHoldingRegisters(addr, cnt),
+                _ => Request::ReadInputRegisters(addr, cnt),
+            }
+        },
+        FC_WRITE_SINGLE_COIL => {
+            need(4)?;
+            match word(data, 2) {
+                0xFF00 => Request::WriteSingleCoil(word(data, 0), true),
+                0x0000 => Request::WriteSingleCoil(word(data, 0), false),
+                _ => return Err(EXCEPTION_ILLEGAL_DATA_VALUE),
+            }
+        },
+        FC_WRITE_SINGLE_REGISTER => {
+            need(4)?;
+            Request::WriteSingleRegister(word(data, 0), word(data, 2))
+        },
+        FC_WRITE_MULTIPLE_COILS => {
+            need(5)?;
+            let (addr, cnt) = (word(data, 0), word(data, 2) as usize);
+            if cnt == 0 || cnt > 1968 || data[4] as usize != (cnt + 7) / 8 {
+                return Err(EXCEPTION_ILLEGAL_DATA_VALUE);
+            }
+            need(5 + data[4] as usize)?;
+            Request::WriteMultipleCoils(addr, unpack_bits(&data[5..], cnt))
+        },
+        FC_WRITE_MULTIPLE_REGISTERS => {
+            need(5)?;
+            let (addr, cnt) = (word(data, 0), word(data, 2) as usize);
+            if cnt == 0 || cnt > 123 || data[4] as usize != cnt * 2 {
+                return Err(EXCEPTION_ILLEGAL_DATA_VALUE);
+            }
+            need(5 + cnt * 2)?;
+            Request::WriteMultipleRegisters(addr, words_from(&data[5..], cnt))
+        },
+        FC_MASK_WRITE_REGISTER => {
+            need(6)?;
+            Request::MaskWriteRegister(word(data, 0), word(data, 2), word(data, 4))
+        },
+        FC_READ_WRITE_MULTIPLE_REGISTERS => {
+            need(9)?;
+            let (read_cnt, write_cnt) = (word(data, 2), word(data, 6) as usize);
+            if read_cnt == 0 || read_cnt > 125 || write_cnt == 0 || write_cnt > 121 || data[8] as usize != write_cnt * 2 {
+                return Err(EXCEPTION_ILLEGAL_DATA_VALUE);
+            }
+            need(9 + write_cnt * 2)?;
+            Request::ReadWriteMultipleRegisters(word(data, 0), read_cnt, word(data, 4), words_from(&data[9..], write_cnt))
+        },
+        fc if fc & 0x80 == 0 => Request::Custom(fc, data.to_vec()),
+        _ => return Err(EXCEPTION_ILLEGAL_FUNCTION),
+    };
+    Ok(request)
+}
+
+pub fn encode_response(response: &Response) -> Vec<u8> {
+    let mut out = vec![response.function_code()];
+    match response {
+        Response::ReadCoils(bits) | Response::ReadDiscreteInputs(bits) => {
+            let packed = pack_bits(bits);
+            out.push(packed.len() as u8);
+            out.extend_from_slice(&packed);
+        },
+        Response::ReadHoldingRegisters(words)
+        | Response::ReadInputRegisters(words)
+        | Response::ReadWriteMultipleRegisters(words) => {
+            out.push((words.len() * 2) as u8);
+            push_words(&mut out, words);
+        },
+        Response::WriteSingleCoil(addr, value) => push_words(&mut out, &[*addr, coil_value(*value)]),
+        Response::WriteSingleRegister(addr, value) => push_words(&mut out, &[*addr, *value]),
+        Response::WriteMultipleCoils(addr, cnt) | Response::WriteMultipleRegisters(addr, cnt) => {
+            push_words(&mut out, &[*addr, *cnt])
+        },
+        Response::MaskWriteRegister(addr, and_mask, or_mask) => push_words(&mut out, &[*addr, *and_mask, *or_mask]),
+        Response::Custom(_, data) => out.extend_from_slice(data),
+    }
+    out
+}
+
+pub fn encode_exception(exception: &ExceptionResponse) -> Vec<u8> {
+    vec![exception.function | 0x80, exception.exception]
+}
+
+/// Parses the response PDU to `request`, checking it really answers it.
+pub fn decode_response(pdu: &[u8], request: &Request) -> io::Result<Result<Response, ExceptionResponse>> {
+    let fc = *pdu.get(0).ok_or_else(|| invalid_data("empty response".to_string()))?;
+    let expected = request.function_code();
+    if fc == expected | 0x80 {
+        let exception = *pdu.get(1).ok_or_else(|| invalid_data("truncated exception response".to_string()))?;
+        return Ok(Err(ExceptionResponse { function: expected, exception }));
+    }
+    if fc != expected {
+        return Err(invalid_data(format!("response function code {:#04x} doesn't match request {:#04x}", fc, expected)));
+    }
+    let data = &pdu[1..];
+    let need = |len: usize| {
+        if data.len() < len {
+            Err(invalid_data(format!("truncated response to function {:#04x}", fc)))
+        } else {
+            Ok(())
+        }
+    };
+    let response = match request {
+        Request::ReadCoils(_, cnt) | Request::ReadDiscreteInputs(_, cnt) => {
+            need(1)?;
+            let cnt = *cnt as usize;
+            if data[0] as usize != (cnt + 7) / 8 {
+                return Err(invalid_data(format!("expected {} coil bytes, got {}", (cnt + 7) / 8, data[0])));
+            }
+            need(1 + data[0] as usize)?;
+            let bits = unpack_bits(&data[1..], cnt);
+            if fc == FC_READ_COILS {
+                Response::ReadCoils(bits)
+            } else {
+                Response::ReadDiscreteInputs(bits)
+            }
+        },
+        Request::ReadHoldingRegisters(_, cnt)
+        | Request::ReadInputRegisters(_, cnt)
+        | Request::ReadWriteMultipleRegisters(_, cnt, _, _) => {
+            need(1)?;
+            let cnt = *cnt as usize;
+            if data[0] as usize != cnt * 2 {
+                return Err(invalid_data(format!("expected {} register bytes, got {}", cnt * 2, data[0])));
+            }
+            need(1 + cnt * 2)?;
+            let words = words_from(&data[1..], cnt);
+            match fc {
+                FC_READ_HOLDING_REGISTERS => Response::ReadHoldingRegisters(words),
+                FC_READ_INPUT_REGISTERS => Response::ReadInputRegisters(words),
+                _ => Response::ReadWriteMultipleRegisters(words),
+            }
+        },
+        Request::WriteSingleCoil(..) => {
+            need(4)?;
+            Response::WriteSingleCoil(word(data, 0), word(data, 2) == 0xFF00)
+        },
+        Request::WriteSingleRegister(..) => {
+            need(4)?;
+            Response::WriteSingleRegister(word(data, 0), word(data, 2))
+        },
+        Request::WriteMultipleCoils(..) => {
+            need(4)?;
+            Response::WriteMultipleCoils(word(data, 0), word(data, 2))
+        },
+        Request::WriteMultipleRegisters(..) => {
+            need(4)?;
+            Response::WriteMultipleRegisters(word(data, 0), word(data, 2))
+        },
+        Request::MaskWriteRegister(..) => {
+            need(6)?;
+            Response::MaskWriteRegister(word(data, 0), word(data, 2), word(data, 4))
+        },
+        Request::Custom(..) => Response::Custom(fc, data.to_vec()),
+    };
+    Ok(Ok(response))
+}
+
+/// One page of a read device identification answer.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceIdentification {
+    pub read_code: u8,
+    pub conformity_level: u8,
+    /// `Some(next object id)` when the objects didn't fit in this answer
+    pub more_follows: Option<u8>,
+    pub objects: Vec<(u8, Vec<u8>)>,
+}
+
+/// Report server id answer, the id itself is device specific and taken as its first byte.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerId {
+    pub id: u8,
+    pub running: bool,
+    pub additional_data: Vec<u8>,
+}
+
+/// Request data of read device identification.
+pub fn device_identification_request(read_code: u8, object_id: u8) -> Vec<u8> {
+    vec![MEI_READ_DEVICE_ID, read_code, object_id]
+}
+
+/// Parses the data of a read device identification response, without the function code.
+pub fn parse_device_identification(data: &[u8]) -> io::Result<DeviceIdentification> {
+    if data.len() < 6 || data[0] != MEI_READ_DEVICE_ID {
+        return Err(invalid_data("malformed read device identification response".to_string()));
+    }
+    let count = data[5] as usize;
+    let mut objects = Vec::with_capacity(count);
+    let mut idx = 6;
+    for _ in 0..count {
+        let header = data.get(idx..idx + 2).ok_or_else(|| invalid_data("truncated device identification object".to_string()))?;
+        let (id, len) = (header[0], header[1] as usize);
+        let value = data
+            .get(idx + 2..idx + 2 + len)
+            .ok_or_else(|| invalid_data(format!("truncated device identification object {:#04x}", id)))?;
+        objects.push((id, value.to_vec()));
+        idx += 2 + len;
+    }
+    Ok(DeviceIdentification {
+        read_code: data[1],
+        conformity_level: data[2],
+        more_follows: if data[3] == 0xFF { Some(data[4]) } else { None },
+        objects,
+    })
+}
+
+/// Parses the data of a report server id response, without the function code.
+pub fn parse_report_server_id(data: &[u8]) -> io::Result<ServerId> {
+    let count = *data.get(0).ok_or_else(|| invalid_data("empty report server id response".to_string()))? as usize;
+    let body = data
+        .get(1..1 + count)
+        .ok_or_else(|| invalid_data("truncated report server id response".to_string()))?;
+    if body.len() < 2 {
+        return Err(invalid_data("report server id response misses the run indicator".to_string()));
+    }
+    Ok(ServerId {
+        id: body[0],
+        running: body[1] == 0xFF,
+        additional_data: body[2..].to_vec(),
+    })
+}
+
+/// Sub-function and data word of a diagnostics request or response.
+pub fn parse_diagnostics(data: &[u8]) -> io::Result<(u16, u16)> {
+    if data.len() < 4 {
+        return Err(invalid_data("truncated diagnostics response".to_string()));
+    }
+    Ok((word(data, 0), word(data, 2)))
+}
+
+/// Status word (0xFFFF while busy) and event count of a get comm event counter response.
+pub fn parse_comm_event_counter(data: &[u8]) -> io::Result<(u16, u16)> {
+    if data.len() < 4 {
+        return Err(invalid_data("truncated get comm event counter response".to_string()));
+    }
+    Ok((word(data, 0), word(data, 2)))
+}
+
+/// Modbus CRC-16 (polynomial 0xA001, initial 0xFFFF), sent low byte first.
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0xFFFF;
+    for byte in data {
+        crc ^= *byte as u16;
+        for _ in 0..8 {
+            if crc & 1 != 0 {
+                crc = (crc >> 1) ^ 0xA001;
+            } else {
+                crc >>= 1;
+            }
+        }
+    }
+    crc
+}
+
+/// `unit + pdu + crc`
+pub fn rtu_frame(unit: u8, pdu: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(pdu.len() + 3);
+    frame.push(unit);
+    frame.extend_from_slice(pdu);
+    let crc = crc16(&frame);
+    frame.extend_from_slice(&crc.to_le_bytes());
+    frame
+}
+
+/// Checks the trailing CRC of an RTU frame, returning unit and PDU.
+pub fn rtu_unframe(frame: &[u8]) -> io::Result<(u8, &[u8])> {
+    if frame.len() < 4 {
+        return Err(invalid_data(format!("RTU frame too short: {} bytes", frame.len())));
+    }
+    let (body, crc) = frame.split_at(frame.len() - 2);
+    let expected = crc16(body);
+    let got = u16::from_le_bytes([crc[0], crc[1]]);
+    if expected != got {
+        return Err(checksum_mismatch(format!("CRC mismatch: expected {:#06x}, got {:#06x}", expected, got)));
+    }
+    Ok((body[0], &body[1..]))
+}
+
+/// Total length of the RTU request frame starting with `head`, `None` until enough bytes are known.
+pub fn rtu_request_len(head: &[u8]) -> Option<usize> {
+    let fc = *head.get(1)?;
+    let len = match fc {
+        0x01..=0x06 => 8,
+        FC_WRITE_MULTIPLE_COILS | FC_WRITE_MULTIPLE_REGISTERS => 9 + *head.get(6)? as usize,
+        FC_MASK_WRITE_REGISTER => 10,
+        FC_READ_WRITE_MULTIPLE_REGISTERS => 13 + *head.get(10)? as usize,
+        // diagnostics: sub-function and one data word
+        FC_DIAGNOSTICS => 8,
+        FC_GET_COMM_EVENT_COUNTER | FC_REPORT_SERVER_ID => 4,
+        // encapsulated interface transport: MEI type, read code, object id
+        FC_ENCAPSULATED_INTERFACE => 7,
+        _ => return Some(head.len().max(4)),
+    };
+    Some(len)
+}
+
+/// Total length of the RTU response frame starting with `head`, `None` until enough bytes are known.
+pub fn rtu_response_len(head: &[u8]) -> Option<usize> {
+    let fc = *head.get(1)?;
+    let len = match fc {
+        fc if fc & 0x80 != 0 => 5,
+        0x01..=0x04 | FC_READ_WRITE_MULTIPLE_REGISTERS | FC_REPORT_SERVER_ID => 5 + *head.get(2)? as usize,
+        0x05 | 0x06 | FC_DIAGNOSTICS | FC_GET_COMM_EVENT_COUNTER | FC_WRITE_MULTIPLE_COILS | FC_WRITE_MULTIPLE_REGISTERS => 8,
+        FC_MASK_WRITE_REGISTER => 10,
+        FC_ENCAPSULATED_INTERFACE => return read_device_id_response_len(head),
+        _ => return Some(head.len().max(4)),
+    };
+    Some(len)
+}
+
+/// The read device identification answer is a list of `id, len, value` objects.
+fn read_device_id_response_len(head: &[u8]) -> Option<usize> {
+    // unit, fc, MEI type, read code, conformity, more follows, next id, object count
+    let count = *head.get(7)? as usize;
+    let mut len = 8;
+    for _ in 0..count {
+        let object_len = *head.get(len + 1)? as usize;
+        len += 2 + object_len;
+    }
+    Some(len + 2)
+}
+
+/// Reads one RTU frame, its length being worked out by `frame_len` as the bytes come in.
+pub async fn read_rtu_frame<R, F>(reader: &mut R, frame_len: F) -> io::Result<Vec<u8>>
+where
+    R: AsyncRead + Unpin,
+    F: Fn(&[u8]) -> Option<usize>,
+{
+    let mut frame = Vec::with_capacity(16);
+    let mut byte = [0u8; 1];
+    loop {
+        if let Some(len) = frame_len(&frame) {
+            if frame.len() >= len {
+                return Ok(frame);
+            }
+        }
+        if frame.len() > MAX_PDU_LEN + 3 {
+            return Err(invalid_data("RTU frame exceeds the maximum length".to_string()));
+        }
+        reader.read_exact(&mut byte).await?;
+        frame.push(byte[0]);
+    }
+}
+
+/// Longitudinal redundancy check of modbus ASCII: two's complement of the byte sum.
+pub fn lrc(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |acc, byte| acc.wrapping_add(*byte)).wrapping_neg()
+}
+
+/// `:` + hex(unit + pdu + lrc) + CRLF
+pub fn ascii_frame(unit: u8, pdu: &[u8]) -> Vec<u8> {
+    let mut body = Vec::with_capacity(pdu.len() + 2);
+    body.push(unit);
+    body.extend_from_slice(pdu);
+    body.push(lrc(&body));
+
+    let mut frame = Vec::with_capacity(body.len() * 2 + 3);
+    frame.push(b':');
+    for byte in body {
+        frame.extend_from_slice(format!("{:02X}", byte).as_bytes());
+    }
+    frame.extend_from_slice(b"\r\n");
+    frame
+}
+
+/// Decodes one ASCII line (without the trailing CRLF), returning unit and PDU.
+pub fn ascii_unframe(line: &[u8]) -> io::Result<(u8, Vec<u8>)> {
+    let hex = match line.split_first() {
+        Some((b':', hex)) => hex,
+        _ => return Err(invalid_data("ASCII frame doesn't start with ':'".to_string())),
+    };
+    if hex.len() < 6 || hex.len() % 2 != 0 {
+        return Err(invalid_data(format!("ASCII frame has an invalid length of {} characters", hex.len())));
+    }
+    let text = std::str::from_utf8(hex).map_err(|_| invalid_data("ASCII frame is not hex".to_string()))?;
+    let mut body = Vec::with_capacity(hex.len() / 2);
+    for idx in (0..text.len()).step_by(2) {
+        let byte = u8::from_str_radix(&text[idx..idx + 2], 16)
+            .map_err(|_| invalid_data(format!("invalid hex '{}' in ASCII frame", &text[idx..idx + 2])))?;
+        body.push(byte);
+    }
+    let (data, checksum) = body.split_at(body.len() - 1);
+    if lrc(data) != checksum[0] {
+        return Err(checksum_mismatch(format!("LRC mismatch: expected {:#04x}, got {:#04x}", lrc(data), checksum[0])));
+    }
+    Ok((data[0], data[1..].to_vec()))
+}
+
+/// Reads up to and including the next LF, dropping anything before the `:` start character.
+pub async fn read_ascii_line<R>(reader: &mut R) -> io::Result<Vec<u8>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut line = Vec::with_capacity(32);
+    let mut byte = [0u8; 1];
+    loop {
+        reader.read_exact(&mut byte).await?;
+        match byte[0] {
+            b':' => {
+                line.clear();
+                line.push(b':');
+            },
+            b'\n' if !line.is_empty() => {
+                if line.last() == Some(&b'\r') {
+                    line.pop();
+                }
+                return Ok(line);
+            },
+            _ if line.is_empty() => {},
+            other => {
+                if line.len() > 2 * (MAX_PDU_LEN + 2) + 2 {
+                    return Err(invalid_data("ASCII frame exceeds the maximum length".to_string()));
+                }
+                line.push(other)
+            },
+        }
+    }
+}
+
+/// Modbus TCP application header.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MbapHeader {
+    pub transaction: u16,
+    pub unit: u8,
+}
+
+pub fn mbap_frame(header: MbapHeader, pdu: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(pdu.len() + 7);
+    frame.extend_from_slice(&header.transaction.to_be_bytes());
+    frame.extend_from_slice(&0u16.to_be_bytes());
+    frame.extend_from_slice(&((pdu.len() + 1) as u16).to_be_bytes());
+    frame.push(header.unit);
+    frame.extend_from_slice(pdu);
+    frame
+}
+
+/// Reads one MBAP frame, returning its header and PDU.
+pub async fn read_mbap_frame<R>(reader: &mut R) -> io::Result<(MbapHeader, Vec<u8>)>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut head = [0u8; 7];
+    reader.read_exact(&mut head).await?;
+    let protocol = u16::from_be_bytes([head[2], head[3]]);
+    let len = u16::from_be_bytes([head[4], head[5]]) as usize;
+    if protocol != 0 {
+        return Err(invalid_data(format!("unknown MBAP protocol id {}", protocol)));
+    }
+    if len < 2 || len > MAX_PDU_LEN + 1 {
+        return Err(invalid_data(format!("invalid MBAP length {}", len)));
+    }
+    let mut pdu = vec![0u8; len - 1];
+    reader.read_exact(&mut pdu).await?;
+    let header = MbapHeader {
+        transaction: u16::from_be_bytes([head[0], head[1]]),
+        unit: head[6],
+    };
+    Ok((header, pdu))
+}
+
+/// Upper case hex dump, used by traces and error messages.
+pub fn hex(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{:02X}", byte)).collect::<Vec<_>>().join(" ")
+}
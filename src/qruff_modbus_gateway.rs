@@ -0,0 +1,252 @@
+//! Modbus TCP to RTU gateway: `modbusGateway(opts)` or `qruff modbus gateway --listen ADDR --serial PATH`.
+//!
+//! Requests from every TCP connection go through one `RtuContext`, whose bus
+//! arbiter keeps them from overlapping on the serial line.
+
+use std::collections::HashMap;
+use std::slice;
+use std::sync::{Arc, Mutex};
+
+use failure::{format_err, Error};
+use futures::future::{AbortHandle, Abortable};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc::Sender;
+
+use crate::{
+    answer_request, run_server, serve_tcp, ModbusServer, RegisterTables, Request, Response,
+    EXCEPTION_GATEWAY_PATH_UNAVAILABLE, EXCEPTION_GATEWAY_TARGET_FAILED, EXCEPTION_ILLEGAL_FUNCTION,
+    FC_DIAGNOSTICS, FC_ENCAPSULATED_INTERFACE, FC_GET_COMM_EVENT_COUNTER, FC_REPORT_SERVER_ID, MEI_READ_DEVICE_ID,
+    ffi, ContextRef, MsgType, RuffCtx, Value, RJSPromise, RespType, RtuContext, RtuOperation,
+    SerialConfig, qruff_rtu_context_class_id, rtu_connect,
+    js_arg, js_get_option, js_get_property, js_throw_type_error, js_to_integer, js_to_string, js_to_vec,
+};
+
+/// Unit id modbus TCP uses to address the gateway itself.
+const GATEWAY_UNIT: u8 = 0xFF;
+const DEFAULT_GATEWAY_HOST: &str = "0.0.0.0";
+
+#[derive(Debug)]
+pub enum GatewayTarget {
+    /// an existing `rtu_setup`/`tcp_setup` context
+    Context(RtuContext),
+    /// a serial line opened by the gateway
+    Serial(SerialConfig),
+}
+
+#[derive(Debug)]
+pub struct GatewayConfig {
+    host: String,
+    port: u16,
+    target: GatewayTarget,
+    unit_map: Option<HashMap<u8, u8>>,
+}
+
+/// Parses `1=3,2=4` into the TCP to bus unit mapping.
+pub fn parse_unit_map(map: &str) -> Result<HashMap<u8, u8>, Error> {
+    map.split(',')
+        .filter(|pair| !pair.trim().is_empty())
+        .map(|pair| {
+            let mut units = pair.splitn(2, '=').map(|unit| unit.trim().parse::<u8>());
+            match (units.next(), units.next()) {
+                (Some(Ok(tcp)), Some(Ok(bus))) => Ok((tcp, bus)),
+                _ => Err(format_err!("unit map entries look like TCP=RTU, got '{}'", pair)),
+            }
+        })
+        .collect()
+}
+
+impl GatewayConfig {
+    pub fn new(listen: &str, target: GatewayTarget, unit_map: Option<HashMap<u8, u8>>) -> Result<GatewayConfig, Error> {
+        let (host, port) = match listen.rfind(':') {
+            Some(idx) => (&listen[..idx], &listen[idx + 1..]),
+            None => (DEFAULT_GATEWAY_HOST, listen),
+        };
+        Ok(GatewayConfig {
+            host: host.to_string(),
+            port: port.parse().map_err(|_| format_err!("invalid gateway port '{}'", port))?,
+            target,
+            unit_map,
+        })
+    }
+
+    /// `{tcp, host, unitMap}` plus either `context` or `rtu` with the `rtu_setup` line options.
+    unsafe fn from_js(ctxt: &ContextRef, opts: ffi::JSValue) -> Result<GatewayConfig, Error> {
+        let port = js_get_option(ctxt, opts, "tcp", |v, name| js_to_integer::<u16>(ctxt, v, name))?
+            .ok_or_else(|| format_err!("modbusGateway needs a tcp port"))?;
+        let host = js_get_option(ctxt, opts, "host", |v, name| js_to_string(ctxt, v, name))?
+            .unwrap_or_else(|| DEFAULT_GATEWAY_HOST.to_string());
+        let rtu = js_get_option(ctxt, opts, "rtu", |v, name| js_to_string(ctxt, v, name))?;
+
+        let context = js_get_property(ctxt, opts, "context");
+        let ptr = Value::from(context).get_opaque::<RtuContext>(qruff_rtu_context_class_id());
+        let shared = ptr.as_ref().cloned();
+        ctxt.free_value(context);
+
+        let target = match (shared, rtu) {
+            (Some(context), None) => GatewayTarget::Context(context),
+            (None, Some(path)) => GatewayTarget::Serial(SerialConfig::from_js(ctxt, path, opts)?),
+            _ => return Err(format_err!("modbusGateway needs either a context or an rtu path")),
+        };
+        let unit_map = js_get_option(ctxt, opts, "unitMap", |v, name| {
+            js_to_vec(ctxt, v, name, |pair, name| {
+                let units: Vec<u8> = js_to_vec(ctxt, pair, name, |unit, name| js_to_integer(ctxt, unit, name))?;
+                match units[..] {
+                    [tcp, bus] => Ok((tcp, bus)),
+                    _ => Err(format_err!("{} must be a [tcpUnit, rtuUnit] pair", name)),
+                }
+            })
+        })?;
+        Ok(GatewayConfig {
+            host,
+            port,
+            target,
+            unit_map: unit_map.map(|pairs| pairs.into_iter().collect()),
+        })
+    }
+}
+
+fn operation_for(request: &Request) -> Option<RtuOperation> {
+    let operation = match *request {
+        Request::ReadCoils(addr, cnt) => RtuOperation::ReadCoils(addr, cnt),
+        Request::ReadDiscreteInputs(addr, cnt) => RtuOperation::ReadDiscreteInputs(addr, cnt),
+        Request::ReadHoldingRegisters(addr, cnt) => RtuOperation::ReadHoldingRegisters(addr, cnt),
+        Request::ReadInputRegisters(addr, cnt) => RtuOperation::ReadInputRegisters(addr, cnt),
+        Request::WriteSingleCoil(addr, value) => RtuOperation::WriteSingleCoil(addr, value),
+        Request::WriteSingleRegister(addr, value) => RtuOperation::WriteSingleRegister(addr, value),
+        Request::WriteMultipleCoils(addr, ref values) => RtuOperation::WriteMultipleCoils(addr, values.clone()),
+        Request::WriteMultipleRegisters(addr, ref values) => RtuOperation::WriteMultipleRegisters(addr, values.clone()),
+        Request::MaskWriteRegister(addr, and_mask, or_mask) => RtuOperation::MaskedWriteRegister(addr, and_mask, or_mask),
+        Request::ReadWriteMultipleRegisters(read_addr, read_cnt, write_addr, ref values) => {
+            RtuOperation::ReadWriteMultipleRegisters(read_addr, read_cnt, write_addr, values.clone())
+        },
+        Request::Custom(FC_ENCAPSULATED_INTERFACE, ref data) if data.len() == 3 && data[0] == MEI_READ_DEVICE_ID => {
+            RtuOperation::ReadDeviceIdentification(data[1], data[2])
+        },
+        Request::Custom(FC_REPORT_SERVER_ID, _) => RtuOperation::ReportServerId,
+        Request::Custom(FC_DIAGNOSTICS, ref data) if data.len() == 4 => {
+            RtuOperation::Diagnostics(u16::from_be_bytes([data[0], data[1]]), u16::from_be_bytes([data[2], data[3]]))
+        },
+        Request::Custom(FC_GET_COMM_EVENT_COUNTER, _) => RtuOperation::GetCommEventCounter,
+        Request::Custom(..) => return None,
+    };
+    Some(operation)
+}
+
+#[derive(Clone)]
+struct Gateway {
+    context: RtuContext,
+    unit_map: Option<Arc<HashMap<u8, u8>>>,
+}
+
+impl Gateway {
+    /// Unit on the bus a TCP unit id is forwarded to, `None` when it isn't mapped.
+    fn bus_unit(&self, unit: u8) -> Option<u8> {
+        match self.unit_map {
+            Some(ref map) => map.get(&unit).cloned(),
+            None if unit == GATEWAY_UNIT => Some(self.context.unit()),
+            None => Some(unit),
+        }
+    }
+
+    async fn forward(&self, unit: u8, request: Request) -> Result<Response, u8> {
+        let bus_unit = self.bus_unit(unit).ok_or(EXCEPTION_GATEWAY_PATH_UNAVAILABLE)?;
+        let operation = operation_for(&request).ok_or(EXCEPTION_ILLEGAL_FUNCTION)?;
+        match self.context.run(&operation, bus_unit, self.context.policy(), None).await {
+            Ok(response) => Ok(operation.response(&response)),
+            // exceptions pass through, a silent or broken device is the gateway's to report
+            Err(err) => {
+                debug!("gateway request to unit {} failed: {}", bus_unit, err);
+                Err(err.code.exception_code().unwrap_or(EXCEPTION_GATEWAY_TARGET_FAILED))
+            },
+        }
+    }
+
+    async fn answer(&self, unit: u8, pdu: Vec<u8>) -> Vec<u8> {
+        answer_request(&pdu, |request| self.forward(unit, request)).await
+    }
+}
+
+async fn gateway_listen(config: GatewayConfig) -> Result<(Gateway, TcpListener), Error> {
+    let context = match config.target {
+        GatewayTarget::Context(context) => context,
+        GatewayTarget::Serial(ref serial) => rtu_connect(serial).await?,
+    };
+    let listener = TcpListener::bind((config.host.as_str(), config.port))
+        .await
+        .map_err(|err| format_err!("failed to listen on {}:{}: {}", config.host, config.port, err))?;
+    let gateway = Gateway {
+        context,
+        unit_map: config.unit_map.map(Arc::new),
+    };
+    Ok((gateway, listener))
+}
+
+fn gateway_answer(gateway: Gateway) -> impl Fn(u8, Vec<u8>) -> futures::future::BoxFuture<'static, Vec<u8>> + Clone {
+    move |unit, pdu| {
+        let gateway = gateway.clone();
+        Box::pin(async move { gateway.answer(unit, pdu).await })
+    }
+}
+
+/// A gateway runs as a modbus server without tables, so it shares the server's JS class.
+pub async fn modbus_gateway_start(config: GatewayConfig, mut tx: Sender<RespType>, job_id: u32) {
+    let started = gateway_listen(config).await.map(|(gateway, listener)| {
+        let local_addr = listener.local_addr().ok();
+        let (abort, registration) = AbortHandle::new_pair();
+        let serve = Abortable::new(serve_tcp(job_id, gateway_answer(gateway), listener, None), registration);
+        tokio::spawn(run_server(serve, tx.clone(), job_id));
+        ModbusServer::new(job_id, Arc::new(Mutex::new(RegisterTables::default())), abort, local_addr)
+    });
+    tx.send(RespType::ModbusServerStarted(job_id, started)).await.unwrap();
+}
+
+/// Runs the gateway given on the command line until interrupted.
+pub fn run_gateway_cli(listen: &str, serial: SerialConfig, unit_map: Option<&str>) -> Result<(), Error> {
+    let unit_map = unit_map.map(parse_unit_map).transpose()?;
+    let config = GatewayConfig::new(listen, GatewayTarget::Serial(serial), unit_map)?;
+
+    let mut event_rt = tokio::runtime::Builder::new()
+        .threaded_scheduler()
+        .enable_all()
+        .build()?;
+    event_rt.block_on(async {
+        let (gateway, listener) = gateway_listen(config).await?;
+        println!("modbus gateway listening on {}", listener.local_addr()?);
+        tokio::select! {
+            res = serve_tcp(0, gateway_answer(gateway), listener, None) => res?,
+            _ = tokio::signal::ctrl_c() => {},
+        }
+        Ok::<(), Error>(())
+    })
+}
+
+pub unsafe extern "C" fn qruff_modbus_gateway(
+    ctx: *mut ffi::JSContext,
+    _this_val: ffi::JSValue,
+    argc: ::std::os::raw::c_int,
+    argv: *mut ffi::JSValue,
+) -> ffi::JSValue {
+    let ctxt = ContextRef::from_ptr(ctx);
+    let args = slice::from_raw_parts(argv, argc as usize);
+
+    let config = match GatewayConfig::from_js(ctxt, js_arg(args, 0)) {
+        Ok(config) => config,
+        Err(err) => return js_throw_type_error(ctxt, &err.to_string()),
+    };
+    let mut ruff_ctx = ctxt.userdata::<RuffCtx>().unwrap();
+
+    let rfunc: [ffi::JSValue; 2] = [ffi::UNDEFINED; 2];
+    let id = ruff_ctx.as_mut().id_generator.next_id();
+    let promise = ffi::JS_NewPromiseCapability(ctxt.as_ptr(), rfunc.as_ptr() as *mut _);
+    let handle = RJSPromise::new(
+        id,
+        ctxt,
+        &Value::from(promise),
+        &Value::from(rfunc[0]),
+        &Value::from(rfunc[1]),
+    );
+
+    let mut request_msg = ruff_ctx.as_mut().request_msg.lock().unwrap();
+    request_msg.push(MsgType::CreateModbusGateway(id, config, handle));
+    promise
+}
@@ -0,0 +1,278 @@
+//! Bus scan: `modbusScan(path, opts)` or `qruff modbus scan --port PATH`.
+//!
+//! Every baud rate and parity is tried in turn through `rtu_connect`, probing
+//! each unit with a one register read. A unit answering, even with an
+//! exception, is a device on the bus.
+
+use std::fmt;
+use std::slice;
+use std::time::Duration;
+
+use failure::{format_err, Error};
+use tokio::sync::mpsc::Sender;
+use tokio_serial::Parity;
+
+use crate::{
+    ffi, ContextRef, MsgType, RuffCtx, Value, RJSPromise, RespType, Framing, ModbusError, RtuOperation,
+    SerialConfig, js_modbus_error, parse_parity, rtu_connect,
+    js_arg, js_get_option, js_new_array, js_new_value, js_set_property, js_throw_type_error, js_to_integer, js_to_string,
+    js_to_vec, settle_promise,
+};
+
+const DEFAULT_SCAN_BAUDS: [u32; 1] = [9600];
+const DEFAULT_SCAN_PARITIES: [Parity; 2] = [Parity::Even, Parity::None];
+const DEFAULT_SCAN_UNITS: &str = "1-247";
+const DEFAULT_PROBE_TIMEOUT_MS: u64 = 100;
+const MAX_SCAN_UNIT: u8 = 247;
+
+/// Holding register 0 is the cheapest read there is, devices without it answer with an exception.
+const PROBE: RtuOperation = RtuOperation::ReadHoldingRegisters(0, 1);
+
+#[derive(Debug)]
+pub struct ScanConfig {
+    path: String,
+    bauds: Vec<u32>,
+    parities: Vec<Parity>,
+    units: Vec<u8>,
+    framing: Framing,
+    /// how long a single probe waits for its answer
+    timeout: Duration,
+}
+
+/// A unit that answered the probe.
+#[derive(Debug, Clone)]
+pub struct ScanHit {
+    pub baud: u32,
+    pub parity: Parity,
+    pub unit: u8,
+    /// exception code the probe was answered with
+    pub exception: Option<u8>,
+}
+
+impl fmt::Display for ScanHit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unit {} at {} baud, {} parity", self.unit, self.baud, parity_name(self.parity))?;
+        match self.exception {
+            Some(exception) => write!(f, " (answered with exception {})", exception),
+            None => Ok(()),
+        }
+    }
+}
+
+fn parity_name(parity: Parity) -> &'static str {
+    match parity {
+        Parity::None => "none",
+        Parity::Even => "even",
+        Parity::Odd => "odd",
+    }
+}
+
+/// Parses `1-247` or `1,2,10-20` into the list of units to probe.
+pub fn parse_units(units: &str) -> Result<Vec<u8>, Error> {
+    let mut parsed = Vec::new();
+    for range in units.split(',').map(str::trim).filter(|range| !range.is_empty()) {
+        let mut bounds = range.splitn(2, '-').map(|unit| unit.trim().parse::<u8>());
+        let (first, last) = match (bounds.next(), bounds.next()) {
+            (Some(Ok(unit)), None) => (unit, unit),
+            (Some(Ok(first)), Some(Ok(last))) => (first, last),
+            _ => return Err(format_err!("units look like 1-247 or 1,2,5, got '{}'", range)),
+        };
+        if first == 0 || last > MAX_SCAN_UNIT || first > last {
+            return Err(format_err!("units must be between 1 and {}, got '{}'", MAX_SCAN_UNIT, range));
+        }
+        parsed.extend(first..=last);
+    }
+    if parsed.is_empty() {
+        return Err(format_err!("no units to scan"));
+    }
+    Ok(parsed)
+}
+
+/// Parses a comma separated list, e.g. the `--bauds` and `--parities` of the command line.
+fn parse_list<T, F>(list: &str, parse: F) -> Result<Vec<T>, Error>
+where
+    F: Fn(&str) -> Result<T, Error>,
+{
+    list.split(',').map(str::trim).filter(|item| !item.is_empty()).map(parse).collect()
+}
+
+impl ScanConfig {
+    pub fn new(
+        path: String,
+        bauds: &str,
+        parities: &str,
+        units: &str,
+        framing: &str,
+        timeout_ms: u64,
+    ) -> Result<ScanConfig, Error> {
+        let config = ScanConfig {
+            path,
+            bauds: parse_list(bauds, |baud| baud.parse().map_err(|_| format_err!("invalid baud rate '{}'", baud)))?,
+            parities: parse_list(parities, parse_parity)?,
+            units: parse_units(units)?,
+            framing: framing.parse()?,
+            timeout: Duration::from_millis(timeout_ms),
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// `{bauds, parities, units, framing, timeoutMs}`, `units` being a list or a string like `1-247`.
+    unsafe fn from_js(ctxt: &ContextRef, path: String, opts: ffi::JSValue) -> Result<ScanConfig, Error> {
+        let bauds = js_get_option(ctxt, opts, "bauds", |v, name| {
+            js_to_vec(ctxt, v, name, |item, name| js_to_integer(ctxt, item, name))
+        })?;
+        let parities = js_get_option(ctxt, opts, "parities", |v, name| {
+            js_to_vec(ctxt, v, name, |item, name| parse_parity(&js_to_string(ctxt, item, name)?))
+        })?;
+        let units = js_get_option(ctxt, opts, "units", |v, name| {
+            if Value::from(v).is_string() {
+                parse_units(&js_to_string(ctxt, v, name)?)
+            } else {
+                js_to_vec(ctxt, v, name, |item, name| js_to_integer(ctxt, item, name))
+            }
+        })?;
+        let config = ScanConfig {
+            path,
+            bauds: bauds.unwrap_or_else(|| DEFAULT_SCAN_BAUDS.to_vec()),
+            parities: parities.unwrap_or_else(|| DEFAULT_SCAN_PARITIES.to_vec()),
+            units: units.map_or_else(|| parse_units(DEFAULT_SCAN_UNITS), Ok)?,
+            framing: js_get_option(ctxt, opts, "framing", |v, name| js_to_string(ctxt, v, name))?
+                .map(|framing| framing.parse())
+                .transpose()?
+                .unwrap_or(Framing::Rtu),
+            timeout: Duration::from_millis(
+                js_get_option(ctxt, opts, "timeoutMs", |v, name| js_to_integer(ctxt, v, name))?
+                    .unwrap_or(DEFAULT_PROBE_TIMEOUT_MS),
+            ),
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        if self.bauds.is_empty() || self.parities.is_empty() {
+            return Err(format_err!("a scan needs at least one baud rate and one parity"));
+        }
+        if let Some(unit) = self.units.iter().find(|unit| **unit == 0 || **unit > MAX_SCAN_UNIT) {
+            return Err(format_err!("units must be between 1 and {}, got {}", MAX_SCAN_UNIT, unit));
+        }
+        if self.timeout == Duration::from_millis(0) {
+            return Err(format_err!("timeoutMs must be greater than 0"));
+        }
+        Ok(())
+    }
+}
+
+/// Probes every line setting and unit of `config`, telling `found` about each device as it answers.
+pub async fn scan_bus<F>(config: &ScanConfig, mut found: F) -> Result<Vec<ScanHit>, Error>
+where
+    F: FnMut(&ScanHit),
+{
+    let mut hits = Vec::new();
+    for &baud in &config.bauds {
+        for &parity in &config.parities {
+            let serial = SerialConfig::probe(config.path.clone(), baud, parity, config.framing, config.timeout)?;
+            let context = rtu_connect(&serial).await?;
+            debug!("scanning {} at {} baud, {} parity", config.path, baud, parity_name(parity));
+            for &unit in &config.units {
+                let exception = match context.run(&PROBE, unit, context.policy(), None).await {
+                    Ok(_) => None,
+                    Err(err) => match err.code.exception_code() {
+                        Some(exception) => Some(exception),
+                        // silence or line noise, most likely nobody there at these settings
+                        None => continue,
+                    },
+                };
+                let hit = ScanHit { baud, parity, unit, exception };
+                found(&hit);
+                hits.push(hit);
+            }
+            // the next line settings reopen the port
+            context.close().await;
+        }
+    }
+    Ok(hits)
+}
+
+pub async fn modbus_scan(config: ScanConfig, mut tx: Sender<RespType>, job_id: u32) {
+    let hits = scan_bus(&config, |hit| debug!("scan {} found {}", job_id, hit)).await;
+    tx.send(RespType::ModbusScan(job_id, hits)).await.unwrap();
+}
+
+/// Runs the scan given on the command line, printing the devices as they are found.
+pub fn run_scan_cli(config: ScanConfig) -> Result<(), Error> {
+    let mut event_rt = tokio::runtime::Builder::new()
+        .basic_scheduler()
+        .enable_all()
+        .build()?;
+    event_rt.block_on(async {
+        println!(
+            "scanning {}: {} baud rate(s), {} parity setting(s), {} unit(s)",
+            config.path,
+            config.bauds.len(),
+            config.parities.len(),
+            config.units.len()
+        );
+        let hits = scan_bus(&config, |hit| println!("found {}", hit)).await?;
+        println!("{} device(s) answered", hits.len());
+        Ok::<(), Error>(())
+    })
+}
+
+pub fn qruff_modbus_scan_settle_promise<'a>(promise: RJSPromise<'a>, hits: Result<Vec<ScanHit>, Error>) {
+    let result = match hits {
+        Ok(hits) => unsafe {
+            let ctxt = promise.ctxt;
+            let hits = hits
+                .into_iter()
+                .map(|hit| {
+                    let obj = ffi::JS_NewObject(ctxt.as_ptr());
+                    js_set_property(ctxt, obj, "baud", js_new_value(ctxt, hit.baud as i32));
+                    js_set_property(ctxt, obj, "parity", js_new_value(ctxt, String::from(parity_name(hit.parity))));
+                    js_set_property(ctxt, obj, "unit", js_new_value(ctxt, hit.unit as i32));
+                    if let Some(exception) = hit.exception {
+                        js_set_property(ctxt, obj, "exceptionCode", js_new_value(ctxt, exception as i32));
+                    }
+                    obj
+                })
+                .collect();
+            Ok(js_new_array(ctxt, hits))
+        },
+        Err(err) => Err(unsafe { js_modbus_error(promise.ctxt, &ModbusError::from_error(err)) }),
+    };
+    settle_promise(&promise, result);
+}
+
+/// `modbusScan(path, opts)`, resolves to the list of `{baud, parity, unit, exceptionCode}` that answered.
+pub unsafe extern "C" fn qruff_modbus_scan(
+    ctx: *mut ffi::JSContext,
+    _this_val: ffi::JSValue,
+    argc: ::std::os::raw::c_int,
+    argv: *mut ffi::JSValue,
+) -> ffi::JSValue {
+    let ctxt = ContextRef::from_ptr(ctx);
+    let args = slice::from_raw_parts(argv, argc as usize);
+
+    let config = js_to_string(ctxt, js_arg(args, 0), "path").and_then(|path| ScanConfig::from_js(ctxt, path, js_arg(args, 1)));
+    let config = match config {
+        Ok(config) => config,
+        Err(err) => return js_throw_type_error(ctxt, &err.to_string()),
+    };
+    let mut ruff_ctx = ctxt.userdata::<RuffCtx>().unwrap();
+
+    let rfunc: [ffi::JSValue; 2] = [ffi::UNDEFINED; 2];
+    let id = ruff_ctx.as_mut().id_generator.next_id();
+    let promise = ffi::JS_NewPromiseCapability(ctxt.as_ptr(), rfunc.as_ptr() as *mut _);
+    let handle = RJSPromise::new(
+        id,
+        ctxt,
+        &Value::from(promise),
+        &Value::from(rfunc[0]),
+        &Value::from(rfunc[1]),
+    );
+
+    let mut request_msg = ruff_ctx.as_mut().request_msg.lock().unwrap();
+    request_msg.push(MsgType::ModbusScan(id, config, handle));
+    promise
+}
@@ -0,0 +1,887 @@
+//! Modbus slave mode: `modbusServer({tcp: port} | {rtu: path, framing, ...})`.
+//!
+//! Requests are answered from backing tables, or handed to a JS `handler`
+//! first. The framing is ours rather than the tokio-modbus server, which can
+//! neither see the unit id nor answer with an exception.
+
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::ops::Range;
+use std::slice;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use failure::{format_err, Error};
+use futures::future::{AbortHandle, Abortable, Aborted};
+use futures::stream::{FuturesUnordered, StreamExt};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, PollEvented};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::Sender;
+use tokio::sync::oneshot;
+use tokio_serial::Serial;
+
+use crate::{
+    ascii_frame, ascii_unframe, decode_request, encode_exception, encode_response, mbap_frame, read_ascii_line,
+    read_mbap_frame, read_rtu_frame, rtu_frame, rtu_request_len, rtu_unframe, ExceptionResponse, Request, Response,
+    EXCEPTION_GATEWAY_PATH_UNAVAILABLE, EXCEPTION_ILLEGAL_DATA_ADDRESS, EXCEPTION_ILLEGAL_FUNCTION,
+    EXCEPTION_SLAVE_DEVICE_FAILURE,
+    ffi, mem, ClassId, ContextRef, MsgType, RuffCtx, Runtime, RuntimeRef, Value, RJSCallback, RJSPromise, RespType,
+    Framing, SerialConfig, ServedPort, FaultAction, Faults, PtyMaster, ModbusError, ModbusErrorCode, js_bool_array, js_modbus_error, opt_unit,
+    js_arg, js_get_option, js_get_property, js_is_undefined, js_new_array, js_new_value, js_set_property,
+    js_throw_type_error, js_to_bool, js_to_integer, js_to_string, js_to_vec, settle_promise,
+};
+
+/// Every modbus address is 16 bits wide.
+pub const MAX_TABLE_SIZE: usize = 0x10000;
+const DEFAULT_SERVER_HOST: &str = "0.0.0.0";
+/// RTU requests sent to unit 0 are executed by every slave and answered by none.
+const BROADCAST_UNIT: u8 = 0;
+
+/// Data a server answers from when no handler took the request.
+#[derive(Debug, Default)]
+pub struct RegisterTables {
+    pub coils: Vec<bool>,
+    pub discrete_inputs: Vec<bool>,
+    pub holding_registers: Vec<u16>,
+    pub input_registers: Vec<u16>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Table {
+    Coils,
+    DiscreteInputs,
+    HoldingRegisters,
+    InputRegisters,
+}
+
+impl FromStr for Table {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "coils" => Table::Coils,
+            "discreteInputs" => Table::DiscreteInputs,
+            "holdingRegisters" => Table::HoldingRegisters,
+            "inputRegisters" => Table::InputRegisters,
+            _ => return Err(format_err!(
+                "table must be one of coils, discreteInputs, holdingRegisters, inputRegisters, got '{}'",
+                s
+            )),
+        })
+    }
+}
+
+enum TableMut<'a> {
+    Bits(&'a mut Vec<bool>),
+    Registers(&'a mut Vec<u16>),
+}
+
+fn table_range(len: usize, addr: u16, cnt: usize) -> Result<Range<usize>, u8> {
+    let end = addr as usize + cnt;
+    if end > len {
+        return Err(EXCEPTION_ILLEGAL_DATA_ADDRESS);
+    }
+    Ok(addr as usize..end)
+}
+
+impl RegisterTables {
+    fn table_mut(&mut self, table: Table) -> TableMut<'_> {
+        match table {
+            Table::Coils => TableMut::Bits(&mut self.coils),
+            Table::DiscreteInputs => TableMut::Bits(&mut self.discrete_inputs),
+            Table::HoldingRegisters => TableMut::Registers(&mut self.holding_registers),
+            Table::InputRegisters => TableMut::Registers(&mut self.input_registers),
+        }
+    }
+
+    /// Executes `request` against the tables, the error is the exception code to answer with.
+    pub fn serve(&mut self, request: &Request) -> Result<Response, u8> {
+        let response = match *request {
+            Request::ReadCoils(addr, cnt) => {
+                Response::ReadCoils(self.coils[table_range(self.coils.len(), addr, cnt as usize)?].to_vec())
+            },
+            Request::ReadDiscreteInputs(addr, cnt) => {
+                let range = table_range(self.discrete_inputs.len(), addr, cnt as usize)?;
+                Response::ReadDiscreteInputs(self.discrete_inputs[range].to_vec())
+            },
+            Request::ReadHoldingRegisters(addr, cnt) => {
+                let range = table_range(self.holding_registers.len(), addr, cnt as usize)?;
+                Response::ReadHoldingRegisters(self.holding_registers[range].to_vec())
+            },
+            Request::ReadInputRegisters(addr, cnt) => {
+                let range = table_range(self.input_registers.len(), addr, cnt as usize)?;
+                Response::ReadInputRegisters(self.input_registers[range].to_vec())
+            },
+            Request::WriteSingleCoil(addr, value) => {
+                let range = table_range(self.coils.len(), addr, 1)?;
+                self.coils[range.start] = value;
+                Response::WriteSingleCoil(addr, value)
+            },
+            Request::WriteSingleRegister(addr, value) => {
+                let range = table_range(self.holding_registers.len(), addr, 1)?;
+                self.holding_registers[range.start] = value;
+                Response::WriteSingleRegister(addr, value)
+            },
+            Request::WriteMultipleCoils(addr, ref values) => {
+                let range = table_range(self.coils.len(), addr, values.len())?;
+                self.coils[range].copy_from_slice(values);
+                Response::WriteMultipleCoils(addr, values.len() as u16)
+            },
+            Request::WriteMultipleRegisters(addr, ref values) => {
+                let range = table_range(self.holding_registers.len(), addr, values.len())?;
+                self.holding_registers[range].copy_from_slice(values);
+                Response::WriteMultipleRegisters(addr, values.len() as u16)
+            },
+            Request::MaskWriteRegister(addr, and_mask, or_mask) => {
+                let range = table_range(self.holding_registers.len(), addr, 1)?;
+                let current = self.holding_registers[range.start];
+                self.holding_registers[range.start] = (current & and_mask) | (or_mask & !and_mask);
+                Response::MaskWriteRegister(addr, and_mask, or_mask)
+            },
+            Request::ReadWriteMultipleRegisters(read_addr, read_cnt, write_addr, ref values) => {
+                // the spec performs the write before the read
+                let len = self.holding_registers.len();
+                let write = table_range(len, write_addr, values.len())?;
+                let read = table_range(len, read_addr, read_cnt as usize)?;
+                self.holding_registers[write].copy_from_slice(values);
+                Response::ReadWriteMultipleRegisters(self.holding_registers[read].to_vec())
+            },
+            Request::Custom(..) => return Err(EXCEPTION_ILLEGAL_FUNCTION),
+        };
+        Ok(response)
+    }
+}
+
+#[derive(Debug)]
+pub enum ServerTransport {
+    Tcp { host: String, port: u16 },
+    Rtu(SerialConfig),
+    /// a new pseudo terminal, its other end being opened by the client
+    Pty(Framing),
+}
+
+#[derive(Debug)]
+pub struct ServerConfig {
+    transport: ServerTransport,
+    /// only answer this unit id, every unit is answered when unset
+    unit: Option<u8>,
+    tables: RegisterTables,
+    /// injected misbehaviour, simulators only
+    faults: Option<Faults>,
+}
+
+/// Reads a table option, either its size or its initial content.
+unsafe fn opt_table<T, F>(ctxt: &ContextRef, opts: ffi::JSValue, name: &str, f: F) -> Result<Vec<T>, Error>
+where
+    T: Clone + Default,
+    F: FnMut(ffi::JSValue, &str) -> Result<T, Error>,
+{
+    let table = js_get_option(ctxt, opts, name, |v, name| {
+        if Value::from(v).is_number() {
+            let size: usize = js_to_integer(ctxt, v, name)?;
+            Ok(vec![T::default(); size.min(MAX_TABLE_SIZE + 1)])
+        } else {
+            js_to_vec(ctxt, v, name, f)
+        }
+    })?
+    .unwrap_or_default();
+    if table.len() > MAX_TABLE_SIZE {
+        return Err(format_err!("{} can hold at most {} entries", name, MAX_TABLE_SIZE));
+    }
+    Ok(table)
+}
+
+impl ServerConfig {
+    /// `{tcp, host}` or `{rtu}` plus the `rtu_setup` line options, `unit`, and the
+    /// tables `coils`, `discreteInputs`, `holdingRegisters`, `inputRegisters`.
+    unsafe fn from_js(ctxt: &ContextRef, opts: ffi::JSValue) -> Result<ServerConfig, Error> {
+        let tcp = js_get_option(ctxt, opts, "tcp", |v, name| js_to_integer::<u16>(ctxt, v, name))?;
+        let rtu = js_get_option(ctxt, opts, "rtu", |v, name| js_to_string(ctxt, v, name))?;
+        let (transport, unit) = match (tcp, rtu) {
+            (Some(port), None) => {
+                let host = js_get_option(ctxt, opts, "host", |v, name| js_to_string(ctxt, v, name))?
+                    .unwrap_or_else(|| DEFAULT_SERVER_HOST.to_string());
+                (ServerTransport::Tcp { host, port }, opt_unit(ctxt, opts, u8::max_value())?)
+            },
+            (None, Some(path)) => {
+                let serial = SerialConfig::from_js(ctxt, path, opts)?;
+                let unit = Some(serial.unit);
+                (ServerTransport::Rtu(serial), unit)
+            },
+            _ => return Err(format_err!("modbusServer needs either a tcp port or an rtu path")),
+        };
+        Ok(ServerConfig {
+            transport,
+            unit,
+            tables: RegisterTables {
+                coils: opt_table(ctxt, opts, "coils", |v, _| Ok(js_to_bool(ctxt, v)))?,
+                discrete_inputs: opt_table(ctxt, opts, "discreteInputs", |v, _| Ok(js_to_bool(ctxt, v)))?,
+                holding_registers: opt_table(ctxt, opts, "holdingRegisters", |v, name| js_to_integer(ctxt, v, name))?,
+                input_registers: opt_table(ctxt, opts, "inputRegisters", |v, name| js_to_integer(ctxt, v, name))?,
+            },
+            faults: None,
+        })
+    }
+
+    /// A simulated device answering `unit` on a new pseudo terminal.
+    pub fn simulator(framing: Framing, unit: u8, tables: RegisterTables, faults: Faults) -> ServerConfig {
+        ServerConfig {
+            transport: ServerTransport::Pty(framing),
+            unit: Some(unit),
+            tables,
+            faults: Some(faults),
+        }
+    }
+}
+
+/// A request handed to the JS handler.
+#[derive(Debug)]
+pub struct ServerRequest {
+    pub unit: u8,
+    pub request: Request,
+}
+
+/// What the JS handler made of a request: `None` leaves it to the tables,
+/// an error is the exception code to answer with.
+pub type ServerReply = Result<Option<Response>, u8>;
+
+/// The running server behind a JS `QRuffModbusServer` object.
+#[derive(Debug)]
+pub struct ModbusServer {
+    id: u32,
+    tables: Arc<Mutex<RegisterTables>>,
+    abort: AbortHandle,
+    local_addr: Option<SocketAddr>,
+    /// the serial port to open for a simulator
+    path: Option<String>,
+    faults: Option<Arc<Mutex<Faults>>>,
+}
+
+impl ModbusServer {
+    pub fn new(id: u32, tables: Arc<Mutex<RegisterTables>>, abort: AbortHandle, local_addr: Option<SocketAddr>) -> Self {
+        ModbusServer {
+            id,
+            tables,
+            abort,
+            local_addr,
+            path: None,
+            faults: None,
+        }
+    }
+
+    pub fn path(&self) -> Option<&str> {
+        self.path.as_deref()
+    }
+
+    /// Replaces the faults of a simulator.
+    pub fn inject(&self, faults: Faults) -> Result<(), Error> {
+        match self.faults {
+            Some(ref current) => {
+                *current.lock().unwrap() = faults;
+                Ok(())
+            },
+            None => Err(format_err!("only simulators take injected faults")),
+        }
+    }
+}
+
+impl Drop for ModbusServer {
+    /// The listener doesn't outlive the JS object.
+    fn drop(&mut self) {
+        self.abort.abort();
+    }
+}
+
+/// State shared by every connection of one server.
+#[derive(Clone)]
+struct ServerShared {
+    id: u32,
+    tables: Arc<Mutex<RegisterTables>>,
+    has_handler: bool,
+    tx: Sender<RespType>,
+    faults: Option<Arc<Mutex<Faults>>>,
+}
+
+/// Answers one request PDU with a response or exception PDU, `dispatch` executing the decoded request.
+pub async fn answer_request<F, Fut>(pdu: &[u8], dispatch: F) -> Vec<u8>
+where
+    F: FnOnce(Request) -> Fut,
+    Fut: Future<Output = Result<Response, u8>>,
+{
+    let function = pdu.get(0).map_or(0, |fc| fc & 0x7F);
+    let result = match decode_request(pdu) {
+        Ok(request) => dispatch(request).await,
+        Err(exception) => Err(exception),
+    };
+    match result {
+        Ok(response) => encode_response(&response),
+        Err(exception) => encode_exception(&ExceptionResponse { function, exception }),
+    }
+}
+
+impl ServerShared {
+    async fn dispatch(&mut self, unit: u8, request: Request) -> Result<Response, u8> {
+        if self.has_handler {
+            let (reply_tx, reply_rx) = oneshot::channel();
+            let server_request = ServerRequest { unit, request: request.clone() };
+            if self.tx.send(RespType::ModbusServerRequest(self.id, server_request, reply_tx)).await.is_err() {
+                return Err(EXCEPTION_SLAVE_DEVICE_FAILURE);
+            }
+            match reply_rx.await {
+                Ok(Ok(Some(response))) => return Ok(response),
+                Ok(Ok(None)) => {},
+                Ok(Err(exception)) => return Err(exception),
+                Err(_) => return Err(EXCEPTION_SLAVE_DEVICE_FAILURE),
+            }
+        }
+        let result = self.tables.lock().unwrap().serve(&request);
+        result
+    }
+
+    async fn answer(&mut self, unit: u8, pdu: Vec<u8>) -> Vec<u8> {
+        answer_request(&pdu, |request| self.dispatch(unit, request)).await
+    }
+
+    /// The answer under the injected faults, `None` when the request goes unanswered.
+    async fn answer_faulty(&mut self, unit: u8, pdu: Vec<u8>) -> Option<Vec<u8>> {
+        let (action, latency) = match self.faults {
+            Some(ref faults) => {
+                let mut faults = faults.lock().unwrap();
+                (faults.action(&pdu), faults.latency())
+            },
+            None => (FaultAction::Answer, Duration::from_millis(0)),
+        };
+        if latency > Duration::from_millis(0) {
+            tokio::time::delay_for(latency).await;
+        }
+        match action {
+            FaultAction::Answer => Some(self.answer(unit, pdu).await),
+            FaultAction::Drop => {
+                debug!("modbus server {} dropped a request", self.id);
+                None
+            },
+            FaultAction::Exception(exception) => Some(encode_exception(&ExceptionResponse {
+                function: pdu.first().map_or(0, |fc| fc & 0x7F),
+                exception,
+            })),
+        }
+    }
+}
+
+async fn serve_tcp_connection<A, Fut>(id: u32, answer: A, mut stream: TcpStream, unit: Option<u8>)
+where
+    A: Fn(u8, Vec<u8>) -> Fut,
+    Fut: Future<Output = Vec<u8>>,
+{
+    loop {
+        let (header, pdu) = match read_mbap_frame(&mut stream).await {
+            Ok(frame) => frame,
+            Err(err) => {
+                if err.kind() != io::ErrorKind::UnexpectedEof {
+                    debug!("modbus server {} dropped a connection: {}", id, err);
+                }
+                return;
+            },
+        };
+        let answer = match unit {
+            Some(unit) if unit != header.unit => encode_exception(&ExceptionResponse {
+                function: pdu[0] & 0x7F,
+                exception: EXCEPTION_GATEWAY_PATH_UNAVAILABLE,
+            }),
+            _ => answer(header.unit, pdu).await,
+        };
+        if let Err(err) = stream.write_all(&mbap_frame(header, &answer)).await {
+            debug!("modbus server {} failed to answer: {}", id, err);
+            return;
+        }
+    }
+}
+
+/// Accepts modbus TCP connections, `answer` turning the unit and request PDU into the answer PDU.
+pub async fn serve_tcp<A, Fut>(id: u32, answer: A, mut listener: TcpListener, unit: Option<u8>) -> io::Result<()>
+where
+    A: Fn(u8, Vec<u8>) -> Fut + Clone,
+    Fut: Future<Output = Vec<u8>>,
+{
+    // connections live inside this future, so aborting the server closes them too
+    let mut connections = FuturesUnordered::new();
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, peer)) => {
+                    debug!("modbus server {} accepted {}", id, peer);
+                    connections.push(serve_tcp_connection(id, answer.clone(), stream, unit));
+                },
+                Err(err) => warn!("modbus server {} failed to accept: {}", id, err),
+            },
+            Some(_) = connections.next(), if !connections.is_empty() => {},
+        }
+    }
+}
+
+/// Reads the next request frame off the line, `None` for a frame that didn't check out.
+async fn read_request<P>(port: &mut P, framing: Framing, id: u32) -> io::Result<Option<(u8, Vec<u8>)>>
+where
+    P: AsyncRead + Unpin,
+{
+    let frame = match framing {
+        Framing::Rtu => {
+            let frame = read_rtu_frame(port, rtu_request_len).await?;
+            rtu_unframe(&frame).map(|(unit, pdu)| (unit, pdu.to_vec()))
+        },
+        Framing::Ascii => ascii_unframe(&read_ascii_line(port).await?),
+    };
+    match frame {
+        Ok(frame) => Ok(Some(frame)),
+        Err(err) => {
+            debug!("modbus server {} dropped a frame: {}", id, err);
+            Ok(None)
+        },
+    }
+}
+
+async fn serve_serial<P>(mut shared: ServerShared, mut port: P, framing: Framing, unit: u8) -> io::Result<()>
+where
+    P: AsyncRead + AsyncWrite + Unpin,
+{
+    loop {
+        let (request_unit, pdu) = match read_request(&mut port, framing, shared.id).await? {
+            Some(frame) => frame,
+            None => continue,
+        };
+        if request_unit != unit && request_unit != BROADCAST_UNIT {
+            continue;
+        }
+        let answer = match shared.answer_faulty(request_unit, pdu).await {
+            Some(answer) => answer,
+            None => continue,
+        };
+        if request_unit != BROADCAST_UNIT {
+            let frame = match framing {
+                Framing::Rtu => rtu_frame(request_unit, &answer),
+                Framing::Ascii => ascii_frame(request_unit, &answer),
+            };
+            port.write_all(&frame).await?;
+        }
+    }
+}
+
+/// Runs a server until `close()` aborts it, telling the event loop when it stopped on its own.
+pub async fn run_server<F>(serve: Abortable<F>, mut tx: Sender<RespType>, id: u32)
+where
+    F: Future<Output = io::Result<()>>,
+{
+    match serve.await {
+        // `close()` or the JS object going away, the service ends either way
+        Err(Aborted) => {},
+        Ok(Err(err)) => warn!("modbus server {} stopped: {}", id, err),
+        Ok(Ok(())) => {},
+    }
+    let _ = tx.send(RespType::ModbusServerStopped(id)).await;
+}
+
+pub async fn modbus_server_start(config: ServerConfig, has_handler: bool, mut tx: Sender<RespType>, job_id: u32) {
+    let tables = Arc::new(Mutex::new(config.tables));
+    let shared = ServerShared {
+        id: job_id,
+        tables: tables.clone(),
+        has_handler,
+        tx: tx.clone(),
+        faults: config.faults.map(|faults| Arc::new(Mutex::new(faults))),
+    };
+    let faults = shared.faults.clone();
+    let mut path = None;
+    let (abort, registration) = AbortHandle::new_pair();
+    let started = match config.transport {
+        ServerTransport::Tcp { host, port } => match TcpListener::bind((host.as_str(), port)).await {
+            Ok(listener) => {
+                let local_addr = listener.local_addr().ok();
+                let answer = move |unit, pdu| {
+                    let mut shared = shared.clone();
+                    async move { shared.answer(unit, pdu).await }
+                };
+                let serve = Abortable::new(serve_tcp(job_id, answer, listener, config.unit), registration);
+                tokio::spawn(run_server(serve, tx.clone(), job_id));
+                Ok(local_addr)
+            },
+            Err(err) => Err(format_err!("failed to listen on {}:{}: {}", host, port, err)),
+        },
+        ServerTransport::Rtu(serial) => match ServedPort::claim(&serial.path).and_then(|claim| {
+            Serial::from_path(&serial.path, &serial.settings)
+                .map(|port| (claim, port))
+                .map_err(|err| format_err!("failed to open {}: {}", serial.path, err))
+        }) {
+            Ok((claim, port)) => {
+                let serve = serve_serial(shared, port, serial.framing, serial.unit);
+                // the port stays claimed as long as it is served
+                let serve = async move {
+                    let _claim = claim;
+                    serve.await
+                };
+                let serve = Abortable::new(serve, registration);
+                tokio::spawn(run_server(serve, tx.clone(), job_id));
+                Ok(None)
+            },
+            Err(err) => Err(err),
+        },
+        ServerTransport::Pty(framing) => match PtyMaster::open().and_then(|(pty, slave)| Ok((PollEvented::new(pty)?, slave))) {
+            Ok((pty, slave)) => {
+                let unit = config.unit.unwrap_or(BROADCAST_UNIT);
+                let serve = Abortable::new(serve_serial(shared, pty, framing, unit), registration);
+                tokio::spawn(run_server(serve, tx.clone(), job_id));
+                path = Some(slave);
+                Ok(None)
+            },
+            Err(err) => Err(format_err!("failed to open a pseudo terminal: {}", err)),
+        },
+    };
+    let server = started.map(|local_addr| {
+        let mut server = ModbusServer::new(job_id, tables, abort, local_addr);
+        server.path = path;
+        server.faults = faults;
+        server
+    });
+    tx.send(RespType::ModbusServerStarted(job_id, server)).await.unwrap();
+}
+
+pub fn qruff_modbus_server_settle_promise<'a>(promise: RJSPromise<'a>, server: Result<ModbusServer, Error>) {
+    let result = match server {
+        Ok(server) => unsafe {
+            let obj = promise.ctxt.new_object_class(*QRUFF_MODBUS_SERVER_CLASS_ID);
+            if let Some(addr) = server.local_addr {
+                js_set_property(promise.ctxt, obj.raw(), "port", js_new_value(promise.ctxt, addr.port() as i32));
+            }
+            if let Some(path) = server.path() {
+                js_set_property(promise.ctxt, obj.raw(), "path", js_new_value(promise.ctxt, path.to_string()));
+            }
+            obj.set_opaque(Box::into_raw(Box::new(server)));
+            Ok(js_new_value(promise.ctxt, obj))
+        },
+        Err(err) => Err(unsafe { js_modbus_error(promise.ctxt, &ModbusError::from_error(err)) }),
+    };
+    settle_promise(&promise, result);
+}
+
+/// `{unit, functionCode, address, count, values}`, with `andMask`/`orMask` for a
+/// masked write, `writeAddress` for read/write and `data` for other function codes.
+unsafe fn js_server_request(ctxt: &ContextRef, server_request: &ServerRequest) -> ffi::JSValue {
+    let obj = ffi::JS_NewObject(ctxt.as_ptr());
+    let request = &server_request.request;
+    let set_u16 = |name: &str, value: u16| js_set_property(ctxt, obj, name, js_new_value(ctxt, value as i32));
+    let registers = |values: &[u16]| {
+        let values = values.iter().map(|value| js_new_value(ctxt, *value as i32)).collect();
+        js_new_array(ctxt, values)
+    };
+    set_u16("unit", server_request.unit as u16);
+    set_u16("functionCode", request.function_code() as u16);
+    match *request {
+        Request::ReadCoils(addr, cnt)
+        | Request::ReadDiscreteInputs(addr, cnt)
+        | Request::ReadHoldingRegisters(addr, cnt)
+        | Request::ReadInputRegisters(addr, cnt) => {
+            set_u16("address", addr);
+            set_u16("count", cnt);
+        },
+        Request::WriteSingleCoil(addr, value) => {
+            set_u16("address", addr);
+            set_u16("count", 1);
+            js_set_property(ctxt, obj, "values", js_bool_array(ctxt, vec![value]));
+        },
+        Request::WriteSingleRegister(addr, value) => {
+            set_u16("address", addr);
+            set_u16("count", 1);
+            js_set_property(ctxt, obj, "values", registers(&[value]));
+        },
+        Request::WriteMultipleCoils(addr, ref values) => {
+            set_u16("address", addr);
+            set_u16("count", values.len() as u16);
+            js_set_property(ctxt, obj, "values", js_bool_array(ctxt, values.clone()));
+        },
+        Request::WriteMultipleRegisters(addr, ref values) => {
+            set_u16("address", addr);
+            set_u16("count", values.len() as u16);
+            js_set_property(ctxt, obj, "values", registers(values));
+        },
+        Request::MaskWriteRegister(addr, and_mask, or_mask) => {
+            set_u16("address", addr);
+            set_u16("andMask", and_mask);
+            set_u16("orMask", or_mask);
+        },
+        Request::ReadWriteMultipleRegisters(read_addr, read_cnt, write_addr, ref values) => {
+            set_u16("address", read_addr);
+            set_u16("count", read_cnt);
+            set_u16("writeAddress", write_addr);
+            js_set_property(ctxt, obj, "values", registers(values));
+        },
+        Request::Custom(_, ref data) => {
+            let data = data.iter().map(|byte| js_new_value(ctxt, *byte as i32)).collect();
+            js_set_property(ctxt, obj, "data", js_new_array(ctxt, data));
+        },
+    }
+    obj
+}
+
+/// Turns what the handler returned into the response to `request`.
+///
+/// Reads need an array of exactly the requested length, writes are taken as
+/// done by any value but `undefined`, other function codes return the data bytes.
+unsafe fn handler_response(ctxt: &ContextRef, request: &Request, value: ffi::JSValue) -> Result<Option<Response>, Error> {
+    if js_is_undefined(value) {
+        return Ok(None);
+    }
+    let bits = |cnt: u16| -> Result<Vec<bool>, Error> {
+        let bits = js_to_vec(ctxt, value, "handler result", |item, _| Ok(js_to_bool(ctxt, item)))?;
+        if bits.len() != cnt as usize {
+            return Err(format_err!("handler returned {} values for {} requested", bits.len(), cnt));
+        }
+        Ok(bits)
+    };
+    let registers = |cnt: u16| -> Result<Vec<u16>, Error> {
+        let registers = js_to_vec(ctxt, value, "handler result", |item, name| js_to_integer(ctxt, item, name))?;
+        if registers.len() != cnt as usize {
+            return Err(format_err!("handler returned {} values for {} requested", registers.len(), cnt));
+        }
+        Ok(registers)
+    };
+    let response = match *request {
+        Request::ReadCoils(_, cnt) => Response::ReadCoils(bits(cnt)?),
+        Request::ReadDiscreteInputs(_, cnt) => Response::ReadDiscreteInputs(bits(cnt)?),
+        Request::ReadHoldingRegisters(_, cnt) => Response::ReadHoldingRegisters(registers(cnt)?),
+        Request::ReadInputRegisters(_, cnt) => Response::ReadInputRegisters(registers(cnt)?),
+        Request::ReadWriteMultipleRegisters(_, cnt, _, _) => Response::ReadWriteMultipleRegisters(registers(cnt)?),
+        Request::WriteSingleCoil(addr, value) => Response::WriteSingleCoil(addr, value),
+        Request::WriteSingleRegister(addr, value) => Response::WriteSingleRegister(addr, value),
+        Request::WriteMultipleCoils(addr, ref values) => Response::WriteMultipleCoils(addr, values.len() as u16),
+        Request::WriteMultipleRegisters(addr, ref values) => Response::WriteMultipleRegisters(addr, values.len() as u16),
+        Request::MaskWriteRegister(addr, and_mask, or_mask) => Response::MaskWriteRegister(addr, and_mask, or_mask),
+        Request::Custom(fc, _) => Response::Custom(
+            fc,
+            js_to_vec(ctxt, value, "handler result", |item, name| js_to_integer(ctxt, item, name))?,
+        ),
+    };
+    Ok(Some(response))
+}
+
+/// Exception code for an error thrown by the handler, from its `exceptionCode` or `code`.
+unsafe fn handler_exception(ctxt: &ContextRef, err: ffi::JSValue) -> u8 {
+    let exception = js_get_option(ctxt, err, "exceptionCode", |v, name| js_to_integer::<u8>(ctxt, v, name));
+    let code = js_get_option(ctxt, err, "code", |v, name| js_to_string(ctxt, v, name));
+    match (exception, code) {
+        (Ok(Some(exception)), _) => exception,
+        (_, Ok(Some(code))) => ModbusErrorCode::from_name(&code)
+            .and_then(ModbusErrorCode::exception_code)
+            .unwrap_or(EXCEPTION_SLAVE_DEVICE_FAILURE),
+        _ => EXCEPTION_SLAVE_DEVICE_FAILURE,
+    }
+}
+
+/// Calls the JS handler of a server for one request, on the JS thread.
+pub fn qruff_modbus_server_call_handler(handler: &RJSCallback, request: &ServerRequest) -> ServerReply {
+    let ctxt = handler.ctxt;
+    unsafe {
+        let args = [js_server_request(ctxt, request)];
+        let ret = ffi::JS_Call(ctxt.as_ptr(), handler.callback.raw(), ffi::UNDEFINED, 1, args.as_ptr() as *mut _);
+        ctxt.free_value(args[0]);
+        if Value::from(ret).is_exception() {
+            let err = ffi::JS_GetException(ctxt.as_ptr());
+            let exception = handler_exception(ctxt, err);
+            ctxt.free_value(err);
+            return Err(exception);
+        }
+        let response = handler_response(ctxt, &request.request, ret);
+        ctxt.free_value(ret);
+        response.map_err(|err| {
+            warn!("modbus server handler: {}", err);
+            EXCEPTION_SLAVE_DEVICE_FAILURE
+        })
+    }
+}
+
+pub unsafe extern "C" fn qruff_modbus_server(
+    ctx: *mut ffi::JSContext,
+    _this_val: ffi::JSValue,
+    argc: ::std::os::raw::c_int,
+    argv: *mut ffi::JSValue,
+) -> ffi::JSValue {
+    let ctxt = ContextRef::from_ptr(ctx);
+    let args = slice::from_raw_parts(argv, argc as usize);
+    let opts = js_arg(args, 0);
+
+    let config = ServerConfig::from_js(ctxt, opts);
+    let config = match config {
+        Ok(config) => config,
+        Err(err) => return js_throw_type_error(ctxt, &err.to_string()),
+    };
+    let handler = js_get_property(ctxt, opts, "handler");
+    if !js_is_undefined(handler) && !ctxt.is_function(&Value::from(handler)) {
+        ctxt.free_value(handler);
+        return js_throw_type_error(ctxt, "handler must be a function");
+    }
+    let mut ruff_ctx = ctxt.userdata::<RuffCtx>().unwrap();
+
+    let rfunc: [ffi::JSValue; 2] = [ffi::UNDEFINED; 2];
+    let id = ruff_ctx.as_mut().id_generator.next_id();
+    let promise = ffi::JS_NewPromiseCapability(ctxt.as_ptr(), rfunc.as_ptr() as *mut _);
+    let handle = RJSPromise::new(
+        id,
+        ctxt,
+        &Value::from(promise),
+        &Value::from(rfunc[0]),
+        &Value::from(rfunc[1]),
+    );
+    let callback = if js_is_undefined(handler) {
+        None
+    } else {
+        Some(RJSCallback::new(id, ctxt, &Value::from(handler)))
+    };
+    ctxt.free_value(handler);
+
+    let mut request_msg = ruff_ctx.as_mut().request_msg.lock().unwrap();
+    request_msg.push(MsgType::CreateModbusServer(id, config, callback, handle));
+    promise
+}
+
+unsafe fn this_server<'a>(this_val: ffi::JSValue) -> Option<&'a mut ModbusServer> {
+    Value::from(this_val)
+        .get_opaque::<ModbusServer>(*QRUFF_MODBUS_SERVER_CLASS_ID)
+        .as_mut()
+}
+
+/// `server.close()`, stops listening and drops every connection.
+pub unsafe extern "C" fn qruff_modbus_server_close(
+    ctx: *mut ffi::JSContext,
+    this_val: ffi::JSValue,
+    _argc: ::std::os::raw::c_int,
+    _argv: *mut ffi::JSValue,
+) -> ffi::JSValue {
+    let ctxt = ContextRef::from_ptr(ctx);
+    let server = match this_server(this_val) {
+        Some(server) => server,
+        None => return ffi::EXCEPTION,
+    };
+    server.abort.abort();
+
+    let mut ruff_ctx = ctxt.userdata::<RuffCtx>().unwrap();
+    let mut request_msg = ruff_ctx.as_mut().request_msg.lock().unwrap();
+    request_msg.push(MsgType::CloseModbusServer(server.id));
+    ffi::UNDEFINED
+}
+
+/// `server.get(table, address, count)`, reads the backing table.
+pub unsafe extern "C" fn qruff_modbus_server_get(
+    ctx: *mut ffi::JSContext,
+    this_val: ffi::JSValue,
+    argc: ::std::os::raw::c_int,
+    argv: *mut ffi::JSValue,
+) -> ffi::JSValue {
+    let ctxt = ContextRef::from_ptr(ctx);
+    let args = slice::from_raw_parts(argv, argc as usize);
+    let server = match this_server(this_val) {
+        Some(server) => server,
+        None => return ffi::EXCEPTION,
+    };
+    let parsed = (|| -> Result<(Table, u16, usize), Error> {
+        Ok((
+            js_to_string(ctxt, js_arg(args, 0), "table")?.parse()?,
+            js_to_integer(ctxt, js_arg(args, 1), "address")?,
+            js_to_integer(ctxt, js_arg(args, 2), "count")?,
+        ))
+    })();
+    let (table, addr, cnt) = match parsed {
+        Ok(parsed) => parsed,
+        Err(err) => return js_throw_type_error(ctxt, &err.to_string()),
+    };
+
+    let mut tables = server.tables.lock().unwrap();
+    match tables.table_mut(table) {
+        TableMut::Bits(bits) => match table_range(bits.len(), addr, cnt) {
+            Ok(range) => js_bool_array(ctxt, bits[range].to_vec()),
+            Err(_) => js_throw_type_error(ctxt, &format!("{:?} has only {} entries", table, bits.len())),
+        },
+        TableMut::Registers(registers) => match table_range(registers.len(), addr, cnt) {
+            Ok(range) => {
+                let values = registers[range].iter().map(|value| js_new_value(ctxt, *value as i32)).collect();
+                js_new_array(ctxt, values)
+            },
+            Err(_) => js_throw_type_error(ctxt, &format!("{:?} has only {} entries", table, registers.len())),
+        },
+    }
+}
+
+/// `server.set(table, address, values)`, updates the backing table.
+pub unsafe extern "C" fn qruff_modbus_server_set(
+    ctx: *mut ffi::JSContext,
+    this_val: ffi::JSValue,
+    argc: ::std::os::raw::c_int,
+    argv: *mut ffi::JSValue,
+) -> ffi::JSValue {
+    let ctxt = ContextRef::from_ptr(ctx);
+    let args = slice::from_raw_parts(argv, argc as usize);
+    let server = match this_server(this_val) {
+        Some(server) => server,
+        None => return ffi::EXCEPTION,
+    };
+    let parsed = (|| -> Result<(Table, u16), Error> {
+        Ok((
+            js_to_string(ctxt, js_arg(args, 0), "table")?.parse()?,
+            js_to_integer(ctxt, js_arg(args, 1), "address")?,
+        ))
+    })();
+    let (table, addr) = match parsed {
+        Ok(parsed) => parsed,
+        Err(err) => return js_throw_type_error(ctxt, &err.to_string()),
+    };
+
+    let mut tables = server.tables.lock().unwrap();
+    let written = match tables.table_mut(table) {
+        TableMut::Bits(bits) => js_to_vec(ctxt, js_arg(args, 2), "values", |item, _| Ok(js_to_bool(ctxt, item)))
+            .and_then(|values| {
+                let range = table_range(bits.len(), addr, values.len())
+                    .map_err(|_| format_err!("{:?} has only {} entries", table, bits.len()))?;
+                bits[range].copy_from_slice(&values);
+                Ok(())
+            }),
+        TableMut::Registers(registers) => js_to_vec(ctxt, js_arg(args, 2), "values", |item, name| js_to_integer(ctxt, item, name))
+            .and_then(|values| {
+                let range = table_range(registers.len(), addr, values.len())
+                    .map_err(|_| format_err!("{:?} has only {} entries", table, registers.len()))?;
+                registers[range].copy_from_slice(&values);
+                Ok(())
+            }),
+    };
+    match written {
+        Ok(()) => ffi::UNDEFINED,
+        Err(err) => js_throw_type_error(ctxt, &err.to_string()),
+    }
+}
+
+pub fn qruff_modbus_server_class_id() -> ClassId {
+    *QRUFF_MODBUS_SERVER_CLASS_ID
+}
+
+lazy_static! {
+    static ref QRUFF_MODBUS_SERVER_CLASS_ID: ClassId = Runtime::new_class_id();
+}
+
+pub fn register_modbus_server_class(rt: &RuntimeRef) -> bool {
+    unsafe extern "C" fn qruff_modbus_server_finalizer(_rt: *mut ffi::JSRuntime, obj: ffi::JSValue) {
+        let ptr = ffi::JS_GetOpaque(obj, *QRUFF_MODBUS_SERVER_CLASS_ID) as *mut ModbusServer;
+
+        trace!("free userdata {:p} @ {:?}", ptr, obj.u.ptr);
+
+        mem::drop(Box::from_raw(ptr));
+    }
+
+    rt.new_class(
+        *QRUFF_MODBUS_SERVER_CLASS_ID,
+        &ffi::JSClassDef {
+            class_name: cstr!(QRuffModbusServer).as_ptr(),
+            finalizer: Some(qruff_modbus_server_finalizer),
+            gc_mark: None,
+            call: None,
+            exotic: core::ptr::null_mut(),
+        },
+    )
+}
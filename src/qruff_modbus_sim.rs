@@ -0,0 +1,409 @@
+//! Virtual devices for tests: `modbusSimulator(map)` or `qruff modbus sim --map FILE`.
+//!
+//! A modbus slave answers on the master end of a fresh pseudo terminal from
+//! a register map given as JSON, and the path of the other end is handed out
+//! to be opened with `rtu_setup` like any serial port. Latency, exceptions
+//! and dropped frames can be injected to see how a poller copes with them.
+//!
+//! ```json
+//! {
+//!     "unit": 1,
+//!     "framing": "rtu",
+//!     "holdingRegisters": [1, 2, 3],
+//!     "coils": 16,
+//!     "faults": {"latencyMs": 20, "dropRate": 0.1, "exceptions": [{"functionCode": 3, "address": 2, "exceptionCode": 4}]}
+//! }
+//! ```
+
+use std::ffi::CStr;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::raw::c_char;
+use std::os::unix::io::RawFd;
+use std::slice;
+use std::time::Duration;
+
+use failure::{format_err, Error};
+use mio::unix::EventedFd;
+use mio::{Evented, Poll, PollOpt, Ready, Token};
+use serde::Deserialize;
+use tokio::sync::mpsc::channel;
+
+use crate::{
+    decode_request, modbus_server_start, qruff_modbus_server_class_id, Framing, ModbusServer, RegisterTables, Request,
+    RespType, ServerConfig, MAX_TABLE_SIZE,
+    ffi, ContextRef, MsgType, RuffCtx, Value, RJSPromise, XorShift,
+    js_arg, js_get_option, js_throw_type_error, js_to_f64, js_to_integer, js_to_string, js_to_vec,
+};
+
+const DEFAULT_SIM_UNIT: u8 = 1;
+
+/// A table of the map, either its size or its initial content.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum TableMap<T> {
+    Size(usize),
+    Values(Vec<T>),
+}
+
+impl<T: Clone + Default> TableMap<T> {
+    fn into_table(self, name: &str) -> Result<Vec<T>, Error> {
+        let table = match self {
+            TableMap::Size(size) if size <= MAX_TABLE_SIZE => vec![T::default(); size],
+            TableMap::Values(values) if values.len() <= MAX_TABLE_SIZE => values,
+            _ => return Err(format_err!("{} can hold at most {} entries", name, MAX_TABLE_SIZE)),
+        };
+        Ok(table)
+    }
+}
+
+fn table<T: Clone + Default>(table: Option<TableMap<T>>, name: &str) -> Result<Vec<T>, Error> {
+    table.map_or(Ok(vec![]), |table| table.into_table(name))
+}
+
+/// Register map of a simulated device.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct SimulatorMap {
+    unit: Option<u8>,
+    framing: Option<String>,
+    coils: Option<TableMap<bool>>,
+    discrete_inputs: Option<TableMap<bool>>,
+    holding_registers: Option<TableMap<u16>>,
+    input_registers: Option<TableMap<u16>>,
+    #[serde(default)]
+    faults: Faults,
+}
+
+/// An exception answered instead of the real response.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct InjectedException {
+    /// only for this function code, any when unset
+    function_code: Option<u8>,
+    /// only for requests touching this address, any when unset
+    address: Option<u16>,
+    exception_code: u8,
+}
+
+impl InjectedException {
+    fn matches(&self, function: u8, request: Option<&Request>) -> bool {
+        if self.function_code.map_or(false, |fc| fc != function) {
+            return false;
+        }
+        match (self.address, request) {
+            (None, _) => true,
+            (Some(address), Some(request)) => touches(request, address),
+            (Some(_), None) => false,
+        }
+    }
+}
+
+/// Whether `request` reads or writes `address`.
+fn touches(request: &Request, address: u16) -> bool {
+    let covers = |start: u16, count: usize| (start as usize..start as usize + count).contains(&(address as usize));
+    match *request {
+        Request::ReadCoils(addr, cnt)
+        | Request::ReadDiscreteInputs(addr, cnt)
+        | Request::ReadHoldingRegisters(addr, cnt)
+        | Request::ReadInputRegisters(addr, cnt) => covers(addr, cnt as usize),
+        Request::WriteSingleCoil(addr, _) | Request::WriteSingleRegister(addr, _) | Request::MaskWriteRegister(addr, _, _) => {
+            covers(addr, 1)
+        },
+        Request::WriteMultipleCoils(addr, ref values) => covers(addr, values.len()),
+        Request::WriteMultipleRegisters(addr, ref values) => covers(addr, values.len()),
+        Request::ReadWriteMultipleRegisters(read_addr, read_cnt, write_addr, ref values) => {
+            covers(read_addr, read_cnt as usize) || covers(write_addr, values.len())
+        },
+        Request::Custom(..) => false,
+    }
+}
+
+/// Misbehaviour injected into a server, `server.inject(faults)` replaces it at runtime.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, rename_all = "camelCase", deny_unknown_fields)]
+pub struct Faults {
+    /// delay before every answer
+    latency_ms: u64,
+    /// share of the requests left unanswered, from 0 to 1
+    drop_rate: f64,
+    /// requests still to leave unanswered before answering again
+    drop_next: u32,
+    exceptions: Vec<InjectedException>,
+    #[serde(skip)]
+    random: XorShift,
+}
+
+/// What a server does with a request under its faults.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FaultAction {
+    Answer,
+    Drop,
+    Exception(u8),
+}
+
+impl Faults {
+    /// `{latencyMs, dropRate, dropNext, exceptions: [{functionCode, address, exceptionCode}]}`
+    pub unsafe fn from_js(ctxt: &ContextRef, opts: ffi::JSValue) -> Result<Faults, Error> {
+        let exceptions = js_get_option(ctxt, opts, "exceptions", |v, name| {
+            js_to_vec(ctxt, v, name, |rule, _| {
+                Ok(InjectedException {
+                    function_code: js_get_option(ctxt, rule, "functionCode", |v, name| js_to_integer(ctxt, v, name))?,
+                    address: js_get_option(ctxt, rule, "address", |v, name| js_to_integer(ctxt, v, name))?,
+                    exception_code: js_get_option(ctxt, rule, "exceptionCode", |v, name| js_to_integer(ctxt, v, name))?
+                        .ok_or_else(|| format_err!("injected exceptions need an exceptionCode"))?,
+                })
+            })
+        })?;
+        let faults = Faults {
+            latency_ms: js_get_option(ctxt, opts, "latencyMs", |v, name| js_to_integer(ctxt, v, name))?.unwrap_or(0),
+            drop_rate: js_get_option(ctxt, opts, "dropRate", |v, name| js_to_f64(ctxt, v, name))?.unwrap_or(0.0),
+            drop_next: js_get_option(ctxt, opts, "dropNext", |v, name| js_to_integer(ctxt, v, name))?.unwrap_or(0),
+            exceptions: exceptions.unwrap_or_default(),
+            random: XorShift::new(),
+        };
+        faults.validate()?;
+        Ok(faults)
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        if !(0.0..=1.0).contains(&self.drop_rate) {
+            return Err(format_err!("dropRate must be between 0 and 1, got {}", self.drop_rate));
+        }
+        if self.exceptions.iter().any(|rule| rule.exception_code == 0) {
+            return Err(format_err!("exceptionCode must be greater than 0"));
+        }
+        Ok(())
+    }
+
+    pub fn latency(&self) -> Duration {
+        Duration::from_millis(self.latency_ms)
+    }
+
+    /// Decides the fate of the request `pdu`.
+    pub fn action(&mut self, pdu: &[u8]) -> FaultAction {
+        if self.drop_next > 0 {
+            self.drop_next -= 1;
+            return FaultAction::Drop;
+        }
+        if self.drop_rate > 0.0 && self.random.next_f64() < self.drop_rate {
+            return FaultAction::Drop;
+        }
+        let function = pdu.first().map_or(0, |fc| fc & 0x7F);
+        let request = decode_request(pdu).ok();
+        self.exceptions
+            .iter()
+            .find(|rule| rule.matches(function, request.as_ref()))
+            .map_or(FaultAction::Answer, |rule| FaultAction::Exception(rule.exception_code))
+    }
+}
+
+/// Master end of a pseudo terminal.
+///
+/// The slave end stays open with it, without that the master reads fail with
+/// a hang up whenever no client has the path open.
+#[derive(Debug)]
+pub struct PtyMaster {
+    fd: RawFd,
+    slave: RawFd,
+}
+
+fn check(ret: libc::c_int) -> io::Result<libc::c_int> {
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+impl PtyMaster {
+    /// Opens a new pseudo terminal in raw mode, returning its master end and the path of the slave end.
+    pub fn open() -> io::Result<(PtyMaster, String)> {
+        unsafe {
+            let fd = check(libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY))?;
+            // from here on the fds are closed on drop whatever fails next
+            let mut pty = PtyMaster { fd, slave: -1 };
+            check(libc::grantpt(fd))?;
+            check(libc::unlockpt(fd))?;
+            let mut name = [0 as c_char; 128];
+            let err = libc::ptsname_r(fd, name.as_mut_ptr(), name.len());
+            if err != 0 {
+                return Err(io::Error::from_raw_os_error(err));
+            }
+            let path = CStr::from_ptr(name.as_ptr()).to_string_lossy().into_owned();
+            pty.slave = check(libc::open(name.as_ptr(), libc::O_RDWR | libc::O_NOCTTY))?;
+            // no echo nor line editing, frames are binary
+            let mut termios = std::mem::zeroed::<libc::termios>();
+            check(libc::tcgetattr(pty.slave, &mut termios))?;
+            libc::cfmakeraw(&mut termios);
+            check(libc::tcsetattr(pty.slave, libc::TCSANOW, &termios))?;
+            let flags = check(libc::fcntl(fd, libc::F_GETFL))?;
+            check(libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK))?;
+            Ok((pty, path))
+        }
+    }
+}
+
+impl Drop for PtyMaster {
+    fn drop(&mut self) {
+        unsafe {
+            if self.slave >= 0 {
+                libc::close(self.slave);
+            }
+            libc::close(self.fd);
+        }
+    }
+}
+
+impl Read for PtyMaster {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = unsafe { libc::read(self.fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+        if read < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(read as usize)
+        }
+    }
+}
+
+impl Write for PtyMaster {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = unsafe { libc::write(self.fd, buf.as_ptr() as *const libc::c_void, buf.len()) };
+        if written < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(written as usize)
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Evented for PtyMaster {
+    fn register(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt) -> io::Result<()> {
+        EventedFd(&self.fd).register(poll, token, interest, opts)
+    }
+
+    fn reregister(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt) -> io::Result<()> {
+        EventedFd(&self.fd).reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        EventedFd(&self.fd).deregister(poll)
+    }
+}
+
+/// Parses the JSON register map into the config of a server on a new pseudo terminal.
+pub fn simulator_config(map: &str) -> Result<ServerConfig, Error> {
+    let map: SimulatorMap = serde_json::from_str(map).map_err(|err| format_err!("invalid register map: {}", err))?;
+    map.faults.validate()?;
+    let unit = map.unit.unwrap_or(DEFAULT_SIM_UNIT);
+    if unit == 0 || unit > 247 {
+        return Err(format_err!("unit must be between 1 and 247, got {}", unit));
+    }
+    let framing = map.framing.map_or(Ok(Framing::Rtu), |framing| framing.parse())?;
+    let tables = RegisterTables {
+        coils: table(map.coils, "coils")?,
+        discrete_inputs: table(map.discrete_inputs, "discreteInputs")?,
+        holding_registers: table(map.holding_registers, "holdingRegisters")?,
+        input_registers: table(map.input_registers, "inputRegisters")?,
+    };
+    Ok(ServerConfig::simulator(framing, unit, tables, map.faults))
+}
+
+/// Runs the simulator of the map in `path` until interrupted, printing the path to open.
+pub fn run_simulator_cli(path: &str, link: Option<&str>) -> Result<(), Error> {
+    let config = fs::read_to_string(path)
+        .map_err(|err| format_err!("failed to read {}: {}", path, err))
+        .and_then(|map| simulator_config(&map))?;
+    let mut event_rt = tokio::runtime::Builder::new()
+        .basic_scheduler()
+        .enable_all()
+        .build()?;
+    event_rt.block_on(async {
+        let (tx, mut rx) = channel(16);
+        modbus_server_start(config, false, tx, 0).await;
+        let server: ModbusServer = match rx.recv().await {
+            Some(RespType::ModbusServerStarted(_, server)) => server?,
+            _ => return Err(format_err!("the simulator didn't start")),
+        };
+        let pty = server.path().unwrap_or_default().to_string();
+        if let Some(link) = link {
+            let _ = fs::remove_file(link);
+            std::os::unix::fs::symlink(&pty, link).map_err(|err| format_err!("failed to link {}: {}", link, err))?;
+        }
+        println!("{}", link.unwrap_or(&pty));
+        io::stdout().flush()?;
+        tokio::select! {
+            _ = rx.recv() => warn!("the simulator stopped"),
+            _ = tokio::signal::ctrl_c() => {},
+        }
+        if let Some(link) = link {
+            let _ = fs::remove_file(link);
+        }
+        Ok::<(), Error>(())
+    })
+}
+
+/// `modbusSimulator(map)`, `map` being the JSON text of the register map.
+///
+/// Resolves to a server object whose `path` is the serial port to open.
+pub unsafe extern "C" fn qruff_modbus_simulator(
+    ctx: *mut ffi::JSContext,
+    _this_val: ffi::JSValue,
+    argc: ::std::os::raw::c_int,
+    argv: *mut ffi::JSValue,
+) -> ffi::JSValue {
+    let ctxt = ContextRef::from_ptr(ctx);
+    let args = slice::from_raw_parts(argv, argc as usize);
+
+    let config = js_to_string(ctxt, js_arg(args, 0), "map").and_then(|map| simulator_config(&map));
+    let config = match config {
+        Ok(config) => config,
+        Err(err) => return js_throw_type_error(ctxt, &err.to_string()),
+    };
+    let mut ruff_ctx = ctxt.userdata::<RuffCtx>().unwrap();
+
+    let rfunc: [ffi::JSValue; 2] = [ffi::UNDEFINED; 2];
+    let id = ruff_ctx.as_mut().id_generator.next_id();
+    let promise = ffi::JS_NewPromiseCapability(ctxt.as_ptr(), rfunc.as_ptr() as *mut _);
+    let handle = RJSPromise::new(
+        id,
+        ctxt,
+        &Value::from(promise),
+        &Value::from(rfunc[0]),
+        &Value::from(rfunc[1]),
+    );
+
+    let mut request_msg = ruff_ctx.as_mut().request_msg.lock().unwrap();
+    request_msg.push(MsgType::CreateModbusServer(id, config, None, handle));
+    promise
+}
+
+/// `server.inject(faults)`, replaces the faults of a server, `inject({})` brings it back to normal.
+pub unsafe extern "C" fn qruff_modbus_server_inject(
+    ctx: *mut ffi::JSContext,
+    this_val: ffi::JSValue,
+    argc: ::std::os::raw::c_int,
+    argv: *mut ffi::JSValue,
+) -> ffi::JSValue {
+    let ctxt = ContextRef::from_ptr(ctx);
+    let args = slice::from_raw_parts(argv, argc as usize);
+    let server = match Value::from(this_val)
+        .get_opaque::<ModbusServer>(qruff_modbus_server_class_id())
+        .as_ref()
+    {
+        Some(server) => server,
+        None => return ffi::EXCEPTION,
+    };
+    let faults = match Faults::from_js(ctxt, js_arg(args, 0)) {
+        Ok(faults) => faults,
+        Err(err) => return js_throw_type_error(ctxt, &err.to_string()),
+    };
+    match server.inject(faults) {
+        Ok(()) => ffi::UNDEFINED,
+        Err(err) => js_throw_type_error(ctxt, &err.to_string()),
+    }
+}
@@ -0,0 +1,154 @@
+//! Per context counters: `ctx.stats()` and `ctx.reset_stats()`.
+//!
+//! Every try of an operation counts as a request, retries included, so a
+//! link going bad shows up as timeouts, CRC errors and retries climbing
+//! long before operations start to fail.
+
+use std::collections::BTreeMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::{ffi, js_new_value, js_set_property, qruff_rtu_context_class_id, ContextRef, RtuContext, Value};
+
+#[derive(Debug, Clone)]
+pub struct ModbusStats {
+    /// when counting started, at connection or the last reset
+    since: SystemTime,
+    requests: u64,
+    responses: u64,
+    /// exception answers by exception code
+    exceptions: BTreeMap<u8, u64>,
+    timeouts: u64,
+    crc_errors: u64,
+    retries: u64,
+    /// round trip of the requests answered, with a response or an exception
+    latency_min: Option<Duration>,
+    latency_max: Option<Duration>,
+    latency_total: Duration,
+    answered: u32,
+}
+
+impl Default for ModbusStats {
+    fn default() -> Self {
+        ModbusStats {
+            since: SystemTime::now(),
+            requests: 0,
+            responses: 0,
+            exceptions: BTreeMap::new(),
+            timeouts: 0,
+            crc_errors: 0,
+            retries: 0,
+            latency_min: None,
+            latency_max: None,
+            latency_total: Duration::from_millis(0),
+            answered: 0,
+        }
+    }
+}
+
+impl ModbusStats {
+    fn answered(&mut self, latency: Duration) {
+        self.requests += 1;
+        self.answered += 1;
+        self.latency_total += latency;
+        self.latency_min = Some(self.latency_min.map_or(latency, |min| min.min(latency)));
+        self.latency_max = Some(self.latency_max.map_or(latency, |max| max.max(latency)));
+    }
+
+    pub fn record_response(&mut self, latency: Duration) {
+        self.responses += 1;
+        self.answered(latency);
+    }
+
+    pub fn record_exception(&mut self, exception: u8, latency: Duration) {
+        *self.exceptions.entry(exception).or_insert(0) += 1;
+        self.answered(latency);
+    }
+
+    /// A request left without a usable answer.
+    pub fn record_failure(&mut self, timeout: bool, crc_error: bool) {
+        self.requests += 1;
+        if timeout {
+            self.timeouts += 1;
+        }
+        if crc_error {
+            self.crc_errors += 1;
+        }
+    }
+
+    pub fn record_retry(&mut self) {
+        self.retries += 1;
+    }
+
+    fn latency_avg(&self) -> Option<Duration> {
+        if self.answered == 0 {
+            None
+        } else {
+            Some(self.latency_total / self.answered)
+        }
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+/// `{since, requests, responses, exceptions, timeouts, crcErrors, retries, latencyMs: {min, avg, max}}`,
+/// `exceptions` counting the answers by exception code.
+unsafe fn js_stats(ctxt: &ContextRef, stats: &ModbusStats) -> ffi::JSValue {
+    let obj = ffi::JS_NewObject(ctxt.as_ptr());
+    let since = stats.since.duration_since(UNIX_EPOCH).unwrap_or_default();
+    js_set_property(ctxt, obj, "since", js_new_value(ctxt, millis(since)));
+    js_set_property(ctxt, obj, "requests", js_new_value(ctxt, stats.requests as f64));
+    js_set_property(ctxt, obj, "responses", js_new_value(ctxt, stats.responses as f64));
+    let exceptions = ffi::JS_NewObject(ctxt.as_ptr());
+    for (exception, count) in &stats.exceptions {
+        js_set_property(ctxt, exceptions, &exception.to_string(), js_new_value(ctxt, *count as f64));
+    }
+    js_set_property(ctxt, obj, "exceptions", exceptions);
+    js_set_property(ctxt, obj, "timeouts", js_new_value(ctxt, stats.timeouts as f64));
+    js_set_property(ctxt, obj, "crcErrors", js_new_value(ctxt, stats.crc_errors as f64));
+    js_set_property(ctxt, obj, "retries", js_new_value(ctxt, stats.retries as f64));
+    let latency = ffi::JS_NewObject(ctxt.as_ptr());
+    // left out until something answered
+    for (name, value) in &[("min", stats.latency_min), ("avg", stats.latency_avg()), ("max", stats.latency_max)] {
+        if let Some(value) = value {
+            js_set_property(ctxt, latency, name, js_new_value(ctxt, millis(*value)));
+        }
+    }
+    js_set_property(ctxt, obj, "latencyMs", latency);
+    obj
+}
+
+unsafe fn this_context<'a>(this_val: ffi::JSValue) -> Option<&'a RtuContext> {
+    Value::from(this_val)
+        .get_opaque::<RtuContext>(qruff_rtu_context_class_id())
+        .as_ref()
+}
+
+/// `stats()`, the counters of the context since it was connected or last reset.
+pub unsafe extern "C" fn qruff_rtu_stats(
+    ctx: *mut ffi::JSContext,
+    this_val: ffi::JSValue,
+    _argc: ::std::os::raw::c_int,
+    _argv: *mut ffi::JSValue,
+) -> ffi::JSValue {
+    let ctxt = ContextRef::from_ptr(ctx);
+    match this_context(this_val) {
+        Some(context) => js_stats(ctxt, &context.stats()),
+        None => ffi::EXCEPTION,
+    }
+}
+
+/// `reset_stats()`, starts counting from zero, returning the counters dropped.
+pub unsafe extern "C" fn qruff_rtu_reset_stats(
+    ctx: *mut ffi::JSContext,
+    this_val: ffi::JSValue,
+    _argc: ::std::os::raw::c_int,
+    _argv: *mut ffi::JSValue,
+) -> ffi::JSValue {
+    let ctxt = ContextRef::from_ptr(ctx);
+    match this_context(this_val) {
+        Some(context) => js_stats(ctxt, &context.reset_stats()),
+        None => ffi::EXCEPTION,
+    }
+}
@@ -0,0 +1,256 @@
+//! Raw frame tracing: `ctx.trace(target)` on any RTU or TCP context.
+//!
+//! `target` is `'log'`, a function called with every frame, `{pcap: path}` or
+//! `null` to stop tracing. Serial lines and tunneled sockets record the bytes
+//! exactly as they went over the wire, with the CRC or LRC check of each
+//! response. Modbus TCP contexts record unit and PDU of requests and answers,
+//! the MBAP header being tokio-modbus' business.
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::slice;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use failure::{format_err, Error};
+use tokio::sync::mpsc::Sender;
+
+use crate::{
+    ffi, hex, ContextRef, MsgType, RJSCallback, RespType, RtuContext, RuffCtx, Value, qruff_rtu_context_class_id,
+    js_arg, js_get_option, js_new_value, js_set_property, js_throw_type_error, js_to_bool, js_to_string,
+};
+
+const PCAP_MAGIC: u32 = 0xa1b2_c3d4;
+const PCAP_SNAPLEN: u32 = 65535;
+/// LINKTYPE_USER0, records are a direction byte (0 request, 1 response) followed by the frame.
+const PCAP_LINKTYPE: u32 = 147;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TraceDirection {
+    /// request sent to the device
+    Tx,
+    /// answer read back
+    Rx,
+}
+
+impl TraceDirection {
+    fn as_str(self) -> &'static str {
+        match self {
+            TraceDirection::Tx => "tx",
+            TraceDirection::Rx => "rx",
+        }
+    }
+}
+
+/// One frame seen on a traced context.
+#[derive(Debug)]
+pub struct TraceFrame {
+    pub time: SystemTime,
+    pub direction: TraceDirection,
+    pub unit: u8,
+    /// function code, with the 0x80 flag for exception answers
+    pub function: u8,
+    pub bytes: Vec<u8>,
+    /// time from the end of the request to the answer
+    pub latency: Option<Duration>,
+    /// outcome of the CRC or LRC check, `None` for frames without one
+    pub crc_ok: Option<bool>,
+}
+
+impl TraceFrame {
+    pub fn request(unit: u8, function: u8, bytes: Vec<u8>) -> TraceFrame {
+        TraceFrame {
+            time: SystemTime::now(),
+            direction: TraceDirection::Tx,
+            unit,
+            function,
+            bytes,
+            latency: None,
+            crc_ok: None,
+        }
+    }
+
+    pub fn response(unit: u8, function: u8, bytes: Vec<u8>, latency: Duration, crc_ok: Option<bool>) -> TraceFrame {
+        TraceFrame {
+            time: SystemTime::now(),
+            direction: TraceDirection::Rx,
+            unit,
+            function,
+            bytes,
+            latency: Some(latency),
+            crc_ok,
+        }
+    }
+
+    fn since_epoch(&self) -> Duration {
+        self.time.duration_since(UNIX_EPOCH).unwrap_or_default()
+    }
+}
+
+impl fmt::Display for TraceFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} unit {} fc {:#04x}", self.direction.as_str(), self.unit, self.function)?;
+        if let Some(latency) = self.latency {
+            write!(f, " after {} us", latency.as_micros())?;
+        }
+        match self.crc_ok {
+            Some(true) => write!(f, " crc ok")?,
+            Some(false) => write!(f, " crc BAD")?,
+            None => {},
+        }
+        write!(f, ": {}", hex(&self.bytes))
+    }
+}
+
+/// Appends frames to a libpcap capture file.
+#[derive(Debug)]
+pub struct PcapWriter {
+    file: BufWriter<File>,
+}
+
+impl PcapWriter {
+    pub fn create(path: &str) -> io::Result<PcapWriter> {
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(&PCAP_MAGIC.to_le_bytes())?;
+        // version 2.4, UTC, no timestamp accuracy
+        file.write_all(&2u16.to_le_bytes())?;
+        file.write_all(&4u16.to_le_bytes())?;
+        file.write_all(&0i32.to_le_bytes())?;
+        file.write_all(&0u32.to_le_bytes())?;
+        file.write_all(&PCAP_SNAPLEN.to_le_bytes())?;
+        file.write_all(&PCAP_LINKTYPE.to_le_bytes())?;
+        file.flush()?;
+        Ok(PcapWriter { file })
+    }
+
+    fn write(&mut self, frame: &TraceFrame) -> io::Result<()> {
+        let time = frame.since_epoch();
+        let len = frame.bytes.len() as u32 + 1;
+        self.file.write_all(&(time.as_secs() as u32).to_le_bytes())?;
+        self.file.write_all(&time.subsec_micros().to_le_bytes())?;
+        self.file.write_all(&len.to_le_bytes())?;
+        self.file.write_all(&len.to_le_bytes())?;
+        self.file.write_all(&[(frame.direction == TraceDirection::Rx) as u8])?;
+        self.file.write_all(&frame.bytes)?;
+        // the capture is most useful when the process didn't get to exit cleanly
+        self.file.flush()
+    }
+}
+
+/// Where the frames of a traced context go.
+#[derive(Debug, Clone)]
+pub enum TraceSink {
+    Log,
+    /// handed to the JS listener registered under the id
+    Callback(u32, Sender<RespType>),
+    Pcap(Arc<Mutex<PcapWriter>>),
+}
+
+impl TraceSink {
+    pub async fn record(&self, frame: TraceFrame) {
+        match *self {
+            TraceSink::Log => info!("modbus {}", frame),
+            TraceSink::Callback(id, ref tx) => {
+                // the listener may be gone already, the frame is of no use then
+                let _ = tx.clone().send(RespType::ModbusTrace(id, frame)).await;
+            },
+            TraceSink::Pcap(ref writer) => {
+                if let Err(err) = writer.lock().unwrap().write(&frame) {
+                    warn!("failed to write modbus trace: {}", err);
+                }
+            },
+        }
+    }
+
+    /// Id of the JS listener to unregister once the sink is replaced.
+    pub fn listener(&self) -> Option<u32> {
+        match *self {
+            TraceSink::Callback(id, _) => Some(id),
+            _ => None,
+        }
+    }
+}
+
+/// What `trace()` was asked for, turned into a `TraceSink` by the event loop.
+#[derive(Debug)]
+pub enum TraceTarget<'a> {
+    Off,
+    Log,
+    Callback(RJSCallback<'a>),
+    Pcap(PcapWriter),
+}
+
+/// Passes one frame to the JS listener as `{time, direction, unit, functionCode, hex, latencyMs, crcOk}`.
+pub fn qruff_modbus_trace_call_listener(listener: &RJSCallback, frame: TraceFrame) {
+    let ctxt = listener.ctxt;
+    unsafe {
+        let obj = ffi::JS_NewObject(ctxt.as_ptr());
+        js_set_property(ctxt, obj, "time", js_new_value(ctxt, frame.since_epoch().as_secs_f64() * 1000.0));
+        js_set_property(ctxt, obj, "direction", js_new_value(ctxt, String::from(frame.direction.as_str())));
+        js_set_property(ctxt, obj, "unit", js_new_value(ctxt, frame.unit as i32));
+        js_set_property(ctxt, obj, "functionCode", js_new_value(ctxt, frame.function as i32));
+        js_set_property(ctxt, obj, "hex", js_new_value(ctxt, hex(&frame.bytes)));
+        if let Some(latency) = frame.latency {
+            js_set_property(ctxt, obj, "latencyMs", js_new_value(ctxt, latency.as_secs_f64() * 1000.0));
+        }
+        if let Some(crc_ok) = frame.crc_ok {
+            js_set_property(ctxt, obj, "crcOk", js_new_value(ctxt, crc_ok));
+        }
+        let args = [obj];
+        let ret = ffi::JS_Call(ctxt.as_ptr(), listener.callback.raw(), ffi::UNDEFINED, 1, args.as_ptr() as *mut _);
+        ctxt.free_value(args[0]);
+        if Value::from(ret).is_exception() {
+            let err = ffi::JS_GetException(ctxt.as_ptr());
+            warn!("modbus trace listener threw an exception");
+            ctxt.free_value(err);
+        }
+        ctxt.free_value(ret);
+    }
+}
+
+unsafe fn trace_target<'a>(ctxt: &'a ContextRef, id: u32, target: ffi::JSValue) -> Result<TraceTarget<'a>, Error> {
+    if !js_to_bool(ctxt, target) {
+        return Ok(TraceTarget::Off);
+    }
+    if ctxt.is_function(&Value::from(target)) {
+        return Ok(TraceTarget::Callback(RJSCallback::new(id, ctxt, &Value::from(target))));
+    }
+    if Value::from(target).is_string() {
+        return match js_to_string(ctxt, target, "trace")?.as_str() {
+            "log" => Ok(TraceTarget::Log),
+            other => Err(format_err!("trace target must be 'log', a function or {{pcap: path}}, got '{}'", other)),
+        };
+    }
+    let path = js_get_option(ctxt, target, "pcap", |v, name| js_to_string(ctxt, v, name))?
+        .ok_or_else(|| format_err!("trace target must be 'log', a function or {{pcap: path}}"))?;
+    PcapWriter::create(&path)
+        .map(TraceTarget::Pcap)
+        .map_err(|err| format_err!("failed to create {}: {}", path, err))
+}
+
+/// `trace(target)`, starts, replaces or (with `null`) stops the frame trace of the context.
+pub unsafe extern "C" fn qruff_rtu_trace(
+    ctx: *mut ffi::JSContext,
+    this_val: ffi::JSValue,
+    argc: ::std::os::raw::c_int,
+    argv: *mut ffi::JSValue,
+) -> ffi::JSValue {
+    let ctxt = ContextRef::from_ptr(ctx);
+    let args = slice::from_raw_parts(argv, argc as usize);
+    let ptr = Value::from(this_val).get_opaque::<RtuContext>(qruff_rtu_context_class_id());
+    if ptr.is_null() {
+        return ffi::EXCEPTION;
+    }
+    let context = (*ptr).clone();
+    let mut ruff_ctx = ctxt.userdata::<RuffCtx>().unwrap();
+    let id = ruff_ctx.as_mut().id_generator.next_id();
+    let target = match trace_target(ctxt, id, js_arg(args, 0)) {
+        Ok(target) => target,
+        Err(err) => return js_throw_type_error(ctxt, &err.to_string()),
+    };
+
+    let mut request_msg = ruff_ctx.as_mut().request_msg.lock().unwrap();
+    request_msg.push(MsgType::TraceRtuContext(id, context, target));
+    ffi::UNDEFINED
+}
@@ -1,14 +1,26 @@
 use std::ops::Deref;
 use std::os::raw::c_int;
 use std::slice;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
+use failure::{format_err, Error};
 use serde::{Deserialize, Serialize};
-use tokio::sync::mpsc::{Sender, Receiver, channel};
+use futures::future::AbortHandle;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
 
 use crate::{
     ffi, mem, ClassId, ContextRef, ForeignTypeRef, MsgType, RJSTimerHandler, RuffCtx, Runtime,
-    RuntimeRef, Value, RJSPromise, register_rtu_context_class, qruff_rtu_setup,
-    qruff_rtu_read_holding_registers, qruff_rtu_context_class_id
+    RuntimeRef, Value, RJSPromise, register_rtu_context_class, qruff_rtu_setup, qruff_tcp_setup, qruff_rtu_context_class_id,
+    qruff_rtu_read_holding_registers, qruff_rtu_read_input_registers, qruff_rtu_read_coils, qruff_rtu_read_discrete_inputs,
+    qruff_rtu_write_single_register, qruff_rtu_write_multiple_registers, qruff_rtu_write_single_coil, qruff_rtu_write_multiple_coils,
+    qruff_rtu_read_write_multiple_registers, qruff_rtu_masked_write_register, qruff_rtu_queue_depth,
+    qruff_rtu_read_device_identification, qruff_rtu_report_server_id, qruff_rtu_diagnostics, qruff_rtu_get_comm_event_counter, qruff_rtu_trace,
+    qruff_rtu_stats, qruff_rtu_reset_stats,
+    register_modbus_server_class, qruff_modbus_server, qruff_modbus_server_class_id,
+    qruff_modbus_server_close, qruff_modbus_server_get, qruff_modbus_server_set, qruff_modbus_gateway,
+    qruff_modbus_scan, qruff_modbus_simulator, qruff_modbus_server_inject,
+    RJSCallback, RtuContext, RtuOperation, CmdGeneratorTask, CmdBroadcast, CmdReceiver, SubscribeOptions, js_new_json, js_new_value, js_to_string, js_iter_result, settle_promise, ByteOrder, DataType, DecodedValue, RegisterDecode, js_arg, js_throw_type_error,
 };
 
 lazy_static! {
@@ -18,7 +30,7 @@ lazy_static! {
 }
 
 macro_rules! register_func {
-    ($type_name:ident, $c_func:ident, $argc:expr) => {
+    ($type_name:tt, $c_func:ident, $argc:expr) => {
         ffi::JSCFunctionListEntry {
             name: cstr!($type_name).as_ptr(),
             prop_flags: (ffi::JS_PROP_WRITABLE | ffi::JS_PROP_CONFIGURABLE) as u8,
@@ -71,48 +83,240 @@ fn qruff_timer_class_id() -> ClassId {
     *QRUFF_TIMER_CLASS_ID
 }
 
+/// Table a command reads from.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CmdFunction {
+    Holding,
+    Input,
+    Coil,
+    Discrete,
+}
+
+impl Default for CmdFunction {
+    fn default() -> Self {
+        CmdFunction::Holding
+    }
+}
+
+fn default_scale() -> f64 {
+    1.0
+}
+
+/// Longest interval and phase a command takes, 30 days.
+const MAX_CMD_INTERVAL_MS: u64 = 30 * 24 * 3600 * 1000;
+
+/// One point of a device profile, everything but `id`, `reg_offset`, `reg_len`
+/// and `interval` being optional.
 #[derive(Serialize, Deserialize, Debug,Clone)]
 pub struct Cmd {
     pub id: String,
     pub reg_offset: u16,
     pub reg_len: u16,
-    pub interval: u16,
+    /// milliseconds between two firings
+    pub interval: u64,
+    /// milliseconds before the first firing
+    #[serde(default)]
+    pub phase: u64,
+    /// each firing is delayed by up to this many milliseconds, spreading the load of commands sharing an interval
+    #[serde(default)]
+    pub jitter: u64,
+    /// unit id to read from, the context's when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unit: Option<u8>,
+    #[serde(default)]
+    pub function: CmdFunction,
+    /// how registers are decoded, see `decode` of the read functions, plain uint16 when unset
+    #[serde(default, rename = "dataType", skip_serializing_if = "Option::is_none")]
+    pub data_type: Option<String>,
+    #[serde(default, rename = "byteOrder", skip_serializing_if = "Option::is_none")]
+    pub byte_order: Option<String>,
+    /// numbers are reported as `raw * scale + offset`
+    #[serde(default = "default_scale")]
+    pub scale: f64,
+    #[serde(default)]
+    pub offset: f64,
+    #[serde(default, rename = "unitOfMeasure", skip_serializing_if = "Option::is_none")]
+    pub unit_of_measure: Option<String>,
+}
+
+impl Cmd {
+    /// The read behind the command.
+    pub fn operation(&self) -> RtuOperation {
+        match self.function {
+            CmdFunction::Holding => RtuOperation::ReadHoldingRegisters(self.reg_offset, self.reg_len),
+            CmdFunction::Input => RtuOperation::ReadInputRegisters(self.reg_offset, self.reg_len),
+            CmdFunction::Coil => RtuOperation::ReadCoils(self.reg_offset, self.reg_len),
+            CmdFunction::Discrete => RtuOperation::ReadDiscreteInputs(self.reg_offset, self.reg_len),
+        }
+    }
+
+    /// How the registers read are decoded, `None` for coils and discrete inputs.
+    pub fn decode(&self) -> Result<Option<RegisterDecode>, Error> {
+        if self.function == CmdFunction::Coil || self.function == CmdFunction::Discrete {
+            if self.data_type.is_some() || self.byte_order.is_some() {
+                return Err(format_err!("{}: dataType and byteOrder only apply to registers", self.id));
+            }
+            return Ok(None);
+        }
+        let decode = RegisterDecode {
+            data_type: self.data_type.as_deref().map_or(Ok(DataType::Uint16), str::parse)?,
+            order: self.byte_order.as_deref().map_or(Ok(ByteOrder::default()), str::parse)?,
+        };
+        Ok(Some(decode))
+    }
+
+    /// Checks everything the profile can get wrong before anything is polled.
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.interval == 0 || self.interval > MAX_CMD_INTERVAL_MS {
+            return Err(format_err!(
+                "{}: interval must be between 1 and {} ms, got {}",
+                self.id,
+                MAX_CMD_INTERVAL_MS,
+                self.interval
+            ));
+        }
+        if self.phase > MAX_CMD_INTERVAL_MS {
+            return Err(format_err!("{}: phase must be at most {} ms, got {}", self.id, MAX_CMD_INTERVAL_MS, self.phase));
+        }
+        if self.jitter >= self.interval {
+            return Err(format_err!("{}: jitter must be shorter than the interval, got {}", self.id, self.jitter));
+        }
+        if self.reg_len == 0 {
+            return Err(format_err!("{}: reg_len must be greater than 0", self.id));
+        }
+        if let Some(unit) = self.unit {
+            if unit == 0 || unit > 247 {
+                return Err(format_err!("{}: unit must be between 1 and 247, got {}", self.id, unit));
+            }
+        }
+        if !self.scale.is_finite() || !self.offset.is_finite() {
+            return Err(format_err!("{}: scale and offset must be finite numbers", self.id));
+        }
+        if let Some(decode) = self.decode()? {
+            decode.check_count(self.reg_len).map_err(|err| format_err!("{}: {}", self.id, err))?;
+        }
+        Ok(())
+    }
+
+    /// Scales decoded numbers, other values are left as they are.
+    pub fn scale_value(&self, value: DecodedValue) -> DecodedValue {
+        match value {
+            DecodedValue::Numbers(numbers) => {
+                DecodedValue::Numbers(numbers.into_iter().map(|number| number * self.scale + self.offset).collect())
+            },
+            value => value,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize,Clone, Debug)]
 pub struct CmdList(pub Vec<Cmd>);
 
+impl CmdList {
+    fn validate(&self) -> Result<(), Error> {
+        for (idx, cmd) in self.0.iter().enumerate() {
+            cmd.validate()?;
+            if self.0[..idx].iter().any(|other| other.id == cmd.id) {
+                return Err(format_err!("{}: ids must be unique", cmd.id));
+            }
+        }
+        Ok(())
+    }
+}
+
 impl Drop for CmdList {
     fn drop(&mut self) {
         println!("> Dropping Cmdlist");
     }
 }
+/// Told to the task of a running generator.
+#[derive(Debug, Clone)]
+pub enum GeneratorControl {
+    Pause,
+    Resume,
+    Add(Cmd),
+    Remove(String),
+    Update(Cmd),
+}
+
+/// Handle on the task of a running generator.
+#[derive(Debug)]
+struct GeneratorTask {
+    control: UnboundedSender<GeneratorControl>,
+    abort: AbortHandle,
+    /// cleared by the task when it ends, whatever the reason
+    running: Arc<AtomicBool>,
+    paused: bool,
+}
+
 #[derive(Debug)]
 pub struct CmdGenerator {
-    pub tx: Sender<Cmd>,
-    rx: Option<Receiver<Cmd>>,
-    pub cmds: Option<CmdList>,
+    /// the endpoints and contexts the commands go to, closed once the task ends
+    broadcast: Arc<CmdBroadcast>,
+    /// the commands as last configured, the task gets a copy when run
+    cmds: CmdList,
+    task: Option<GeneratorTask>,
+    /// set for good by `stop()`
+    stopped: bool,
 }
 
 impl CmdGenerator {
-    fn new(cmds: Option<CmdList>, tx: Sender<Cmd>, rx:Option<Receiver<Cmd>>) -> CmdGenerator {
+    fn new(cmds: CmdList) -> CmdGenerator {
         CmdGenerator {
-            tx,
-            rx,
+            broadcast: Arc::new(CmdBroadcast::new()),
             cmds,
+            task: None,
+            stopped: false,
+        }
+    }
+
+    fn position(&self, id: &str) -> Option<usize> {
+        self.cmds.0.iter().position(|cmd| cmd.id == id)
+    }
+
+    /// Passes a change of the commands on to the task, if there is one.
+    fn reconfigure(&self, control: GeneratorControl) {
+        if let Some(ref task) = self.task {
+            let _ = task.control.send(control);
         }
     }
+
+    fn is_running(&self) -> bool {
+        self.task
+            .as_ref()
+            .map_or(false, |task| !task.paused && task.running.load(Ordering::SeqCst))
+    }
+}
+
+impl Drop for CmdGenerator {
+    /// The task doesn't outlive the JS object.
+    fn drop(&mut self) {
+        if let Some(task) = self.task.take() {
+            task.abort.abort();
+        }
+        self.broadcast.close();
+    }
 }
 
 #[derive(Debug)]
 pub struct CmdEndpoint {
-   pub rx: Option<Receiver<Cmd>>
+   pub rx: Option<CmdReceiver>,
+   /// the generator the endpoint is subscribed to, with the id of the subscription
+   subscription: Option<(Arc<CmdBroadcast>, u32)>,
+   /// asks the task iterating `rx` for one more command
+   next: Option<UnboundedSender<u32>>,
+   /// the commands go to an `onCmd` callback
+   listening: bool,
 }
 
 impl CmdEndpoint {
     fn new() -> CmdEndpoint {
         CmdEndpoint {
             rx: None,
+            subscription: None,
+            next: None,
+            listening: false,
         }
     }
 }
@@ -134,30 +338,108 @@ unsafe extern "C" fn qruff_create_cmd_endpoint(
     *ret
 }
 
+/// `endpoint(endpoint, options)`, subscribes `endpoint` to the commands of the generator.
+///
+/// `options` is `{ids, policy, capacity}`, see `SubscribeOptions`. An endpoint
+/// subscribes to one generator at a time.
 unsafe extern "C" fn qruff_cmd_generator_endpoint(
-    _ctx: *mut ffi::JSContext,
+    ctx: *mut ffi::JSContext,
     this_val: ffi::JSValue,
     argc: ::std::os::raw::c_int,
     argv: *mut ffi::JSValue,
 ) -> ffi::JSValue {
-    let this = Value::from(this_val);
+    let ctxt = ContextRef::from_ptr(ctx);
     let args = slice::from_raw_parts(argv, argc as usize);
-    let dest = Value::from(args[0]);
+    let generator = match this_generator(this_val) {
+        Some(generator) => generator,
+        None => return ffi::EXCEPTION,
+    };
+    let endpoint = match this_endpoint(js_arg(args, 0)) {
+        Some(endpoint) => endpoint,
+        None => return js_throw_type_error(ctxt, "endpoint needs an endpoint from createCmdEndpoint"),
+    };
+    if endpoint.subscription.is_some() {
+        return js_throw_type_error(ctxt, "the endpoint is already subscribed to a generator");
+    }
+    let options = match SubscribeOptions::from_js(ctxt, js_arg(args, 1)) {
+        Ok(options) => options,
+        Err(err) => return js_throw_type_error(ctxt, &err.to_string()),
+    };
 
-    let ptr = this.get_opaque::<CmdGenerator>(*QRUFF_CMD_GENERATOR_CLASS_ID);
+    let mut ruff_ctx = ctxt.userdata::<RuffCtx>().unwrap();
+    let id = ruff_ctx.as_mut().id_generator.next_id();
+    *endpoint = CmdEndpoint {
+        rx: Some(CmdBroadcast::subscribe(&generator.broadcast, id, options)),
+        subscription: Some((generator.broadcast.clone(), id)),
+        next: None,
+        listening: false,
+    };
+    ffi::UNDEFINED
+}
 
-    match &(*ptr).rx {
-        Some(_) => {
-            let dest_ptr = dest.get_opaque::<CmdEndpoint>(*QRUFF_CMD_ENDPOINT_CLASS_ID);
-            let rx = (*ptr).rx.take().unwrap();
-            (*dest_ptr).rx.replace(rx);
-            println!("!!!! set endpoint tx !!!!!");
+/// `unsubscribe(endpoint)`, the endpoint gets the commands already queued and no more,
+/// `false` when it wasn't subscribed to this generator, or its `onCmd` callback detached.
+unsafe extern "C" fn qruff_cmd_generator_unsubscribe(
+    ctx: *mut ffi::JSContext,
+    this_val: ffi::JSValue,
+    argc: ::std::os::raw::c_int,
+    argv: *mut ffi::JSValue,
+) -> ffi::JSValue {
+    let ctxt = ContextRef::from_ptr(ctx);
+    let args = slice::from_raw_parts(argv, argc as usize);
+    let generator = match this_generator(this_val) {
+        Some(generator) => generator,
+        None => return ffi::EXCEPTION,
+    };
+    let endpoint = match this_endpoint(js_arg(args, 0)) {
+        Some(endpoint) => endpoint,
+        None => return js_throw_type_error(ctxt, "unsubscribe needs an endpoint from createCmdEndpoint"),
+    };
+    let unsubscribed = match endpoint.subscription.take() {
+        Some((broadcast, id)) if Arc::ptr_eq(&broadcast, &generator.broadcast) => broadcast.unsubscribe(id),
+        subscription => {
+            endpoint.subscription = subscription;
+            false
         },
-        None => {
-            println!("Already run");
-        }
+    };
+    js_new_value(ctxt, unsubscribed)
+}
+
+/// `attach(ctx, callback, options)`, reads every triggered command off the modbus context `ctx`
+/// and hands the results to `callback`, alongside the endpoints.
+///
+/// `options` is `{ids, policy, capacity}` as for `endpoint()`.
+unsafe extern "C" fn qruff_cmd_generator_attach(
+    ctx: *mut ffi::JSContext,
+    this_val: ffi::JSValue,
+    argc: ::std::os::raw::c_int,
+    argv: *mut ffi::JSValue,
+) -> ffi::JSValue {
+    let ctxt = ContextRef::from_ptr(ctx);
+    let args = slice::from_raw_parts(argv, argc as usize);
+    let generator = match Value::from(this_val).get_opaque::<CmdGenerator>(*QRUFF_CMD_GENERATOR_CLASS_ID).as_mut() {
+        Some(generator) => generator,
+        None => return ffi::EXCEPTION,
+    };
+    let context = match Value::from(js_arg(args, 0)).get_opaque::<RtuContext>(qruff_rtu_context_class_id()).as_ref() {
+        Some(context) => context.clone(),
+        None => return js_throw_type_error(ctxt, "attach needs a modbus context from rtu_setup or tcp_setup"),
+    };
+    let callback = Value::from(js_arg(args, 1));
+    if !ctxt.is_function(&callback) {
+        return js_throw_type_error(ctxt, "callback must be a function");
     }
+    let options = match SubscribeOptions::from_js(ctxt, js_arg(args, 2)) {
+        Ok(options) => options,
+        Err(err) => return js_throw_type_error(ctxt, &err.to_string()),
+    };
 
+    let mut ruff_ctx = ctxt.userdata::<RuffCtx>().unwrap();
+    let id = ruff_ctx.as_mut().id_generator.next_id();
+    let rx = CmdBroadcast::subscribe(&generator.broadcast, id, options);
+    let callback = RJSCallback::new(id, ctxt, &callback);
+    let mut request_msg = ruff_ctx.as_mut().request_msg.lock().unwrap();
+    request_msg.push(MsgType::AttachCmdGenerator(id, rx, context, callback));
     ffi::UNDEFINED
 }
 
@@ -174,14 +456,32 @@ unsafe extern "C" fn qruff_cmd_generator_run(
     let id = ruff_ctx.as_mut().id_generator.next_id();
 
     let ptr = this.get_opaque::<CmdGenerator>(*QRUFF_CMD_GENERATOR_CLASS_ID);
+    if (*ptr).stopped {
+        return js_throw_type_error(ctxt, "the generator was stopped and can't be run again");
+    }
 
-    match &(*ptr).cmds {
-        Some(_cmds) => {
-            let cmd_generator = Box::new(CmdGenerator::new((*ptr).cmds.take(), (*ptr).tx.clone(), None));
+    match (*ptr).task {
+        None => {
+            let (control, control_rx) = unbounded_channel();
+            let (abort, registration) = AbortHandle::new_pair();
+            let running = Arc::new(AtomicBool::new(true));
+            (*ptr).task = Some(GeneratorTask {
+                control,
+                abort,
+                running: running.clone(),
+                paused: false,
+            });
+            let task = CmdGeneratorTask {
+                cmds: (*ptr).cmds.clone(),
+                broadcast: (*ptr).broadcast.clone(),
+                control: control_rx,
+                registration,
+                running,
+            };
             let mut request_msg = ruff_ctx.as_mut().request_msg.lock().unwrap();
-            request_msg.push(MsgType::AddCmdGenerator(id, cmd_generator));
+            request_msg.push(MsgType::AddCmdGenerator(id, task));
         },
-        None => {
+        Some(_) => {
             println!("Already run");
         }
     }
@@ -189,6 +489,201 @@ unsafe extern "C" fn qruff_cmd_generator_run(
     ffi::UNDEFINED
 }
 
+unsafe fn this_endpoint<'a>(this_val: ffi::JSValue) -> Option<&'a mut CmdEndpoint> {
+    Value::from(this_val)
+        .get_opaque::<CmdEndpoint>(*QRUFF_CMD_ENDPOINT_CLASS_ID)
+        .as_mut()
+}
+
+/// `onCmd(fn)`, calls `fn` with every command reaching the endpoint.
+unsafe extern "C" fn qruff_cmd_endpoint_on_cmd(
+    ctx: *mut ffi::JSContext,
+    this_val: ffi::JSValue,
+    argc: ::std::os::raw::c_int,
+    argv: *mut ffi::JSValue,
+) -> ffi::JSValue {
+    let ctxt = ContextRef::from_ptr(ctx);
+    let args = slice::from_raw_parts(argv, argc as usize);
+    let endpoint = match this_endpoint(this_val) {
+        Some(endpoint) => endpoint,
+        None => return ffi::EXCEPTION,
+    };
+    let callback = Value::from(js_arg(args, 0));
+    if !ctxt.is_function(&callback) {
+        return js_throw_type_error(ctxt, "callback must be a function");
+    }
+    let rx = match endpoint.rx.take() {
+        Some(rx) => rx,
+        None => return js_throw_type_error(ctxt, "the endpoint has no generator or its commands already go elsewhere"),
+    };
+    endpoint.listening = true;
+
+    let mut ruff_ctx = ctxt.userdata::<RuffCtx>().unwrap();
+    let id = ruff_ctx.as_mut().id_generator.next_id();
+    let callback = RJSCallback::new(id, ctxt, &callback);
+    let mut request_msg = ruff_ctx.as_mut().request_msg.lock().unwrap();
+    request_msg.push(MsgType::ListenCmdEndpoint(id, rx, callback));
+    ffi::UNDEFINED
+}
+
+/// `next()`, a promise of the next command, as the async iterator of the endpoint.
+unsafe extern "C" fn qruff_cmd_endpoint_next(
+    ctx: *mut ffi::JSContext,
+    this_val: ffi::JSValue,
+    _argc: ::std::os::raw::c_int,
+    _argv: *mut ffi::JSValue,
+) -> ffi::JSValue {
+    let ctxt = ContextRef::from_ptr(ctx);
+    let endpoint = match this_endpoint(this_val) {
+        Some(endpoint) => endpoint,
+        None => return ffi::EXCEPTION,
+    };
+    if endpoint.listening {
+        return js_throw_type_error(ctxt, "the commands of the endpoint go to onCmd");
+    }
+
+    let mut ruff_ctx = ctxt.userdata::<RuffCtx>().unwrap();
+    let rfunc: [ffi::JSValue; 2] = [ffi::UNDEFINED; 2];
+    let id = ruff_ctx.as_mut().id_generator.next_id();
+    let promise = ffi::JS_NewPromiseCapability(ctxt.as_ptr(), rfunc.as_ptr() as *mut _);
+    let handle = RJSPromise::new(
+        id,
+        ctxt,
+        &Value::from(promise),
+        &Value::from(rfunc[0]),
+        &Value::from(rfunc[1]),
+    );
+
+    let mut request_msg = ruff_ctx.as_mut().request_msg.lock().unwrap();
+    if endpoint.next.is_none() {
+        if let Some(rx) = endpoint.rx.take() {
+            let (next, next_rx) = unbounded_channel();
+            endpoint.next = Some(next);
+            request_msg.push(MsgType::IterateCmdEndpoint(rx, next_rx));
+        }
+    }
+    match endpoint.next {
+        Some(ref next) => request_msg.push(MsgType::NextEndpointCmd(id, next.clone(), handle)),
+        // nothing to iterate, or the iteration was given up
+        None => settle_promise(&handle, Ok(js_iter_result(ctxt, None))),
+    }
+    promise
+}
+
+/// `return()`, ends the iteration early, as on a `break` out of `for await`.
+unsafe extern "C" fn qruff_cmd_endpoint_return(
+    ctx: *mut ffi::JSContext,
+    this_val: ffi::JSValue,
+    _argc: ::std::os::raw::c_int,
+    _argv: *mut ffi::JSValue,
+) -> ffi::JSValue {
+    let ctxt = ContextRef::from_ptr(ctx);
+    let endpoint = match this_endpoint(this_val) {
+        Some(endpoint) => endpoint,
+        None => return ffi::EXCEPTION,
+    };
+    if !endpoint.listening {
+        // the task ends, and drops `rx`, once the pending `next()` are answered
+        endpoint.next = None;
+        endpoint.rx = None;
+    }
+    js_iter_result(ctxt, None)
+}
+
+/// `[Symbol.asyncIterator]()`, the endpoint is its own iterator.
+unsafe extern "C" fn qruff_cmd_endpoint_async_iterator(
+    ctx: *mut ffi::JSContext,
+    this_val: ffi::JSValue,
+    _argc: ::std::os::raw::c_int,
+    _argv: *mut ffi::JSValue,
+) -> ffi::JSValue {
+    let ctxt = ContextRef::from_ptr(ctx);
+    ctxt.clone_value(&Value::from(this_val)).raw()
+}
+
+unsafe fn this_generator<'a>(this_val: ffi::JSValue) -> Option<&'a mut CmdGenerator> {
+    Value::from(this_val)
+        .get_opaque::<CmdGenerator>(*QRUFF_CMD_GENERATOR_CLASS_ID)
+        .as_mut()
+}
+
+/// `stop()`, ends the task of the generator, which can't be run again.
+unsafe extern "C" fn qruff_cmd_generator_stop(
+    _ctx: *mut ffi::JSContext,
+    this_val: ffi::JSValue,
+    _argc: ::std::os::raw::c_int,
+    _argv: *mut ffi::JSValue,
+) -> ffi::JSValue {
+    let generator = match this_generator(this_val) {
+        Some(generator) => generator,
+        None => return ffi::EXCEPTION,
+    };
+    generator.stopped = true;
+    if let Some(task) = generator.task.take() {
+        task.running.store(false, Ordering::SeqCst);
+        task.abort.abort();
+    }
+    generator.broadcast.close();
+    ffi::UNDEFINED
+}
+
+/// Sends `control` to the task of the generator behind `this`, throwing when it isn't running.
+unsafe fn qruff_cmd_generator_control(ctx: *mut ffi::JSContext, this_val: ffi::JSValue, control: GeneratorControl) -> ffi::JSValue {
+    let ctxt = ContextRef::from_ptr(ctx);
+    let generator = match this_generator(this_val) {
+        Some(generator) => generator,
+        None => return ffi::EXCEPTION,
+    };
+    let task = match generator.task {
+        Some(ref mut task) if task.running.load(Ordering::SeqCst) => task,
+        _ => return js_throw_type_error(ctxt, "the generator isn't running"),
+    };
+    let paused = match control {
+        GeneratorControl::Pause => true,
+        GeneratorControl::Resume => false,
+        _ => task.paused,
+    };
+    if task.control.send(control).is_err() {
+        return js_throw_type_error(ctxt, "the generator isn't running");
+    }
+    task.paused = paused;
+    ffi::UNDEFINED
+}
+
+/// `pause()`, holds every command back until `resume()`.
+unsafe extern "C" fn qruff_cmd_generator_pause(
+    ctx: *mut ffi::JSContext,
+    this_val: ffi::JSValue,
+    _argc: ::std::os::raw::c_int,
+    _argv: *mut ffi::JSValue,
+) -> ffi::JSValue {
+    qruff_cmd_generator_control(ctx, this_val, GeneratorControl::Pause)
+}
+
+/// `resume()`, commands due while paused fire once right away, then keep to their schedule.
+unsafe extern "C" fn qruff_cmd_generator_resume(
+    ctx: *mut ffi::JSContext,
+    this_val: ffi::JSValue,
+    _argc: ::std::os::raw::c_int,
+    _argv: *mut ffi::JSValue,
+) -> ffi::JSValue {
+    qruff_cmd_generator_control(ctx, this_val, GeneratorControl::Resume)
+}
+
+/// `isRunning()`, whether commands are being generated: run, neither paused nor stopped.
+unsafe extern "C" fn qruff_cmd_generator_is_running(
+    ctx: *mut ffi::JSContext,
+    this_val: ffi::JSValue,
+    _argc: ::std::os::raw::c_int,
+    _argv: *mut ffi::JSValue,
+) -> ffi::JSValue {
+    let ctxt = ContextRef::from_ptr(ctx);
+    match this_generator(this_val) {
+        Some(generator) => js_new_value(ctxt, generator.is_running()),
+        None => ffi::EXCEPTION,
+    }
+}
+
 unsafe extern "C" fn qruff_cmd_show(
     ctx: *mut ffi::JSContext,
     _this_val: ffi::JSValue,
@@ -218,6 +713,136 @@ unsafe extern "C" fn qruff_cmd_show(
 }
 
 
+/// A command given as an object or its JSON text, as JSON.
+unsafe fn js_to_json(ctxt: &ContextRef, value: ffi::JSValue, name: &str) -> Result<serde_json::Value, Error> {
+    let text = if Value::from(value).is_string() {
+        js_to_string(ctxt, value, name)?
+    } else {
+        let json = ffi::JS_JSONStringify(ctxt.as_ptr(), value, ffi::UNDEFINED, ffi::UNDEFINED);
+        let text = js_to_string(ctxt, json, name);
+        ctxt.free_value(json);
+        text?
+    };
+    serde_json::from_str(&text).map_err(|err| format_err!("{} is not a command: {}", name, err))
+}
+
+fn json_to_cmd(json: serde_json::Value) -> Result<Cmd, Error> {
+    let cmd: Cmd = serde_json::from_value(json).map_err(|err| format_err!("invalid command: {}", err))?;
+    cmd.validate()?;
+    Ok(cmd)
+}
+
+/// `addCmd(cmd)`, schedules one more command, from now on when the generator runs.
+unsafe extern "C" fn qruff_cmd_generator_add_cmd(
+    ctx: *mut ffi::JSContext,
+    this_val: ffi::JSValue,
+    argc: ::std::os::raw::c_int,
+    argv: *mut ffi::JSValue,
+) -> ffi::JSValue {
+    let ctxt = ContextRef::from_ptr(ctx);
+    let args = slice::from_raw_parts(argv, argc as usize);
+    let generator = match this_generator(this_val) {
+        Some(generator) => generator,
+        None => return ffi::EXCEPTION,
+    };
+    let cmd = match js_to_json(ctxt, js_arg(args, 0), "cmd").and_then(json_to_cmd) {
+        Ok(cmd) => cmd,
+        Err(err) => return js_throw_type_error(ctxt, &err.to_string()),
+    };
+    if generator.position(&cmd.id).is_some() {
+        return js_throw_type_error(ctxt, &format!("{}: ids must be unique", cmd.id));
+    }
+    generator.reconfigure(GeneratorControl::Add(cmd.clone()));
+    generator.cmds.0.push(cmd);
+    ffi::UNDEFINED
+}
+
+/// `removeCmd(id)`, `false` when there was no such command.
+unsafe extern "C" fn qruff_cmd_generator_remove_cmd(
+    ctx: *mut ffi::JSContext,
+    this_val: ffi::JSValue,
+    argc: ::std::os::raw::c_int,
+    argv: *mut ffi::JSValue,
+) -> ffi::JSValue {
+    let ctxt = ContextRef::from_ptr(ctx);
+    let args = slice::from_raw_parts(argv, argc as usize);
+    let generator = match this_generator(this_val) {
+        Some(generator) => generator,
+        None => return ffi::EXCEPTION,
+    };
+    let id = match js_to_string(ctxt, js_arg(args, 0), "id") {
+        Ok(id) => id,
+        Err(err) => return js_throw_type_error(ctxt, &err.to_string()),
+    };
+    let removed = match generator.position(&id) {
+        Some(idx) => {
+            generator.cmds.0.remove(idx);
+            generator.reconfigure(GeneratorControl::Remove(id));
+            true
+        },
+        None => false,
+    };
+    js_new_value(ctxt, removed)
+}
+
+/// `updateCmd(id, patch)`, changes the fields of `patch` in the command `id`.
+///
+/// The command keeps its place in the schedule unless its interval changes,
+/// the next firing then being one new interval after the previous one.
+unsafe extern "C" fn qruff_cmd_generator_update_cmd(
+    ctx: *mut ffi::JSContext,
+    this_val: ffi::JSValue,
+    argc: ::std::os::raw::c_int,
+    argv: *mut ffi::JSValue,
+) -> ffi::JSValue {
+    let ctxt = ContextRef::from_ptr(ctx);
+    let args = slice::from_raw_parts(argv, argc as usize);
+    let generator = match this_generator(this_val) {
+        Some(generator) => generator,
+        None => return ffi::EXCEPTION,
+    };
+    let updated = (|| -> Result<(usize, Cmd), Error> {
+        let id = js_to_string(ctxt, js_arg(args, 0), "id")?;
+        let patch = match js_to_json(ctxt, js_arg(args, 1), "patch")? {
+            serde_json::Value::Object(patch) => patch,
+            _ => return Err(format_err!("patch must be an object")),
+        };
+        let idx = generator.position(&id).ok_or_else(|| format_err!("no command {}", id))?;
+        let mut cmd = match serde_json::to_value(&generator.cmds.0[idx])? {
+            serde_json::Value::Object(cmd) => cmd,
+            _ => unreachable!("commands serialize to objects"),
+        };
+        cmd.extend(patch);
+        let cmd = json_to_cmd(serde_json::Value::Object(cmd))?;
+        if cmd.id != id {
+            return Err(format_err!("{}: the id of a command can't be changed", id));
+        }
+        Ok((idx, cmd))
+    })();
+    let (idx, cmd) = match updated {
+        Ok(updated) => updated,
+        Err(err) => return js_throw_type_error(ctxt, &err.to_string()),
+    };
+    generator.reconfigure(GeneratorControl::Update(cmd.clone()));
+    generator.cmds.0[idx] = cmd;
+    ffi::UNDEFINED
+}
+
+/// `listCmds()`, the commands as currently configured.
+unsafe extern "C" fn qruff_cmd_generator_list_cmds(
+    ctx: *mut ffi::JSContext,
+    this_val: ffi::JSValue,
+    _argc: ::std::os::raw::c_int,
+    _argv: *mut ffi::JSValue,
+) -> ffi::JSValue {
+    let ctxt = ContextRef::from_ptr(ctx);
+    let generator = match this_generator(this_val) {
+        Some(generator) => generator,
+        None => return ffi::EXCEPTION,
+    };
+    js_new_json(ctxt, &generator.cmds)
+}
+
 unsafe extern "C" fn qruff_create_cmd_generator(
     ctx: *mut ffi::JSContext,
     _this_val: ffi::JSValue,
@@ -232,9 +857,14 @@ unsafe extern "C" fn qruff_create_cmd_generator(
         Some(value) => String::from(value.to_string_lossy()),
         None => return ffi::EXCEPTION,
     };
-    let cmds: CmdList = serde_json::from_str(&cmd_json).unwrap();
-    let (tx, rx) = channel(100);
-    let cmd_generator = Box::new(CmdGenerator::new(Some(cmds), tx, Some(rx)));
+    let cmds: CmdList = match serde_json::from_str(&cmd_json) {
+        Ok(cmds) => cmds,
+        Err(err) => return js_throw_type_error(ctxt, &format!("invalid command list: {}", err)),
+    };
+    if let Err(err) = cmds.validate() {
+        return js_throw_type_error(ctxt, &err.to_string());
+    }
+    let cmd_generator = Box::new(CmdGenerator::new(cmds));
     let ret = ctxt.new_object_class(*QRUFF_CMD_GENERATOR_CLASS_ID);
     ret.set_opaque(Box::into_raw(cmd_generator));
 
@@ -327,10 +957,10 @@ unsafe extern "C" fn qruff_getAddrInfo(
 
 pub fn register_create_cmd_endpoint_class(rt: &RuntimeRef) -> bool {
     unsafe extern "C" fn qruff_cmd_endpoint_finalizer(_rt: *mut ffi::JSRuntime, obj: ffi::JSValue) {
-        let ptr = ffi::JS_GetOpaque(obj, *QRUFF_CMD_ENDPOINT_CLASS_ID);
+        let ptr = ffi::JS_GetOpaque(obj, *QRUFF_CMD_ENDPOINT_CLASS_ID) as *mut CmdEndpoint;
 
         trace!("free userdata {:p} @ {:?}", ptr, obj.u.ptr);
-        println!("free userdata for cmd generator {:p} @ {:?}", ptr, obj.u.ptr);
+        println!("free userdata for cmd endpoint {:p} @ {:?}", ptr, obj.u.ptr);
 
         mem::drop(Box::from_raw(ptr));
     }
@@ -391,10 +1021,11 @@ pub fn register_timer_class(rt: &RuntimeRef) -> bool {
     )
 }
 
-new_func_table_type!(QRuffModuleFuncList, ModuleFuncList, 7);
-new_func_table_type!(QRuffCmdGeneratorFuncList, CmdGeneratorFuncList, 2);
-new_func_table_type!(QRuffCmdEndpointFuncList, CmdEndpointFuncList, 1);
-new_func_table_type!(QRuffRtuFuncList, RtuFuncList, 1);
+new_func_table_type!(QRuffModuleFuncList, ModuleFuncList, 12);
+new_func_table_type!(QRuffCmdGeneratorFuncList, CmdGeneratorFuncList, 12);
+new_func_table_type!(QRuffCmdEndpointFuncList, CmdEndpointFuncList, 5);
+new_func_table_type!(QRuffRtuFuncList, RtuFuncList, 18);
+new_func_table_type!(QRuffModbusServerFuncList, ModbusServerFuncList, 4);
 
 lazy_static! {
     static ref QRUFF_MODULE_FUNC_TABLE: QRuffModuleFuncList = QRuffModuleFuncList([
@@ -404,21 +1035,64 @@ lazy_static! {
         register_func!(getAddrInfo, qruff_getAddrInfo, 1),
         register_func!(createCmdGenerator, qruff_create_cmd_generator, 1),
         register_func!(createCmdEndpoint, qruff_create_cmd_endpoint, 1),
-        register_func!(rtu_setup, qruff_rtu_setup, 1),
+        register_func!(rtu_setup, qruff_rtu_setup, 2),
+        register_func!(tcp_setup, qruff_tcp_setup, 3),
+        register_func!(modbusServer, qruff_modbus_server, 1),
+        register_func!(modbusGateway, qruff_modbus_gateway, 1),
+        register_func!(modbusScan, qruff_modbus_scan, 2),
+        register_func!(modbusSimulator, qruff_modbus_simulator, 1),
     ]);
 
     static ref QRUFF_CMD_ENDPOINT_FUNC_TABLE: QRuffCmdEndpointFuncList = QRuffCmdEndpointFuncList([
         //register_func!(show, qruff_cmd_generator_run, 0),
         register_func!(show, qruff_cmd_show, 0),
+        register_func!(onCmd, qruff_cmd_endpoint_on_cmd, 1),
+        register_func!(next, qruff_cmd_endpoint_next, 0),
+        register_func!("return", qruff_cmd_endpoint_return, 0),
+        register_func!("[Symbol.asyncIterator]", qruff_cmd_endpoint_async_iterator, 0),
     ]);
 
     static ref QRUFF_RTU_FUNC_TABLE: QRuffRtuFuncList = QRuffRtuFuncList([
-        register_func!(read_holding_registers, qruff_rtu_read_holding_registers, 0),
+        register_func!(read_holding_registers, qruff_rtu_read_holding_registers, 2),
+        register_func!(read_input_registers, qruff_rtu_read_input_registers, 2),
+        register_func!(read_coils, qruff_rtu_read_coils, 2),
+        register_func!(read_discrete_inputs, qruff_rtu_read_discrete_inputs, 2),
+        register_func!(write_single_register, qruff_rtu_write_single_register, 2),
+        register_func!(write_multiple_registers, qruff_rtu_write_multiple_registers, 2),
+        register_func!(write_single_coil, qruff_rtu_write_single_coil, 2),
+        register_func!(write_multiple_coils, qruff_rtu_write_multiple_coils, 2),
+        register_func!(read_write_multiple_registers, qruff_rtu_read_write_multiple_registers, 4),
+        register_func!(masked_write_register, qruff_rtu_masked_write_register, 3),
+        register_func!(queue_depth, qruff_rtu_queue_depth, 0),
+        register_func!(read_device_identification, qruff_rtu_read_device_identification, 2),
+        register_func!(report_server_id, qruff_rtu_report_server_id, 0),
+        register_func!(diagnostics, qruff_rtu_diagnostics, 2),
+        register_func!(get_comm_event_counter, qruff_rtu_get_comm_event_counter, 0),
+        register_func!(trace, qruff_rtu_trace, 1),
+        register_func!(stats, qruff_rtu_stats, 0),
+        register_func!(reset_stats, qruff_rtu_reset_stats, 0),
+    ]);
+
+    static ref QRUFF_MODBUS_SERVER_FUNC_TABLE: QRuffModbusServerFuncList = QRuffModbusServerFuncList([
+        register_func!(close, qruff_modbus_server_close, 0),
+        register_func!(get, qruff_modbus_server_get, 3),
+        register_func!(set, qruff_modbus_server_set, 3),
+        register_func!(inject, qruff_modbus_server_inject, 1),
     ]);
 
     static ref QRUFF_CMD_GENERATOR_FUNC_TABLE: QRuffCmdGeneratorFuncList = QRuffCmdGeneratorFuncList([
         register_func!(run, qruff_cmd_generator_run, 0),
-        register_func!(endpoint, qruff_cmd_generator_endpoint, 1),
+        register_func!(endpoint, qruff_cmd_generator_endpoint, 2),
+        register_func!(unsubscribe, qruff_cmd_generator_unsubscribe, 1),
+        register_func!(attach, qruff_cmd_generator_attach, 3),
+        register_func!(stop, qruff_cmd_generator_stop, 0),
+        register_func!(pause, qruff_cmd_generator_pause, 0),
+        register_func!(resume, qruff_cmd_generator_resume, 0),
+        register_func!(isRunning, qruff_cmd_generator_is_running, 0),
+        register_func!(addCmd, qruff_cmd_generator_add_cmd, 1),
+        register_func!(removeCmd, qruff_cmd_generator_remove_cmd, 1),
+        register_func!(updateCmd, qruff_cmd_generator_update_cmd, 2),
+        register_func!(listCmds, qruff_cmd_generator_list_cmds, 0),
     ]);
 
 }
@@ -443,6 +1117,9 @@ unsafe extern "C" fn js_module_dummy_init(
     if register_rtu_context_class(ctxt.runtime()) {
         println!("Fail to register rtu context Class");
     }
+    if register_modbus_server_class(ctxt.runtime()) {
+        println!("Fail to register modbus server Class");
+    }
     let cmd_obj = ctxt.new_object();
     ffi::JS_SetPropertyFunctionList(_ctx, cmd_obj.raw(),
         QRUFF_CMD_GENERATOR_FUNC_TABLE.as_ptr() as *mut _,
@@ -459,6 +1136,13 @@ unsafe extern "C" fn js_module_dummy_init(
     );
     ctxt.set_class_proto(qruff_rtu_context_class_id(), rtu_obj);
 
+    let server_obj = ctxt.new_object();
+    ffi::JS_SetPropertyFunctionList(_ctx, server_obj.raw(),
+        QRUFF_MODBUS_SERVER_FUNC_TABLE.as_ptr() as *mut _,
+        QRUFF_MODBUS_SERVER_FUNC_TABLE.0.len() as i32,
+    );
+    ctxt.set_class_proto(qruff_modbus_server_class_id(), server_obj);
+
     let cmd_endpoint_obj = ctxt.new_object();
     ffi::JS_SetPropertyFunctionList(_ctx, cmd_endpoint_obj.raw(),
         QRUFF_CMD_ENDPOINT_FUNC_TABLE.as_ptr() as *mut _,
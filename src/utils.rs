@@ -1,20 +1,32 @@
-use crate::{ffi, Args, ContextRef, Eval, Local, Value, CmdGenerator, Cmd, qruff_rtu_setup_settle_promise, SerialConfig, rtu_setup, RtuContext, RtuOperation, qruff_rtu_operation_settle_promise, rtu_operation};
-use failure::Error;
+use crate::{ffi, Args, ContextRef, Eval, Local, Value, CmdList, GeneratorControl, Cmd, qruff_rtu_setup_settle_promise, SerialConfig, rtu_setup, TcpConfig, tcp_setup, RtuContext, RtuOperation, RtuCallOptions, qruff_rtu_operation_settle_promise, RtuResponse, ModbusError, rtu_operation,
+    ServerConfig, ServerRequest, ServerReply, ModbusServer, modbus_server_start, qruff_modbus_server_settle_promise, qruff_modbus_server_call_handler,
+    EXCEPTION_SLAVE_DEVICE_FAILURE, GatewayConfig, modbus_gateway_start, ScanConfig, ScanHit, modbus_scan,
+    qruff_modbus_scan_settle_promise, qruff_modbus_trace_call_listener, TraceFrame, TraceSink, TraceTarget,
+    cmd_poll_loop, js_cmd_poll, CmdPoll, CmdScheduler,
+    cmd_endpoint_listen, cmd_endpoint_iterate, qruff_cmd_endpoint_settle_next,
+    CmdBroadcast, CmdReceiver};
+use failure::{format_err, Error};
 use foreign_types::ForeignTypeRef;
 use std::collections::HashMap;
-use std::ffi::{CStr, OsStr};
+use std::convert::TryFrom;
+use std::ffi::{CStr, CString, OsStr};
 use std::os::raw::{c_char, c_void};
 use std::os::unix::ffi::OsStrExt;
 use std::path::Path;
+use std::mem;
 use std::ptr::null_mut;
 use std::rc::Rc;
-use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 use tokio::fs::File;
 use tokio::prelude::*;
-use tokio::sync::mpsc::{Sender, Receiver};
+use futures::future::{AbortHandle, AbortRegistration, Abortable};
+use tokio::sync::mpsc::{Sender, UnboundedReceiver, UnboundedSender};
+use tokio::sync::oneshot;
 use tokio::time::{delay_queue, DelayQueue};
 use std::net::SocketAddr;
-use tokio::time::{self, Duration};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::time::{self, Duration, Instant};
 use serde_json::value::Serializer;
 use dns_lookup::{AddrInfo, getaddrinfo};
 use serde::{Serialize, Deserialize};
@@ -110,23 +122,75 @@ pub async fn get_addr_info(addr: String, mut tx: Sender<RespType>, job_id: u32)
     tx.send(RespType::GetAddrInfo(job_id, Ok(output.into_bytes()))).await.unwrap();
 }
 
-pub async fn cmd_generator_loop(mut cmd_generator: Box<CmdGenerator>, _id: u32) {
-    let mut interval = time::interval(Duration::from_millis(1000));
-    let mut current_slot: u16 = 0;
-    if let Some(cmds) = cmd_generator.cmds {
-        loop {
-            println!("in cmd_generator_loop");
-            current_slot += 1000;
-            for cmd in &cmds.0 {
-                if current_slot % cmd.interval == 0 {
-                    cmd_generator.tx.send(cmd.clone()).await.unwrap();
-                    //println!("cmd triggered -> {:?}", cmd);
+/// What the task of a generator is made of, put together by `run()`.
+#[derive(Debug)]
+pub struct CmdGeneratorTask {
+    pub cmds: CmdList,
+    pub broadcast: Arc<CmdBroadcast>,
+    pub control: UnboundedReceiver<GeneratorControl>,
+    pub registration: AbortRegistration,
+    pub running: Arc<AtomicBool>,
+}
+
+async fn cmd_generator_loop(mut cmds: CmdList, broadcast: &CmdBroadcast, mut control: UnboundedReceiver<GeneratorControl>) {
+    let mut scheduler = CmdScheduler::new(mem::take(&mut cmds.0), Instant::now());
+    let mut paused = false;
+    loop {
+        let due = scheduler.next_due();
+        tokio::select! {
+            received = control.recv() => match received {
+                Some(GeneratorControl::Pause) => paused = true,
+                Some(GeneratorControl::Resume) => paused = false,
+                Some(GeneratorControl::Add(cmd)) => scheduler.add(cmd, Instant::now()),
+                Some(GeneratorControl::Remove(id)) => {
+                    scheduler.remove(&id);
+                },
+                Some(GeneratorControl::Update(cmd)) => scheduler.update(cmd, Instant::now()),
+                None => return,
+            },
+            _ = time::delay_until(due.unwrap_or_else(Instant::now)), if due.is_some() && !paused => {
+                for cmd in scheduler.take_due(Instant::now()) {
+                    broadcast.send(&cmd).await;
                 }
-            }
-            interval.tick().await;
+            },
         }
     }
 }
+
+/// Runs a generator until `stop()` or the JS object going away, its subscribers then see the end of the commands.
+pub async fn run_cmd_generator(task: CmdGeneratorTask, mut resp_tx: Sender<RespType>, id: u32) {
+    let CmdGeneratorTask { cmds, broadcast, control, registration, running } = task;
+    let _ = Abortable::new(cmd_generator_loop(cmds, &broadcast, control), registration).await;
+    broadcast.close();
+    running.store(false, Ordering::SeqCst);
+    let _ = resp_tx.send(RespType::CmdGeneratorStopped(id)).await;
+}
+
+/// xorshift, good enough to spread timings and faults without pulling in a crate.
+#[derive(Debug, Clone)]
+pub struct XorShift(u64);
+
+impl XorShift {
+    pub fn new() -> Self {
+        let seed = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        XorShift(seed.as_nanos() as u64 | 1)
+    }
+
+    /// Uniform in `[0, 1)`.
+    pub fn next_f64(&mut self) -> f64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+impl Default for XorShift {
+    fn default() -> Self {
+        XorShift::new()
+    }
+}
+
 #[derive(Debug)]
 pub struct RJSPromise<'a> {
     id: u32,
@@ -180,16 +244,66 @@ impl<'a> RJSTimerHandler<'a> {
     }
 }
 
+/// A JS function kept by the event loop and called whenever its source has news.
+#[derive(Debug)]
+pub struct RJSCallback<'a> {
+    pub id: u32,
+    pub ctxt: &'a ContextRef,
+    pub callback: Local<'a, Value>,
+}
+
+impl<'a> RJSCallback<'a> {
+    pub unsafe fn new(id: u32, ctxt: &'a ContextRef, callback: &Value) -> Self {
+        Self {
+            id,
+            ctxt,
+            callback: ctxt.clone_value(callback),
+        }
+    }
+}
+
+/// Calls `callback` with `arg`, which it takes, on the JS thread.
+///
+/// Returns `false` when the callback asks to be detached, by returning `false`
+/// or any falsy value but `undefined`. A callback that throws stays attached,
+/// the exception is logged as coming from `what`.
+pub unsafe fn js_call_listener(callback: &RJSCallback, arg: ffi::JSValue, what: &str) -> bool {
+    let ctxt = callback.ctxt;
+    let args = [arg];
+    let ret = ffi::JS_Call(ctxt.as_ptr(), callback.callback.raw(), ffi::UNDEFINED, 1, args.as_ptr() as *mut _);
+    ctxt.free_value(arg);
+    let keep = if Value::from(ret).is_exception() {
+        let err = ffi::JS_GetException(ctxt.as_ptr());
+        warn!("{} callback threw an exception", what);
+        ctxt.free_value(err);
+        true
+    } else {
+        Value::from(ret).is_undefined() || js_to_bool(ctxt, ret)
+    };
+    ctxt.free_value(ret);
+    keep
+}
+
 #[derive(Debug)]
 pub enum MsgType<'a> {
     AddTimer(u32, RJSTimerHandler<'a>),
     DeleteTimer(u32),
     FsReadAll(u32, String, RJSPromise<'a>),
     GetAddrInfo(u32, String, RJSPromise<'a>),
-    AddCmdGenerator(u32, Box<CmdGenerator>),
-    AddCmdShower(u32, Receiver<Cmd>),
+    AddCmdGenerator(u32, CmdGeneratorTask),
+    AddCmdShower(u32, CmdReceiver),
+    ListenCmdEndpoint(u32, CmdReceiver, RJSCallback<'a>),
+    IterateCmdEndpoint(CmdReceiver, UnboundedReceiver<u32>),
+    NextEndpointCmd(u32, UnboundedSender<u32>, RJSPromise<'a>),
+    AttachCmdGenerator(u32, CmdReceiver, RtuContext, RJSCallback<'a>),
     CreateRtuSetup(u32, SerialConfig, RJSPromise<'a>),
-    AddRtuOperation(u32, RtuOperation, RJSPromise<'a>),
+    CreateTcpSetup(u32, TcpConfig, RJSPromise<'a>),
+    AddRtuOperation(u32, RtuContext, RtuOperation, RtuCallOptions, RJSPromise<'a>),
+    CreateModbusServer(u32, ServerConfig, Option<RJSCallback<'a>>, RJSPromise<'a>),
+    CloseModbusServer(u32),
+    CreateModbusGateway(u32, GatewayConfig, RJSPromise<'a>),
+    ModbusScan(u32, ScanConfig, RJSPromise<'a>),
+    TraceRtuContext(u32, RtuContext, TraceTarget<'a>),
 }
 
 #[derive(Debug)]
@@ -197,7 +311,18 @@ pub enum RespType {
     FsResponse(u32, Result<Vec<u8>, Error>),
     GetAddrInfo(u32, Result<Vec<u8>, Error>),
     RtuSetup(u32, Result<RtuContext, Error>),
-    RtuReadHoldingRegisters(u32, Result<Vec<u16>, Error>),
+    RtuOperation(u32, Result<RtuResponse, ModbusError>),
+    ModbusServerStarted(u32, Result<ModbusServer, Error>),
+    ModbusServerRequest(u32, ServerRequest, oneshot::Sender<ServerReply>),
+    ModbusServerStopped(u32),
+    ModbusScan(u32, Result<Vec<ScanHit>, Error>),
+    ModbusTrace(u32, TraceFrame),
+    CmdPoll(u32, CmdPoll),
+    CmdPollStopped(u32),
+    CmdGeneratorStopped(u32),
+    EndpointCmd(u32, Cmd),
+    CmdEndpointClosed(u32),
+    EndpointNext(u32, Result<Option<Cmd>, Error>),
 }
 
 type RequestMsg<'a> = Rc<Mutex<Vec<MsgType<'a>>>>;
@@ -238,6 +363,12 @@ impl RRIdGenerator {
 pub struct RRIdManager<'a> {
     pending_job: HashMap<u32, RJSPromise<'a>>,
     pending_timer: HashMap<u32, delay_queue::Key>,
+    /// long running sources keeping the loop alive, with the JS callback they report to
+    services: HashMap<u32, Option<RJSCallback<'a>>>,
+    /// tasks of services that end along with them
+    service_tasks: HashMap<u32, AbortHandle>,
+    /// JS callbacks fed by something else alive, they don't keep the loop running on their own
+    listeners: HashMap<u32, RJSCallback<'a>>,
 }
 
 unsafe impl<'a> Send for RRIdManager<'a> {}
@@ -248,6 +379,9 @@ impl<'a> RRIdManager<'a> {
         Self {
             pending_job: HashMap::new(),
             pending_timer: HashMap::new(),
+            services: HashMap::new(),
+            service_tasks: HashMap::new(),
+            listeners: HashMap::new(),
         }
     }
 
@@ -275,6 +409,31 @@ impl<'a> RRIdManager<'a> {
         self.pending_job.insert(id, promise);
     }
 
+    pub fn add_service(&mut self, id: u32, callback: Option<RJSCallback<'a>>) {
+        self.services.insert(id, callback);
+    }
+
+    /// Adds a service whose task is aborted once the service goes away.
+    pub fn add_service_task(&mut self, id: u32, callback: Option<RJSCallback<'a>>, abort: AbortHandle) {
+        self.services.insert(id, callback);
+        self.service_tasks.insert(id, abort);
+    }
+
+    pub fn del_service(&mut self, id: u32) {
+        self.services.remove(&id);
+        if let Some(abort) = self.service_tasks.remove(&id) {
+            abort.abort();
+        }
+    }
+
+    pub fn add_listener(&mut self, id: u32, callback: RJSCallback<'a>) {
+        self.listeners.insert(id, callback);
+    }
+
+    pub fn del_listener(&mut self, id: u32) {
+        self.listeners.remove(&id);
+    }
+
     pub fn handle_response(&mut self, mut resp: Option<RespType>) {
         match resp {
             Some(RespType::FsResponse(job_id, ref mut content)) | Some(RespType::GetAddrInfo(job_id, ref mut content)) => {
@@ -288,11 +447,61 @@ impl<'a> RRIdManager<'a> {
                     qruff_rtu_setup_settle_promise(promise, context);
                 }
             },
-            Some(RespType::RtuReadHoldingRegisters(job_id, content)) => {
+            Some(RespType::RtuOperation(job_id, content)) => {
                 if let Some(promise) = self.pending_job.remove(&job_id) {
                     qruff_rtu_operation_settle_promise(promise, content);
                 }
             },
+            Some(RespType::ModbusServerStarted(job_id, server)) => {
+                if server.is_err() {
+                    self.del_service(job_id);
+                }
+                if let Some(promise) = self.pending_job.remove(&job_id) {
+                    qruff_modbus_server_settle_promise(promise, server);
+                }
+            },
+            Some(RespType::ModbusServerRequest(id, request, reply)) => {
+                let result = match self.services.get(&id) {
+                    Some(Some(handler)) => qruff_modbus_server_call_handler(handler, &request),
+                    _ => Err(EXCEPTION_SLAVE_DEVICE_FAILURE),
+                };
+                let _ = reply.send(result);
+            },
+            Some(RespType::ModbusServerStopped(id)) => self.del_service(id),
+            Some(RespType::ModbusScan(job_id, hits)) => {
+                if let Some(promise) = self.pending_job.remove(&job_id) {
+                    qruff_modbus_scan_settle_promise(promise, hits);
+                }
+            },
+            Some(RespType::ModbusTrace(id, frame)) => {
+                if let Some(listener) = self.listeners.get(&id) {
+                    qruff_modbus_trace_call_listener(listener, frame);
+                }
+            },
+            Some(RespType::CmdPoll(id, poll)) => {
+                if let Some(Some(callback)) = self.services.get(&id) {
+                    let poll = unsafe { js_cmd_poll(callback.ctxt, poll) };
+                    if !unsafe { js_call_listener(callback, poll, "attach") } {
+                        self.del_service(id);
+                    }
+                }
+            },
+            Some(RespType::EndpointCmd(id, cmd)) => {
+                if let Some(Some(callback)) = self.services.get(&id) {
+                    let cmd = unsafe { js_new_json(callback.ctxt, &cmd) };
+                    if !unsafe { js_call_listener(callback, cmd, "onCmd") } {
+                        self.del_service(id);
+                    }
+                }
+            },
+            Some(RespType::EndpointNext(job_id, cmd)) => {
+                if let Some(promise) = self.pending_job.remove(&job_id) {
+                    qruff_cmd_endpoint_settle_next(promise, cmd);
+                }
+            },
+            Some(RespType::CmdPollStopped(id))
+            | Some(RespType::CmdGeneratorStopped(id))
+            | Some(RespType::CmdEndpointClosed(id)) => self.del_service(id),
             None => {}
         }
     }
@@ -308,7 +517,7 @@ impl<'a> RRIdManager<'a> {
 
     pub fn is_empty(&self) -> bool {
         if self.pending_timer.is_empty() {
-            self.pending_job.is_empty()
+            self.pending_job.is_empty() && self.services.is_empty()
         } else {
             false
         }
@@ -335,20 +544,71 @@ pub fn check_msg_queue<'a>(
                 tokio::spawn(get_addr_info(addr, resp_tx.clone(), id));
                 resoure_manager.add_promise(id, promise)
             },
-            MsgType::AddCmdGenerator(id, cmd_generator) => {
-                tokio::spawn(cmd_generator_loop(cmd_generator, id));
+            MsgType::AddCmdGenerator(id, task) => {
+                tokio::spawn(run_cmd_generator(task, resp_tx.clone(), id));
+                resoure_manager.add_service(id, None);
             },
             MsgType::CreateRtuSetup(id, config, promise) => {
                 tokio::spawn(rtu_setup(config, resp_tx.clone(), id));
                 resoure_manager.add_promise(id, promise)
             },
-            MsgType::AddRtuOperation(id, operation, promise) => {
-                tokio::spawn(rtu_operation(operation, resp_tx.clone(), id));
+            MsgType::CreateTcpSetup(id, config, promise) => {
+                tokio::spawn(tcp_setup(config, resp_tx.clone(), id));
                 resoure_manager.add_promise(id, promise)
             },
+            MsgType::AddRtuOperation(id, context, operation, options, promise) => {
+                tokio::spawn(rtu_operation(context, operation, options, resp_tx.clone(), id));
+                resoure_manager.add_promise(id, promise)
+            },
+            MsgType::CreateModbusServer(id, config, handler, promise) => {
+                tokio::spawn(modbus_server_start(config, handler.is_some(), resp_tx.clone(), id));
+                resoure_manager.add_service(id, handler);
+                resoure_manager.add_promise(id, promise)
+            },
+            MsgType::CloseModbusServer(id) => resoure_manager.del_service(id),
+            MsgType::CreateModbusGateway(id, config, promise) => {
+                tokio::spawn(modbus_gateway_start(config, resp_tx.clone(), id));
+                resoure_manager.add_service(id, None);
+                resoure_manager.add_promise(id, promise)
+            },
+            MsgType::ModbusScan(id, config, promise) => {
+                tokio::spawn(modbus_scan(config, resp_tx.clone(), id));
+                resoure_manager.add_promise(id, promise)
+            },
+            MsgType::TraceRtuContext(id, context, target) => {
+                let trace = match target {
+                    TraceTarget::Off => None,
+                    TraceTarget::Log => Some(TraceSink::Log),
+                    TraceTarget::Callback(callback) => {
+                        resoure_manager.add_listener(id, callback);
+                        Some(TraceSink::Callback(id, resp_tx.clone()))
+                    },
+                    TraceTarget::Pcap(writer) => Some(TraceSink::Pcap(Arc::new(Mutex::new(writer)))),
+                };
+                if let Some(listener) = context.set_trace(trace).and_then(|old| old.listener()) {
+                    resoure_manager.del_listener(listener);
+                }
+            },
+            MsgType::AttachCmdGenerator(id, rx, context, callback) => {
+                let (abort, registration) = AbortHandle::new_pair();
+                tokio::spawn(Abortable::new(cmd_poll_loop(rx, context, resp_tx.clone(), id), registration));
+                resoure_manager.add_service_task(id, Some(callback), abort);
+            },
+            MsgType::ListenCmdEndpoint(id, rx, callback) => {
+                let (abort, registration) = AbortHandle::new_pair();
+                tokio::spawn(Abortable::new(cmd_endpoint_listen(rx, resp_tx.clone(), id), registration));
+                resoure_manager.add_service_task(id, Some(callback), abort);
+            },
+            MsgType::IterateCmdEndpoint(rx, next) => {
+                tokio::spawn(cmd_endpoint_iterate(rx, next, resp_tx.clone()));
+            },
+            MsgType::NextEndpointCmd(id, next, promise) => {
+                resoure_manager.add_promise(id, promise);
+                let _ = next.send(id);
+            },
             MsgType::AddCmdShower(id, mut rx) => {
                 tokio::spawn(async move {
-                    while let Some(cmd) = rx.recv().await {
+                    while let Ok(Some(cmd)) = rx.recv().await {
                         println!("Got {:?}", cmd);
                     }
                 });
@@ -409,3 +669,141 @@ pub fn settle_promise_for_array_buffer<'a>(promise: RJSPromise<'a>, content: &mu
         promise.ctxt.free_value(*arg);
     }
 }
+
+/// Returns the `idx`-th call argument, or `undefined` when the caller passed fewer.
+pub fn js_arg(args: &[ffi::JSValue], idx: usize) -> ffi::JSValue {
+    args.get(idx).cloned().unwrap_or(ffi::UNDEFINED)
+}
+
+pub fn js_is_undefined(value: ffi::JSValue) -> bool {
+    Value::from(value).is_undefined()
+}
+
+pub unsafe fn js_throw_type_error(ctxt: &ContextRef, msg: &str) -> ffi::JSValue {
+    let msg = CString::new(msg.replace('\0', "")).unwrap();
+    ffi::JS_ThrowTypeError(ctxt.as_ptr(), cstr!("%s").as_ptr(), msg.as_ptr());
+    ffi::EXCEPTION
+}
+
+/// Converts `value` to a JS value through its JSON form, returning the raw (owned) JSValue.
+pub unsafe fn js_new_json<T: Serialize>(ctxt: &ContextRef, value: &T) -> ffi::JSValue {
+    let json = CString::new(serde_json::to_string(value).unwrap()).unwrap();
+    ffi::JS_ParseJSON(ctxt.as_ptr(), json.as_ptr(), json.as_bytes().len(), cstr!("<json>").as_ptr())
+}
+
+/// Converts any JS value to a Rust value through `Args`, returning the raw (owned) JSValue.
+pub fn js_new_value<T: Args>(ctxt: &ContextRef, value: T) -> ffi::JSValue {
+    let values = value.into_values(ctxt);
+    values[0]
+}
+
+pub unsafe fn js_get_property(ctxt: &ContextRef, obj: ffi::JSValue, name: &str) -> ffi::JSValue {
+    let name = CString::new(name).unwrap();
+    ffi::JS_GetPropertyStr(ctxt.as_ptr(), obj, name.as_ptr())
+}
+
+/// Sets `obj[name] = value`, taking ownership of `value`.
+pub unsafe fn js_set_property(ctxt: &ContextRef, obj: ffi::JSValue, name: &str, value: ffi::JSValue) {
+    let name = CString::new(name).unwrap();
+    ffi::JS_SetPropertyStr(ctxt.as_ptr(), obj, name.as_ptr(), value);
+}
+
+pub unsafe fn js_to_f64(ctxt: &ContextRef, value: ffi::JSValue, name: &str) -> Result<f64, Error> {
+    let mut out: f64 = 0.0;
+    if js_is_undefined(value) || ffi::JS_ToFloat64(ctxt.as_ptr(), &mut out, value) < 0 || out.is_nan() {
+        return Err(format_err!("{} must be a number", name));
+    }
+    Ok(out)
+}
+
+/// Reads an integer argument and checks it fits into `T`.
+pub unsafe fn js_to_integer<T: TryFrom<i64>>(ctxt: &ContextRef, value: ffi::JSValue, name: &str) -> Result<T, Error> {
+    let out = js_to_f64(ctxt, value, name)?;
+    if out.fract() != 0.0 || out.is_infinite() {
+        return Err(format_err!("{} must be an integer, got {}", name, out));
+    }
+    T::try_from(out as i64).map_err(|_| format_err!("{} is out of range: {}", name, out))
+}
+
+pub unsafe fn js_to_bool(ctxt: &ContextRef, value: ffi::JSValue) -> bool {
+    ffi::JS_ToBool(ctxt.as_ptr(), value) > 0
+}
+
+pub unsafe fn js_to_string(ctxt: &ContextRef, value: ffi::JSValue, name: &str) -> Result<String, Error> {
+    if js_is_undefined(value) {
+        return Err(format_err!("{} must be a string", name));
+    }
+    ctxt.to_cstring(&Value::from(value))
+        .map(|value| String::from(value.to_string_lossy()))
+        .ok_or_else(|| format_err!("{} must be a string", name))
+}
+
+/// Maps every element of a JS array through `f`.
+pub unsafe fn js_to_vec<T, F>(ctxt: &ContextRef, value: ffi::JSValue, name: &str, mut f: F) -> Result<Vec<T>, Error>
+where
+    F: FnMut(ffi::JSValue, &str) -> Result<T, Error>,
+{
+    if ffi::JS_IsArray(ctxt.as_ptr(), value) <= 0 {
+        return Err(format_err!("{} must be an array", name));
+    }
+    let length = js_get_property(ctxt, value, "length");
+    let len: u32 = js_to_integer(ctxt, length, name)?;
+    ctxt.free_value(length);
+
+    let mut out = Vec::with_capacity(len as usize);
+    for idx in 0..len {
+        let item = ffi::JS_GetPropertyUint32(ctxt.as_ptr(), value, idx);
+        let res = f(item, &format!("{}[{}]", name, idx));
+        ctxt.free_value(item);
+        out.push(res?);
+    }
+    Ok(out)
+}
+
+/// Builds a JS array from already converted (owned) values.
+pub unsafe fn js_new_array(ctxt: &ContextRef, values: Vec<ffi::JSValue>) -> ffi::JSValue {
+    let array = ffi::JS_NewArray(ctxt.as_ptr());
+    for (idx, value) in values.into_iter().enumerate() {
+        ffi::JS_SetPropertyUint32(ctxt.as_ptr(), array, idx as u32, value);
+    }
+    array
+}
+
+/// Resolves or rejects `promise` with a single (owned) value.
+pub fn settle_promise(promise: &RJSPromise, result: Result<ffi::JSValue, ffi::JSValue>) {
+    let (handle, arg) = match result {
+        Ok(value) => (&promise.resolve, value),
+        Err(value) => (&promise.reject, value),
+    };
+    let args = [arg];
+    unsafe {
+        ffi::JS_Call(
+            promise.ctxt.as_ptr(),
+            handle.raw(),
+            ffi::NULL,
+            1 as i32,
+            args.as_ptr() as *mut _,
+        );
+    }
+    promise.ctxt.free_value(arg);
+}
+
+/// Reads the optional property `name` of an options object through `f`.
+///
+/// Returns `Ok(None)` when either `opts` or the property is `undefined`.
+pub unsafe fn js_get_option<T, F>(ctxt: &ContextRef, opts: ffi::JSValue, name: &str, f: F) -> Result<Option<T>, Error>
+where
+    F: FnOnce(ffi::JSValue, &str) -> Result<T, Error>,
+{
+    if js_is_undefined(opts) {
+        return Ok(None);
+    }
+    let value = js_get_property(ctxt, opts, name);
+    let res = if js_is_undefined(value) {
+        Ok(None)
+    } else {
+        f(value, name).map(Some)
+    };
+    ctxt.free_value(value);
+    res
+}
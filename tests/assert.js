@@ -1,3 +1,6 @@
+import * as std from "std";
+import * as qruff from "qruff";
+
 export function assert(actual, expected, message) {
     if (arguments.length == 1)
         expected = true;
@@ -15,3 +18,35 @@ export function assert(actual, expected, message) {
                 (message ? " (" + message + ")" : ""));
 }
 
+
+// ends the run with a failure, for the promise of a test that rejected
+export function fail(err) {
+    console.log('error is', err);
+    std.exit(1);
+}
+
+export let sleep = (ms) => new Promise((resolve) => qruff.setTimeout(resolve, ms));
+
+// calls f, which must throw a TypeError, the error is returned for further checks
+export function throwsTypeError(f, what) {
+    try {
+        f();
+    } catch (err) {
+        assert(err instanceof TypeError, true, String(err));
+        return err;
+    }
+    throw Error(what + ' should throw');
+}
+
+// awaits promise, which must reject with an error of `code` when given, the error is returned for further checks
+export async function rejects(promise, code) {
+    try {
+        await promise;
+    } catch (err) {
+        assert(err instanceof Error, true, String(err));
+        if (code !== undefined)
+            assert(err.code, code, String(err));
+        return err;
+    }
+    throw Error('the promise should reject' + (code !== undefined ? ' with ' + code : ''));
+}
@@ -0,0 +1,182 @@
+#!/usr/bin/env python3
+"""Minimal Modbus TCP server stand-in for tests/test_tcp.js.
+
+Serves 1000 holding/input registers and coils/discrete inputs from memory,
+holding register N is initialised to N. Requests to unit 99 are answered
+late, after SLOW_DELAY seconds. Only the standard library is used.
+With `--rtu` the socket carries RTU frames instead, like a terminal server
+in front of a serial device answering as unit 1.
+
+    python3 tests/modbus_tcp_standin.py [--rtu] [port]
+"""
+import socketserver
+import struct
+import sys
+import time
+
+SIZE = 1000
+holding = list(range(SIZE))
+inputs = [SIZE - i for i in range(SIZE)]
+coils = [i % 2 == 0 for i in range(SIZE)]
+discrete = [i % 3 == 0 for i in range(SIZE)]
+device_id = {
+    0x00: b"qruff", 0x01: b"QR-1", 0x02: b"1.2.3",
+    0x03: b"https://example.com", 0x04: b"stand-in", 0x05: b"QR-1 test", 0x06: b"modbus_tcp_standin",
+    0x80: b"private",
+}
+# objects per read device identification answer, small enough to need several
+DEVICE_ID_PAGE = 3
+SLOW_UNIT = 99
+SLOW_DELAY = 0.3
+
+
+def pack_bits(bits):
+    out = bytearray((len(bits) + 7) // 8)
+    for i, bit in enumerate(bits):
+        if bit:
+            out[i // 8] |= 1 << (i % 8)
+    return bytes(out)
+
+
+def unpack_bits(data, count):
+    return [bool(data[i // 8] & (1 << (i % 8))) for i in range(count)]
+
+
+def exception(fc, code):
+    return struct.pack(">BB", fc | 0x80, code)
+
+
+def handle(pdu):
+    fc = pdu[0]
+    try:
+        if fc in (1, 2):
+            addr, cnt = struct.unpack(">HH", pdu[1:5])
+            table = coils if fc == 1 else discrete
+            if addr + cnt > SIZE:
+                return exception(fc, 2)
+            data = pack_bits(table[addr:addr + cnt])
+            return struct.pack(">BB", fc, len(data)) + data
+        if fc in (3, 4):
+            addr, cnt = struct.unpack(">HH", pdu[1:5])
+            table = holding if fc == 3 else inputs
+            if addr + cnt > SIZE:
+                return exception(fc, 2)
+            return struct.pack(">BB", fc, cnt * 2) + struct.pack(">%dH" % cnt, *table[addr:addr + cnt])
+        if fc == 5:
+            addr, value = struct.unpack(">HH", pdu[1:5])
+            coils[addr] = value == 0xFF00
+            return pdu[:5]
+        if fc == 6:
+            addr, value = struct.unpack(">HH", pdu[1:5])
+            holding[addr] = value
+            return pdu[:5]
+        if fc == 15:
+            addr, cnt, _ = struct.unpack(">HHB", pdu[1:6])
+            coils[addr:addr + cnt] = unpack_bits(pdu[6:], cnt)
+            return pdu[:5]
+        if fc == 16:
+            addr, cnt, _ = struct.unpack(">HHB", pdu[1:6])
+            holding[addr:addr + cnt] = struct.unpack(">%dH" % cnt, pdu[6:6 + cnt * 2])
+            return pdu[:5]
+        if fc == 22:
+            addr, and_mask, or_mask = struct.unpack(">HHH", pdu[1:7])
+            holding[addr] = (holding[addr] & and_mask) | (or_mask & ~and_mask & 0xFFFF)
+            return pdu[:7]
+        if fc == 23:
+            raddr, rcnt, waddr, wcnt, _ = struct.unpack(">HHHHB", pdu[1:10])
+            holding[waddr:waddr + wcnt] = struct.unpack(">%dH" % wcnt, pdu[10:10 + wcnt * 2])
+            return struct.pack(">BB", fc, rcnt * 2) + struct.pack(">%dH" % rcnt, *holding[raddr:raddr + rcnt])
+        if fc == 8:
+            sub, data = struct.unpack(">HH", pdu[1:5])
+            # return query data echoes, every counter reads 42
+            return pdu[:5] if sub == 0 else struct.pack(">BHH", fc, sub, 42)
+        if fc == 11:
+            return struct.pack(">BHH", fc, 0, 7)
+        if fc == 17:
+            data = bytes([0x2A, 0xFF]) + b"qruff"
+            return bytes([fc, len(data)]) + data
+        if fc == 43 and pdu[1] == 0x0E:
+            code, first = pdu[2], pdu[3]
+            last = {1: 0x02, 2: 0x06, 3: 0xFF, 4: first}.get(code)
+            if last is None:
+                return exception(fc, 3)
+            if first not in device_id:
+                return exception(fc, 2)
+            ids = [i for i in sorted(device_id) if first <= i <= last]
+            page, rest = ids[:DEVICE_ID_PAGE], ids[DEVICE_ID_PAGE:]
+            if code == 4:
+                page, rest = ids[:1], []
+            rsp = bytes([fc, 0x0E, code, 0x83, 0xFF if rest else 0, rest[0] if rest else 0, len(page)])
+            for i in page:
+                rsp += bytes([i, len(device_id[i])]) + device_id[i]
+            return rsp
+    except (IndexError, struct.error):
+        return exception(fc, 3)
+    return exception(fc, 1)
+
+
+def crc16(data):
+    crc = 0xFFFF
+    for byte in data:
+        crc ^= byte
+        for _ in range(8):
+            crc = (crc >> 1) ^ 0xA001 if crc & 1 else crc >> 1
+    return struct.pack("<H", crc)
+
+
+def rtu_request_len(head):
+    if len(head) < 2:
+        return None
+    fc = head[1]
+    if fc in (15, 16):
+        return 9 + head[6] if len(head) > 6 else None
+    if fc == 22:
+        return 10
+    if fc == 23:
+        return 13 + head[10] if len(head) > 10 else None
+    if fc in (11, 17):
+        return 4
+    if fc == 43:
+        return 7
+    return 8
+
+
+class Handler(socketserver.BaseRequestHandler):
+    def handle(self):
+        while True:
+            header = self.request.recv(7)
+            if len(header) < 7:
+                return
+            tid, pid, length, unit = struct.unpack(">HHHB", header)
+            pdu = self.request.recv(length - 1)
+            rsp = handle(pdu)
+            if unit == SLOW_UNIT:
+                time.sleep(SLOW_DELAY)
+            self.request.sendall(struct.pack(">HHHB", tid, pid, len(rsp) + 1, unit) + rsp)
+
+
+class RtuHandler(socketserver.BaseRequestHandler):
+    def handle(self):
+        frame = b""
+        while True:
+            length = rtu_request_len(frame)
+            if length is not None and len(frame) >= length:
+                request, frame = frame[:length], frame[length:]
+                # other units on the line and garbled frames go unanswered
+                if request[0] == 1 and crc16(request[:-2]) == request[-2:]:
+                    rsp = bytes([1]) + handle(request[1:-2])
+                    self.request.sendall(rsp + crc16(rsp))
+                continue
+            data = self.request.recv(256)
+            if not data:
+                return
+            frame += data
+
+
+if __name__ == "__main__":
+    args = [arg for arg in sys.argv[1:] if arg != "--rtu"]
+    port = int(args[0]) if args else 5502
+    handler = RtuHandler if "--rtu" in sys.argv else Handler
+    socketserver.ThreadingTCPServer.allow_reuse_address = True
+    with socketserver.ThreadingTCPServer(("127.0.0.1", port), handler) as server:
+        server.serve_forever()
@@ -0,0 +1,127 @@
+import * as qruff from "qruff";
+import * as std from "std";
+import { assert, fail, sleep, throwsTypeError, rejects } from "./assert.js";
+
+function listen(generator, options) {
+    let endpoint = qruff.createCmdEndpoint();
+    let ids = [];
+    generator.endpoint(endpoint, options);
+    endpoint.onCmd((cmd) => { ids.push(cmd.id); });
+    return [endpoint, ids];
+}
+
+// an endpoint nobody holds, subscribed without ever taking a command
+function forget(generator) {
+    generator.endpoint(qruff.createCmdEndpoint(), { policy: 'block', capacity: 2 });
+}
+
+// one generator feeding several endpoints, each with its own filter and policy
+(async () => {
+    let generator = qruff.createCmdGenerator(JSON.stringify([
+        { id: 'a', reg_offset: 0, reg_len: 1, interval: 100 },
+        { id: 'b', reg_offset: 1, reg_len: 1, interval: 100, phase: 50 },
+    ]));
+    let [all, allIds] = listen(generator);
+    let [onlyB, onlyBIds] = listen(generator, { ids: ['b'] });
+    throwsTypeError(() => generator.endpoint(all), 'subscribing twice');
+    throwsTypeError(() => generator.endpoint(qruff.createCmdEndpoint(), { policy: 'newest' }), 'an unknown policy');
+    throwsTypeError(() => generator.endpoint(qruff.createCmdEndpoint(), { capacity: 0 }), 'an empty queue');
+    throwsTypeError(() => generator.endpoint({}), 'a plain object');
+
+    generator.run();
+    await sleep(330);
+    assert(allIds.slice(0, 4).join(), 'a,b,a,b');
+    assert(onlyBIds.length >= 3, true, 'b reached ' + onlyBIds.length + ' times');
+    assert(onlyBIds.every((id) => id == 'b'), true);
+
+    // an unsubscribed endpoint gets no more, and can subscribe again
+    let other = qruff.createCmdGenerator(JSON.stringify([
+        { id: 'c', reg_offset: 0, reg_len: 1, interval: 100 },
+    ]));
+    assert(other.unsubscribe(onlyB), false);
+    assert(generator.unsubscribe(onlyB), true);
+    assert(generator.unsubscribe(onlyB), false);
+    await sleep(50);
+    let seenB = onlyBIds.length;
+    let seenAll = allIds.length;
+    await sleep(250);
+    assert(onlyBIds.length, seenB);
+    assert(allIds.length >= seenAll + 4, true);
+    generator.endpoint(onlyB, { ids: ['a'] });
+    let onlyAIds = [];
+    onlyB.onCmd((cmd) => { onlyAIds.push(cmd.id); });
+    await sleep(250);
+    assert(onlyAIds.length >= 2, true, 'a reached ' + onlyAIds.length + ' times');
+    assert(onlyAIds.every((id) => id == 'a'), true);
+    generator.stop();
+
+    // dropOldest keeps the latest commands of a subscriber that doesn't keep up
+    let lossy = qruff.createCmdGenerator(JSON.stringify([
+        { id: 'first', reg_offset: 0, reg_len: 1, interval: 100000 },
+        { id: 'tick', reg_offset: 0, reg_len: 1, interval: 20, phase: 10 },
+    ]));
+    let lagging = qruff.createCmdEndpoint();
+    lossy.endpoint(lagging, { policy: 'dropOldest', capacity: 4 });
+    lossy.run();
+    await sleep(300);
+    lossy.stop();
+    let kept = [];
+    for await (const cmd of lagging) {
+        kept.push(cmd.id);
+    }
+    assert(kept.length, 4);
+    assert(kept.every((id) => id == 'tick'), true);
+
+    // error ends the subscription of a subscriber that doesn't keep up
+    let strict = qruff.createCmdGenerator(JSON.stringify([
+        { id: 'tick', reg_offset: 0, reg_len: 1, interval: 20 },
+    ]));
+    let overflowing = qruff.createCmdEndpoint();
+    strict.endpoint(overflowing, { policy: 'error', capacity: 2 });
+    strict.run();
+    await sleep(200);
+    let taken = 0;
+    let err = await rejects((async () => {
+        for await (const cmd of overflowing) {
+            taken++;
+        }
+    })());
+    assert(err.message.includes('behind'), true, err.message);
+    assert(taken, 2);
+    strict.stop();
+
+    // block holds the generator, and every other subscriber, back
+    let blocking = qruff.createCmdGenerator(JSON.stringify([
+        { id: 'tick', reg_offset: 0, reg_len: 1, interval: 20 },
+    ]));
+    let blocked = qruff.createCmdEndpoint();
+    blocking.endpoint(blocked, { policy: 'block', capacity: 2 });
+    let [watcher, watched] = listen(blocking);
+    blocking.run();
+    await sleep(300);
+    assert(watched.length <= 4, true, 'watcher got ' + watched.length + ' commands');
+    let count = 0;
+    for await (const cmd of blocked) {
+        if (++count == 3) {
+            break;
+        }
+    }
+    let released = watched.length;
+    await sleep(200);
+    assert(watched.length >= released + 5, true, 'watcher got ' + (watched.length - released) + ' more commands');
+    blocking.stop();
+
+    // a collected endpoint unsubscribes instead of holding the generator back
+    let forgetting = qruff.createCmdGenerator(JSON.stringify([
+        { id: 'tick', reg_offset: 0, reg_len: 1, interval: 20 },
+    ]));
+    forget(forgetting);
+    let [survivor, survived] = listen(forgetting);
+    std.gc();
+    forgetting.run();
+    await sleep(300);
+    assert(survived.length >= 10, true, 'survivor got ' + survived.length + ' commands');
+    forgetting.stop();
+
+    console.log('test_cmd_broadcast done');
+})().catch(fail);
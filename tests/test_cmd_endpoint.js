@@ -0,0 +1,75 @@
+import * as qruff from "qruff";
+import { assert, fail, sleep, throwsTypeError } from "./assert.js";
+
+function generatorWithEndpoint(cmds) {
+    let generator = qruff.createCmdGenerator(JSON.stringify(cmds));
+    let endpoint = qruff.createCmdEndpoint();
+    generator.endpoint(endpoint);
+    return [generator, endpoint];
+}
+
+// the commands of a generator reach JS through onCmd and for await
+(async () => {
+    let [generator, endpoint] = generatorWithEndpoint([
+        { id: 'temperature', reg_offset: 3, reg_len: 1, interval: 100, unitOfMeasure: 'C' },
+    ]);
+    let seen = [];
+    endpoint.onCmd((cmd) => { seen.push(cmd); });
+    throwsTypeError(() => endpoint.onCmd(() => {}), 'a second onCmd');
+    generator.run();
+    await sleep(250);
+    assert(seen.length >= 2, true, 'got ' + seen.length + ' commands');
+    assert(seen[0].id, 'temperature');
+    assert(seen[0].reg_offset, 3);
+    assert(seen[0].unitOfMeasure, 'C');
+
+    // returning false stops the delivery
+    let [stopping, stoppingEndpoint] = generatorWithEndpoint([
+        { id: 'once', reg_offset: 0, reg_len: 1, interval: 50 },
+    ]);
+    let calls = 0;
+    stoppingEndpoint.onCmd(() => { calls++; return false; });
+    // the detached endpoint unsubscribes, it doesn't hold the others back
+    let watcher = qruff.createCmdEndpoint();
+    stopping.endpoint(watcher, { policy: 'block', capacity: 1 });
+    let watched = 0;
+    watcher.onCmd(() => { watched++; });
+    stopping.run();
+    await sleep(200);
+    assert(calls, 1);
+    assert(watched >= 3, true, 'watcher got ' + watched + ' commands');
+    assert(stopping.unsubscribe(stoppingEndpoint), false);
+    stopping.stop();
+
+    // the iteration ends once the generator is stopped
+    let [iterated, iteratedEndpoint] = generatorWithEndpoint([
+        { id: 'a', reg_offset: 0, reg_len: 1, interval: 100 },
+        { id: 'b', reg_offset: 1, reg_len: 1, interval: 100, phase: 50 },
+    ]);
+    iterated.run();
+    qruff.setTimeout(() => iterated.stop(), 420);
+    let ids = [];
+    for await (const cmd of iteratedEndpoint) {
+        ids.push(cmd.id);
+    }
+    assert(ids.slice(0, 4).join(), 'a,b,a,b');
+    assert((await iteratedEndpoint.next()).done, true);
+
+    // break gives the iteration up
+    let [broken, brokenEndpoint] = generatorWithEndpoint([
+        { id: 'c', reg_offset: 0, reg_len: 1, interval: 50 },
+    ]);
+    broken.run();
+    let count = 0;
+    for await (const cmd of brokenEndpoint) {
+        assert(cmd.id, 'c');
+        if (++count == 3) {
+            break;
+        }
+    }
+    assert((await brokenEndpoint.next()).done, true);
+    broken.stop();
+
+    generator.stop();
+    console.log('test_cmd_endpoint done');
+})().catch(fail);
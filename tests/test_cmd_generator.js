@@ -25,4 +25,5 @@ cmd_generator.endpoint(my_endpoint);
 //
 console.log(cmd_generator.run());
 my_endpoint.show();
+setTimeout(() => cmd_generator.stop(), 5000);
 //console.log(cmd_generator.run());
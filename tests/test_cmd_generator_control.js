@@ -0,0 +1,48 @@
+import * as qruff from "qruff";
+import { assert, fail, sleep, throwsTypeError } from "./assert.js";
+
+// stop, pause and resume of a generator polling a simulated device
+(async () => {
+    let sim = await qruff.modbusSimulator(JSON.stringify({ holdingRegisters: 4 }));
+    let rtu = await qruff.rtu_setup(sim.path, { baud: 115200, unit: 1, timeoutMs: 300, retries: 0 });
+
+    let generator = qruff.createCmdGenerator(JSON.stringify([
+        { id: 'tick', reg_offset: 0, reg_len: 1, interval: 100 },
+    ]));
+    let polls = 0;
+    generator.attach(rtu, () => { polls++; });
+    assert(generator.isRunning(), false);
+    throwsTypeError(() => generator.pause(), 'pausing before run');
+
+    generator.run();
+    assert(generator.isRunning(), true);
+    await sleep(350);
+    assert(polls >= 3, true, 'polled ' + polls + ' times');
+
+    generator.pause();
+    assert(generator.isRunning(), false);
+    await sleep(50);
+    let paused = polls;
+    await sleep(300);
+    assert(polls, paused);
+
+    generator.resume();
+    assert(generator.isRunning(), true);
+    await sleep(250);
+    assert(polls >= paused + 2, true, 'polled ' + (polls - paused) + ' times after resume');
+
+    generator.stop();
+    assert(generator.isRunning(), false);
+    await sleep(50);
+    let stopped = polls;
+    await sleep(300);
+    assert(polls, stopped);
+    // stopping twice is harmless, running again isn't possible
+    generator.stop();
+    throwsTypeError(() => generator.resume(), 'resuming a stopped generator');
+    throwsTypeError(() => generator.run(), 'running a stopped generator');
+    sim.close();
+
+    // the attached callback ended with the generator, so the script ends here
+    console.log('test_cmd_generator_control done');
+})().catch(fail);
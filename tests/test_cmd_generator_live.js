@@ -0,0 +1,58 @@
+import * as qruff from "qruff";
+import { assert, fail, sleep, throwsTypeError } from "./assert.js";
+
+// commands added, removed and updated while the generator runs
+(async () => {
+    let sim = await qruff.modbusSimulator(JSON.stringify({ holdingRegisters: 4 }));
+    let rtu = await qruff.rtu_setup(sim.path, { baud: 115200, unit: 1, timeoutMs: 300, retries: 0 });
+
+    let generator = qruff.createCmdGenerator(JSON.stringify([
+        { id: 'slow', reg_offset: 0, reg_len: 1, interval: 1000 },
+    ]));
+    let polls = {};
+    generator.attach(rtu, (poll) => { polls[poll.id] = (polls[poll.id] || 0) + 1; });
+
+    // before run only the list changes
+    generator.addCmd({ id: 'fast', reg_offset: 1, reg_len: 1, interval: 100 });
+    assert(generator.listCmds().map((cmd) => cmd.id).join(), 'slow,fast');
+    throwsTypeError(() => generator.addCmd({ id: 'fast', reg_offset: 2, reg_len: 1, interval: 100 }), 'a duplicate id');
+    throwsTypeError(() => generator.addCmd('{"id": "bad", "reg_offset": 0, "reg_len": 0, "interval": 100}'), 'an empty read');
+    throwsTypeError(() => generator.updateCmd('slow', { id: 'renamed' }), 'changing an id');
+    throwsTypeError(() => generator.updateCmd('missing', { interval: 100 }), 'updating a missing command');
+    throwsTypeError(() => generator.updateCmd('slow', { interval: 0 }), 'a zero interval');
+    assert(generator.listCmds()[0].interval, 1000);
+
+    generator.run();
+    await sleep(450);
+    assert(polls.fast >= 3, true, 'fast polled ' + polls.fast + ' times');
+    assert(polls.slow, 1);
+
+    // removal stops the polls of that command only
+    assert(generator.removeCmd('fast'), true);
+    assert(generator.removeCmd('fast'), false);
+    await sleep(50);
+    let fast = polls.fast;
+    await sleep(300);
+    assert(polls.fast, fast);
+
+    // a command added while running fires after its phase
+    generator.addCmd(JSON.stringify({ id: 'late', reg_offset: 2, reg_len: 1, interval: 100, phase: 50 }));
+    await sleep(20);
+    assert(polls.late, undefined);
+    await sleep(300);
+    assert(polls.late >= 2, true, 'late polled ' + polls.late + ' times');
+
+    // a shorter interval counts from the last firing
+    generator.updateCmd('slow', { interval: 100, unitOfMeasure: 'V' });
+    let cmds = generator.listCmds();
+    assert(cmds.length, 2);
+    assert(cmds[0].interval, 100);
+    assert(cmds[0].unitOfMeasure, 'V');
+    let slow = polls.slow;
+    await sleep(350);
+    assert(polls.slow >= slow + 2, true, 'slow polled ' + (polls.slow - slow) + ' times after update');
+
+    generator.stop();
+    sim.close();
+    console.log('test_cmd_generator_live done');
+})().catch(fail);
@@ -0,0 +1,62 @@
+import * as qruff from "qruff";
+import { assert, fail, sleep, throwsTypeError } from "./assert.js";
+
+// a generator polling a simulated device
+(async () => {
+    let sim = await qruff.modbusSimulator(JSON.stringify({
+        holdingRegisters: [20, 21, 22, 23, 24, 25],
+        inputRegisters: [0, 2300],
+        coils: [false, true, false],
+    }));
+    let rtu = await qruff.rtu_setup(sim.path, { baud: 9600, unit: 1, timeoutMs: 300, retries: 0 });
+
+    let generator = qruff.createCmdGenerator(JSON.stringify([
+        { id: 'temperature', reg_offset: 3, reg_len: 1, interval: 1000 },
+        { id: 'humidity', reg_offset: 4, reg_len: 2, interval: 2000 },
+        { id: 'missing', reg_offset: 100, reg_len: 1, interval: 2000 },
+        {
+            id: 'power', reg_offset: 0, reg_len: 2, interval: 2000,
+            function: 'input', dataType: 'uint32', scale: 0.5, offset: 10, unitOfMeasure: 'W',
+        },
+        { id: 'relay', reg_offset: 1, reg_len: 2, interval: 2000, function: 'coil' },
+    ]));
+    let polls = [];
+    generator.attach(rtu, (poll) => {
+        polls.push(poll);
+        if (polls.length == 7) {
+            sim.close();
+            return false;
+        }
+    });
+    throwsTypeError(() => generator.attach(rtu, () => {}, { policy: 'newest' }), 'an unknown policy');
+    generator.run();
+
+    // every command has been read at least once after two seconds
+    await sleep(3000);
+    assert(polls.length, 7);
+    let temperature = polls.find((poll) => poll.id == 'temperature');
+    assert(temperature.value.join(), '23');
+    let humidity = polls.find((poll) => poll.id == 'humidity');
+    assert(humidity.value.join(), '24,25');
+    let missing = polls.find((poll) => poll.id == 'missing');
+    assert(missing.value, undefined);
+    assert(missing.error.code, 'ILLEGAL_DATA_ADDRESS');
+    let power = polls.find((poll) => poll.id == 'power');
+    assert(power.value.join(), '1160');
+    assert(power.unitOfMeasure, 'W');
+    let relay = polls.find((poll) => poll.id == 'relay');
+    assert(relay.value.join(), 'true,false');
+    assert(relay.unitOfMeasure, undefined);
+
+    // profiles are checked up front
+    for (let cmd of [
+        { id: 'odd', reg_offset: 0, reg_len: 3, interval: 1000, dataType: 'float32' },
+        { id: 'coil', reg_offset: 0, reg_len: 1, interval: 1000, function: 'coil', dataType: 'int16' },
+        { id: 'table', reg_offset: 0, reg_len: 1, interval: 1000, function: 'holdings' },
+        { id: 'broadcast', reg_offset: 0, reg_len: 1, interval: 1000, unit: 0 },
+    ]) {
+        throwsTypeError(() => qruff.createCmdGenerator(JSON.stringify([cmd])), cmd.id);
+    }
+
+    console.log('test_cmd_poll done');
+})().catch(fail);
@@ -0,0 +1,40 @@
+import * as qruff from "qruff";
+import { assert, fail, sleep, throwsTypeError } from "./assert.js";
+
+// per command deadlines, polled off a simulated device
+(async () => {
+    let sim = await qruff.modbusSimulator(JSON.stringify({ holdingRegisters: 4 }));
+    let rtu = await qruff.rtu_setup(sim.path, { baud: 115200, unit: 1, timeoutMs: 300, retries: 0 });
+
+    let generator = qruff.createCmdGenerator(JSON.stringify([
+        { id: 'fast', reg_offset: 0, reg_len: 1, interval: 100 },
+        { id: 'slow', reg_offset: 1, reg_len: 1, interval: 300, phase: 150, jitter: 20 },
+    ]));
+    let start = Date.now();
+    let fired = { fast: [], slow: [] };
+    generator.attach(rtu, (poll) => {
+        fired[poll.id].push(Date.now() - start);
+        if (Date.now() - start >= 1000) {
+            sim.close();
+            return false;
+        }
+    });
+    generator.run();
+    await sleep(1200);
+
+    // the deadlines themselves are checked by the unit tests of CmdScheduler
+    assert(fired.fast.length >= 3, true, 'fast fired ' + fired.fast.length);
+    assert(fired.slow.length >= 1, true, 'slow fired ' + fired.slow.length);
+    assert(fired.slow.length < fired.fast.length, true, 'slow fired ' + fired.slow.length + ' times, fast ' + fired.fast.length);
+
+    for (let cmd of [
+        { id: 'zero', reg_offset: 0, reg_len: 1, interval: 0 },
+        { id: 'negative', reg_offset: 0, reg_len: 1, interval: -5 },
+        { id: 'jitter', reg_offset: 0, reg_len: 1, interval: 100, jitter: 100 },
+        { id: 'forever', reg_offset: 0, reg_len: 1, interval: 1e12 },
+    ]) {
+        throwsTypeError(() => qruff.createCmdGenerator(JSON.stringify([cmd])), cmd.id);
+    }
+
+    console.log('test_cmd_scheduler done');
+})().catch(fail);
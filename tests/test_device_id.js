@@ -0,0 +1,49 @@
+import * as qruff from "qruff";
+import { assert, fail, rejects } from "./assert.js";
+
+// identification and diagnostics over modbus TCP and tunneled RTU, needs
+// `python3 tests/modbus_tcp_standin.py 5502` and `python3 tests/modbus_tcp_standin.py --rtu 5503`
+async function check(ctx) {
+    // the stand-in answers with 3 objects a page, the regular set takes three requests
+    let id = await ctx.read_device_identification(2);
+    assert(id.conformityLevel, 0x83);
+    assert(id.vendorName, 'qruff');
+    assert(id.productCode, 'QR-1');
+    assert(id.revision, '1.2.3');
+    assert(id.userApplicationName, 'modbus_tcp_standin');
+    assert(Object.keys(id.objects).length, 7);
+
+    let basic = await ctx.read_device_identification();
+    assert(Object.keys(basic.objects).length, 3);
+    assert(basic.modelName, undefined);
+    let extended = await ctx.read_device_identification(3);
+    assert(extended.objects[128], 'private');
+    let single = await ctx.read_device_identification(4, 4);
+    assert(single.productName, 'stand-in');
+    assert(Object.keys(single.objects).length, 1);
+
+    let err = await rejects(ctx.read_device_identification(4, 0x90), 'ILLEGAL_DATA_ADDRESS');
+    assert(err.functionCode, 0x2B);
+
+    let server = await ctx.report_server_id();
+    assert(server.serverId, 0x2A);
+    assert(server.running, true);
+    assert(new Uint8Array(server.additionalData).length, 5);
+
+    let echo = await ctx.diagnostics(0, 0xA537);
+    assert(echo.subFunction, 0);
+    assert(echo.data, 0xA537);
+    let messages = await ctx.diagnostics(0x0B);
+    assert(messages.data, 42);
+
+    let counter = await ctx.get_comm_event_counter();
+    assert(counter.busy, false);
+    assert(counter.eventCount, 7);
+}
+
+(async () => {
+    await check(await qruff.tcp_setup('127.0.0.1', 5502, { timeoutMs: 500, retries: 0 }));
+    await check(await qruff.tcp_setup('127.0.0.1', 5503, { framing: 'rtu', timeoutMs: 500, retries: 0 }));
+
+    console.log('test_device_id done');
+})().catch(fail);
@@ -0,0 +1,27 @@
+import * as qruff from "qruff";
+import { assert, fail, throwsTypeError, rejects } from "./assert.js";
+
+// modbus ASCII over a pty pair, runs like test_modbus_server_rtu.js:
+// `qruff tests/test_modbus_ascii.js /tmp/qruff-slave /tmp/qruff-master`
+(async () => {
+    let [slavePath, masterPath] = scriptArgs.slice(1);
+    let line = { baud: 9600, dataBits: 7, parity: 'even', framing: 'ascii' };
+
+    // 7 data bits can't carry RTU frames
+    throwsTypeError(() => qruff.rtu_setup(masterPath, { baud: 9600, dataBits: 7, parity: 'even' }), 'RTU with 7 data bits');
+
+    let server = await qruff.modbusServer(Object.assign({ rtu: slavePath, unit: 17, coils: 8, holdingRegisters: [0x6B, 0, 0x0300] }, line));
+    let ascii = await qruff.rtu_setup(masterPath, Object.assign({ unit: 17, timeoutMs: 500, retries: 0 }, line));
+
+    let regs = new DataView(await ascii.read_holding_registers(0, 3));
+    assert(regs.getUint16(0), 0x6B);
+    assert(regs.getUint16(4), 0x0300);
+    await ascii.write_multiple_coils(3, [true, false, true]);
+    assert(server.get('coils', 3, 3).join(), 'true,false,true');
+    assert((await ascii.read_coils(3, 3)).join(), 'true,false,true');
+
+    await rejects(ascii.read_holding_registers(100, 1), 'ILLEGAL_DATA_ADDRESS');
+    server.close();
+
+    console.log('test_modbus_ascii done');
+})().catch(fail);
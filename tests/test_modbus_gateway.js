@@ -0,0 +1,38 @@
+import * as qruff from "qruff";
+import { assert, fail, rejects } from "./assert.js";
+
+// gateway -> context -> server, all over loopback; a serial line works the
+// same with `rtu: path` instead of `context`
+(async () => {
+    let device = await qruff.modbusServer({
+        tcp: 0,
+        host: '127.0.0.1',
+        holdingRegisters: [100, 101, 102, 103],
+        handler(req) {
+            if (req.unit != 3)
+                throw Object.assign(new Error('wrong unit'), { exceptionCode: 4 });
+        },
+    });
+    let bus = await qruff.tcp_setup('127.0.0.1', device.port, { timeoutMs: 300, retries: 0 });
+    let gateway = await qruff.modbusGateway({ tcp: 0, host: '127.0.0.1', context: bus, unitMap: [[1, 3]] });
+
+    let client = await qruff.tcp_setup('127.0.0.1', gateway.port, { unit: 1, timeoutMs: 1000, retries: 0 });
+    let regs = new DataView(await client.read_holding_registers(1, 2));
+    assert(regs.getUint16(0), 101);
+    assert(regs.getUint16(2), 102);
+
+    // concurrent requests queue up on the one context
+    await Promise.all([
+        client.write_single_register(0, 1),
+        client.write_single_register(1, 2),
+        client.write_single_register(2, 3),
+    ]);
+    assert(device.get('holdingRegisters', 0, 3), [1, 2, 3]);
+
+    await rejects(client.read_holding_registers(0, 1, { unit: 2 }), 'GATEWAY_PATH_UNAVAILABLE');
+    await rejects(client.read_holding_registers(10, 1), 'ILLEGAL_DATA_ADDRESS');
+
+    gateway.close();
+    device.close();
+    console.log('test_modbus_gateway done');
+})().catch(fail);
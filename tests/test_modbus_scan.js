@@ -0,0 +1,39 @@
+import * as qruff from "qruff";
+import { assert, fail, throwsTypeError } from "./assert.js";
+
+// scans a pty pair, runs like test_modbus_server_rtu.js:
+// `qruff tests/test_modbus_scan.js /tmp/qruff-slave /tmp/qruff-master`
+(async () => {
+    let [slavePath, masterPath] = scriptArgs.slice(1);
+    let refuse = false;
+    let server = await qruff.modbusServer({
+        rtu: slavePath,
+        unit: 5,
+        holdingRegisters: 4,
+        handler(req) {
+            if (refuse) {
+                let err = new Error('gone');
+                err.code = 'ILLEGAL_DATA_ADDRESS';
+                throw err;
+            }
+        },
+    });
+
+    // a pty ignores the line settings, so the device answers at every one of them
+    let hits = await qruff.modbusScan(masterPath, { bauds: [9600, 19200], parities: ['even', 'none'], units: '1-8', timeoutMs: 100 });
+    assert(hits.length, 4);
+    assert(hits.every((hit) => hit.unit === 5 && hit.exceptionCode === undefined), true);
+    assert(hits.map((hit) => hit.baud + hit.parity).join(), '9600even,9600none,19200even,19200none');
+
+    // answering with an exception still gives the device away
+    refuse = true;
+    hits = await qruff.modbusScan(masterPath, { units: [4, 5, 6], parities: ['none'] });
+    assert(hits.length, 1);
+    assert(hits[0].unit, 5);
+    assert(hits[0].exceptionCode, 2);
+    server.close();
+
+    throwsTypeError(() => qruff.modbusScan(masterPath, { units: '0-3' }), 'unit 0');
+
+    console.log('test_modbus_scan done');
+})().catch(fail);
@@ -0,0 +1,64 @@
+import * as qruff from "qruff";
+import { assert, fail, rejects } from "./assert.js";
+
+// serves itself over loopback, port 0 picks a free port
+(async () => {
+    let server = await qruff.modbusServer({
+        tcp: 0,
+        host: '127.0.0.1',
+        holdingRegisters: [0, 1, 2, 3, 4, 5, 6, 7],
+        inputRegisters: 4,
+        coils: 8,
+    });
+    assert(server.port > 0);
+
+    let tcp = await qruff.tcp_setup('127.0.0.1', server.port, { timeoutMs: 500, retries: 0 });
+    let regs = new DataView(await tcp.read_holding_registers(2, 2));
+    assert(regs.getUint16(0), 2);
+    assert(regs.getUint16(2), 3);
+
+    await tcp.write_multiple_registers(4, [40, 50]);
+    assert(server.get('holdingRegisters', 4, 2), [40, 50]);
+    await tcp.masked_write_register(0, 0x00f2, 0x0025);
+    assert(server.get('holdingRegisters', 0, 1)[0], 0x05);
+
+    server.set('inputRegisters', 0, [7, 8]);
+    let input = new DataView(await tcp.read_input_registers(0, 2));
+    assert(input.getUint16(2), 8);
+
+    await tcp.write_single_coil(3, true);
+    assert(server.get('coils', 0, 4), [false, false, false, true]);
+
+    await rejects(tcp.read_holding_registers(6, 4), 'ILLEGAL_DATA_ADDRESS');
+    server.close();
+
+    // a handler sees every request first, `undefined` falls back to the tables
+    let seen = [];
+    let handled = await qruff.modbusServer({
+        tcp: 0,
+        host: '127.0.0.1',
+        holdingRegisters: 4,
+        handler(req) {
+            seen.push(req.functionCode);
+            if (req.functionCode == 4)
+                return [req.unit, req.address, req.count];
+            if (req.address == 99) {
+                let err = new Error('not here');
+                err.code = 'ILLEGAL_DATA_VALUE';
+                throw err;
+            }
+        },
+    });
+    tcp = await qruff.tcp_setup('127.0.0.1', handled.port, { unit: 3, timeoutMs: 500, retries: 0 });
+    input = new DataView(await tcp.read_input_registers(10, 3));
+    assert(input.getUint16(0), 3);
+    assert(input.getUint16(2), 10);
+    await tcp.write_single_register(1, 11);
+    assert(handled.get('holdingRegisters', 1, 1)[0], 11);
+    let err = await rejects(tcp.write_single_register(99, 1));
+    assert(err.exceptionCode, 3);
+    assert(seen, [4, 6, 6]);
+    handled.close();
+
+    console.log('test_modbus_server done');
+})().catch(fail);
@@ -0,0 +1,22 @@
+import * as qruff from "qruff";
+import { assert, fail, rejects } from "./assert.js";
+
+// needs a pty pair, e.g.
+// `socat pty,raw,echo=0,link=/tmp/qruff-slave pty,raw,echo=0,link=/tmp/qruff-master`
+// and runs with both ends: `qruff tests/test_modbus_server_rtu.js /tmp/qruff-slave /tmp/qruff-master`
+(async () => {
+    let [slavePath, masterPath] = scriptArgs.slice(1);
+    let server = await qruff.modbusServer({ rtu: slavePath, baud: 19200, unit: 5, holdingRegisters: [10, 11, 12] });
+    let rtu = await qruff.rtu_setup(masterPath, { baud: 19200, unit: 5, timeoutMs: 300, retries: 0 });
+
+    let regs = new DataView(await rtu.read_holding_registers(0, 3));
+    assert(regs.getUint16(4), 12);
+    await rtu.write_single_register(1, 110);
+    assert(server.get('holdingRegisters', 1, 1)[0], 110);
+
+    // other units on the line are none of our business
+    await rejects(rtu.read_holding_registers(0, 1, { unit: 6 }), 'TIMEOUT');
+    server.close();
+
+    console.log('test_modbus_server_rtu done');
+})().catch(fail);
@@ -0,0 +1,41 @@
+import * as qruff from "qruff";
+import { assert, fail, throwsTypeError, rejects } from "./assert.js";
+
+// injected faults of the device simulator
+(async () => {
+    let sim = await qruff.modbusSimulator(JSON.stringify({
+        unit: 3,
+        holdingRegisters: [1, 2, 3, 4],
+        coils: 16,
+        faults: { exceptions: [{ functionCode: 3, address: 3, exceptionCode: 4 }] },
+    }));
+    assert(sim.path.startsWith('/dev/'), true);
+    let rtu = await qruff.rtu_setup(sim.path, { baud: 19200, unit: 3, timeoutMs: 300, retries: 0 });
+
+    let regs = new DataView(await rtu.read_holding_registers(0, 2));
+    assert(regs.getUint16(2), 2);
+    await rtu.write_single_coil(15, true);
+    assert(sim.get('coils', 15, 1)[0], true);
+
+    // only requests touching register 3 get the exception
+    await rejects(rtu.read_holding_registers(2, 2), 'SLAVE_DEVICE_FAILURE');
+
+    sim.inject({ dropNext: 1 });
+    await rejects(rtu.read_holding_registers(0, 1), 'TIMEOUT');
+    regs = new DataView(await rtu.read_holding_registers(2, 2));
+    assert(regs.getUint16(2), 4);
+
+    sim.inject({ latencyMs: 150 });
+    let start = Date.now();
+    await rtu.read_holding_registers(0, 1);
+    assert(Date.now() - start >= 150, true);
+
+    sim.inject({ dropRate: 1 });
+    await rejects(rtu.read_holding_registers(0, 1), 'TIMEOUT');
+
+    throwsTypeError(() => sim.inject({ dropRate: 2 }), 'dropRate above 1');
+    throwsTypeError(() => qruff.modbusSimulator(JSON.stringify({ holdingRegister: 4 })), 'unknown map keys');
+    sim.close();
+
+    console.log('test_modbus_sim done');
+})().catch(fail);
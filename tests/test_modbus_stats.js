@@ -0,0 +1,47 @@
+import * as qruff from "qruff";
+import { assert, fail, rejects } from "./assert.js";
+
+// per context counters, needs `python3 tests/modbus_tcp_standin.py 5502`
+// and `python3 tests/modbus_tcp_standin.py --rtu 5503`
+async function check(ctx) {
+    let stats = ctx.stats();
+    assert(stats.requests, 0);
+    assert(stats.latencyMs.avg, undefined);
+
+    await ctx.read_holding_registers(0, 2);
+    await ctx.write_single_register(10, 10);
+    await rejects(ctx.read_holding_registers(2000, 1), 'ILLEGAL_DATA_ADDRESS');
+
+    stats = ctx.stats();
+    assert(stats.requests, 3);
+    assert(stats.responses, 2);
+    assert(stats.exceptions[2], 1);
+    assert(stats.timeouts, 0);
+    assert(stats.crcErrors, 0);
+    assert(stats.retries, 0);
+    assert(stats.latencyMs.min <= stats.latencyMs.avg, true);
+    assert(stats.latencyMs.avg <= stats.latencyMs.max, true);
+
+    let dropped = ctx.reset_stats();
+    assert(dropped.requests, 3);
+    assert(ctx.stats().requests, 0);
+    assert(ctx.stats().since >= dropped.since, true);
+}
+
+(async () => {
+    let tcp = await qruff.tcp_setup('127.0.0.1', 5502, { timeoutMs: 500, retries: 0 });
+    await check(tcp);
+    let rtu = await qruff.tcp_setup('127.0.0.1', 5503, { framing: 'rtu', timeoutMs: 300, retries: 0 });
+    await check(rtu);
+
+    // the stand-in only answers unit 1, every try is a timeout
+    await rejects(rtu.read_holding_registers(0, 1, { unit: 2, retries: 1, retryBackoffMs: 10 }), 'TIMEOUT');
+    let stats = rtu.stats();
+    assert(stats.requests, 2);
+    assert(stats.timeouts, 2);
+    assert(stats.retries, 1);
+    assert(stats.responses, 0);
+    assert(stats.latencyMs.max, undefined);
+
+    console.log('test_modbus_stats done');
+})().catch(fail);
@@ -0,0 +1,49 @@
+import * as qruff from "qruff";
+import { assert, fail, throwsTypeError, rejects } from "./assert.js";
+
+// frame traces over modbus TCP and tunneled RTU, needs
+// `python3 tests/modbus_tcp_standin.py 5502` and `python3 tests/modbus_tcp_standin.py --rtu 5503`
+async function check(ctx, crcOk) {
+    let frames = [];
+    ctx.trace((frame) => frames.push(frame));
+
+    await ctx.read_holding_registers(0, 2);
+    assert(frames.length, 2);
+    let [tx, rx] = frames;
+    assert(tx.direction, 'tx');
+    assert(tx.unit, 1);
+    assert(tx.functionCode, 3);
+    assert(tx.hex.startsWith('01 03 00 00 00 02'), true);
+    assert(tx.latencyMs, undefined);
+    assert(rx.direction, 'rx');
+    assert(rx.functionCode, 3);
+    assert(rx.hex.startsWith('01 03 04'), true);
+    assert(rx.latencyMs >= 0, true);
+    assert(rx.crcOk, crcOk);
+    assert(rx.time >= tx.time, true);
+
+    await rejects(ctx.read_holding_registers(0x9000, 2), 'ILLEGAL_DATA_ADDRESS');
+    assert(frames.length, 4);
+    assert(frames[3].functionCode, 0x83);
+
+    ctx.trace(null);
+    await ctx.read_holding_registers(0, 2);
+    assert(frames.length, 4);
+}
+
+(async () => {
+    let tcp = await qruff.tcp_setup('127.0.0.1', 5502, { timeoutMs: 500, retries: 0 });
+    await check(tcp, undefined);
+    let rtu = await qruff.tcp_setup('127.0.0.1', 5503, { framing: 'rtu', timeoutMs: 500, retries: 0 });
+    await check(rtu, true);
+
+    rtu.trace({ pcap: '/tmp/qruff-trace.pcap' });
+    await rtu.report_server_id();
+    rtu.trace('log');
+    await rtu.report_server_id();
+    rtu.trace(false);
+
+    throwsTypeError(() => rtu.trace('wireshark'), 'unknown target');
+
+    console.log('test_modbus_trace done');
+})().catch(fail);
@@ -1,13 +1,28 @@
 import * as qruff from "qruff";
+import { assert, fail, rejects } from "./assert.js";
 
+// polls a simulated device, no hardware needed
 (async () => {
-    console.log('before rtu_setup');
-    try {
-        let rtu = await qruff.rtu_setup('/dev/cu.iPhone-WirelessiAPv2', 9600);
-        //let rtu = await qruff.rtu_setup('/dev/usb0', 9600);
-    } catch (err) {
-        console.log('error is', err);
+    let sim = await qruff.modbusSimulator(JSON.stringify({ unit: 1, holdingRegisters: [100, 101, 102, 103] }));
+    let rtu = await qruff.rtu_setup(sim.path, { baud: 9600, unit: 1, timeoutMs: 300, retries: 0 });
+
+    // the context stays usable after every read
+    for (let i = 0; i < 3; i++) {
+        let regs = new DataView(await rtu.read_holding_registers(0, 2));
+        assert(regs.byteLength, 4);
+        assert(regs.getUint16(2), 101);
     }
-    console.log('after rtu_setup');
-    console.log('rtu is', rtu);
-})();
+
+    let all = await Promise.all([
+        rtu.read_holding_registers(0, 1),
+        rtu.read_holding_registers(1, 1),
+        rtu.read_holding_registers(2, 1),
+    ]);
+    assert(all.map((regs) => new DataView(regs).getUint16(0)).join(), '100,101,102');
+
+    // same bus, a device that isn't there
+    await rejects(rtu.read_holding_registers(0, 1, { unit: 2 }), 'TIMEOUT');
+    sim.close();
+
+    console.log('test_rtu done');
+})().catch(fail);
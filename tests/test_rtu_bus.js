@@ -0,0 +1,41 @@
+import * as qruff from "qruff";
+import { assert, fail, rejects } from "./assert.js";
+
+// two contexts on one serial port share its bus, runs like test_modbus_server_rtu.js:
+// `qruff tests/test_rtu_bus.js /tmp/qruff-slave /tmp/qruff-master`
+(async () => {
+    let [slavePath, masterPath] = scriptArgs.slice(1);
+    let server = await qruff.modbusServer({ rtu: slavePath, baud: 19200, holdingRegisters: 32 });
+    let poller = await qruff.rtu_setup(masterPath, { baud: 19200, unit: 1, timeoutMs: 300, retries: 0 });
+    let urgent = await qruff.rtu_setup(masterPath, { baud: 19200, unit: 1, timeoutMs: 300, retries: 0, priority: 10 });
+
+    // the port is opened once, a context asking for other line settings is refused
+    await rejects(qruff.rtu_setup(masterPath, { baud: 9600 }), 'IO');
+
+    let order = [];
+    let polls = [];
+    for (let i = 0; i < 8; i++) {
+        polls.push(poller.write_single_register(i, i).then(() => order.push('poll' + i)));
+    }
+    await polls[0];
+    // both contexts see the same queue
+    assert(poller.queue_depth() > 0, true);
+    assert(urgent.queue_depth(), poller.queue_depth());
+
+    // higher priorities overtake what's queued, from the context's options or per call
+    let jumps = [
+        urgent.write_single_register(20, 99).then(() => order.push('urgent')),
+        poller.read_holding_registers(0, 1, { priority: 5 }).then(() => order.push('call')),
+    ];
+    await Promise.all(polls.concat(jumps));
+    assert(order.indexOf('urgent') < order.indexOf('poll7'), true);
+    assert(order.indexOf('call') < order.indexOf('poll7'), true);
+    assert(order.indexOf('urgent') < order.indexOf('call'), true);
+
+    assert(poller.queue_depth(), 0);
+    assert(server.get('holdingRegisters', 0, 8).join(), '0,1,2,3,4,5,6,7');
+    assert(server.get('holdingRegisters', 20, 1)[0], 99);
+    server.close();
+
+    console.log('test_rtu_bus done');
+})().catch(fail);
@@ -0,0 +1,18 @@
+import * as qruff from "qruff";
+import { assert, fail, throwsTypeError } from "./assert.js";
+
+function expectThrow(opts, pattern) {
+    let err = throwsTypeError(() => qruff.rtu_setup('/dev/null', opts), JSON.stringify(opts));
+    assert(String(err).indexOf(pattern) >= 0, true, String(err));
+}
+
+(async () => {
+    expectThrow({ baud: 9600, dataBits: 7 }, 'requires 8 data bits');
+    expectThrow({ baud: 9600, parity: 'mark' }, 'parity');
+    expectThrow({ baud: 9600, stopBits: 3 }, 'stopBits');
+    expectThrow({ baud: 9600, parity: 'even', stopBits: 2 }, 'not a valid modbus character');
+    expectThrow({ baud: 9600, flowControl: 'rts' }, 'flowControl');
+    expectThrow({ baud: 0 }, 'baud');
+    expectThrow({ timeoutMs: 0 }, 'timeoutMs');
+    console.log('test_rtu_config done');
+})().catch(fail);
@@ -0,0 +1,51 @@
+import * as qruff from "qruff";
+import { assert, fail, rejects } from "./assert.js";
+
+// needs a modbus tcp server, e.g. `python3 tests/modbus_tcp_standin.py 5502`
+(async () => {
+    let tcp = await qruff.tcp_setup('127.0.0.1', 5502, { connectTimeoutMs: 1000, timeoutMs: 500, retries: 1 });
+
+    let regs = new DataView(await tcp.read_holding_registers(10, 2));
+    assert(regs.byteLength, 4);
+    assert(regs.getUint16(0), 10);
+    assert(regs.getUint16(2), 11);
+
+    // holding register N holds N, so registers 10/11 read as 0x000a000b
+    let u32 = await tcp.read_holding_registers(10, 2, { decode: 'uint32' });
+    assert(u32[0], 0x000a000b);
+    let swapped = await tcp.read_holding_registers(10, 2, { decode: 'uint32', byteOrder: 'CDAB' });
+    assert(swapped[0], 0x000b000a);
+    let i16 = await tcp.read_holding_registers(10, 2, { decode: 'int16', byteOrder: 'BADC' });
+    assert(i16[0], 0x0a00);
+    let bits = await tcp.read_holding_registers(10, 1, { decode: 'bitfield' });
+    assert(bits[0][1], true);
+    assert(bits[0][3], true);
+
+    await tcp.write_single_register(20, 1234, { timeoutMs: 200, retries: 3, retryBackoffMs: 10 });
+    await tcp.write_multiple_registers(21, [1, 2, 3]);
+    await tcp.masked_write_register(21, 0x00f0, 0x0002);
+
+    let coils = await tcp.read_coils(0, 4);
+    assert(coils.length, 4);
+    assert(coils[0], true);
+    assert(coils[1], false);
+
+    await tcp.write_multiple_coils(0, [false, true]);
+    coils = await tcp.read_coils(0, 2);
+    assert(coils[0], false);
+    assert(coils[1], true);
+
+    let err = await rejects(tcp.read_holding_registers(2000, 1, { unit: 7 }), 'ILLEGAL_DATA_ADDRESS');
+    assert(err.exceptionCode, 2);
+    assert(err.functionCode, 3);
+    assert(err.unit, 7);
+    assert(err.address, 2000);
+
+    // the late answer to a timed out request doesn't reach the next one
+    await rejects(tcp.read_holding_registers(500, 2, { unit: 99, timeoutMs: 100, retries: 0 }), 'TIMEOUT');
+    regs = new DataView(await tcp.read_holding_registers(10, 2));
+    assert(regs.getUint16(0), 10);
+    assert(regs.getUint16(2), 11);
+
+    console.log('test_tcp done');
+})().catch(fail);
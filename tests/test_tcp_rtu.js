@@ -0,0 +1,27 @@
+import * as qruff from "qruff";
+import { assert, fail, throwsTypeError, rejects } from "./assert.js";
+
+// RTU frames tunneled over TCP, needs `python3 tests/modbus_tcp_standin.py --rtu 5503`
+(async () => {
+    let rtu = await qruff.tcp_setup('127.0.0.1', 5503, { framing: 'rtu', connectTimeoutMs: 1000, timeoutMs: 300, retries: 0 });
+
+    let regs = new DataView(await rtu.read_holding_registers(10, 2));
+    assert(regs.getUint16(0), 10);
+    assert(regs.getUint16(2), 11);
+    await rtu.write_multiple_registers(30, [7, 8, 9]);
+    let values = await rtu.read_holding_registers(30, 3, { decode: 'uint16' });
+    assert(values.join(), '7,8,9');
+    assert((await rtu.read_coils(0, 3)).join(), 'true,false,true');
+    assert(rtu.queue_depth(), 0);
+
+    await rejects(rtu.read_holding_registers(2000, 1), 'ILLEGAL_DATA_ADDRESS');
+
+    // the stand-in only answers unit 1, a timeout must not leave stale bytes behind
+    await rejects(rtu.read_holding_registers(0, 1, { unit: 2 }), 'TIMEOUT');
+    regs = new DataView(await rtu.read_holding_registers(12, 1));
+    assert(regs.getUint16(0), 12);
+
+    throwsTypeError(() => qruff.tcp_setup('127.0.0.1', 5503, { framing: 'binary' }), 'unknown framing');
+
+    console.log('test_tcp_rtu done');
+})().catch(fail);